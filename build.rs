@@ -1,4 +1,3 @@
-use std::env;
 use time::{format_description, OffsetDateTime};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -7,21 +6,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rustc-env=PACKAGE_COMPILE_TIME={}", dt);
 
     println!("cargo:rerun-if-changed=proto");
-    println!("cargo:rerun-if-changed=src/keccakf1600_x86-64.s");
+    // Without the miner only the messages are needed, not the gRPC client
     tonic_build::configure()
         .build_server(false)
+        .build_client(std::env::var_os("CARGO_FEATURE_MINER").is_some())
         // .type_attribute(".", "#[derive(Debug)]")
         .compile(
             &["proto/rpc.proto", "proto/p2p.proto", "proto/messages.proto"],
             &["proto"],
         )?;
-    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
-    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
-    if target_arch == "x86_64" && target_os != "windows" && target_os != "macos" {
-        cc::Build::new().flag("-c").file("src/keccakf1600_x86-64.s").compile("libkeccak.a");
-    }
-    if target_arch == "x86_64" && target_os == "macos" {
-        cc::Build::new().flag("-c").file("src/keccakf1600_x86-64-osx.s").compile("libkeccak.a");
-    }
     Ok(())
 }
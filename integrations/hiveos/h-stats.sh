@@ -14,6 +14,20 @@
 #######################
 
 . /hive/miners/custom/pyrin-miner/h-manifest.conf
+
+# A hashrate such as "1.02 Mhash/s" in khs
+to_khs() {
+        awk '{ scale = 0.001; if ($2 ~ /^K/) scale = 1; if ($2 ~ /^M/) scale = 1000; if ($2 ~ /^G/) scale = 1000000;
+                if ($2 ~ /^T/) scale = 1000000000; printf "%.2f\n", $1 * scale }'
+}
+
+# A field of the Hive GPU stats for the GPU at a PCI address such as "01:00.0"
+hive_stat() {
+        jq -r --arg field "$1" --arg bus "${2,,}" \
+                '(.busids | map(ascii_downcase) | index($bus)) as $i | if $i == null then empty else .[$field][$i] end' \
+                $GPU_STATS_JSON 2>/dev/null
+}
+
 stats_raw=`cat $CUSTOM_LOG_BASENAME.log | grep -w "hashrate" | tail -n 1 `
 #echo $stats_raw
 
@@ -26,47 +40,32 @@ time_rep=`date -d $datetime_rep +%s`
 diffTime=`echo $((time_now-time_rep)) | tr -d '-'`
 
 if [ "$diffTime" -lt "$maxDelay" ]; then
-        total_hashrate=`echo $stats_raw | awk '{print $7}' | cut -d "." -f 1,2 --output-delimiter='' | sed 's/$/0/'`
-	if [[ $stats_raw == *"Ghash"* ]]; then
-		total_hashrate=$(($total_hashrate*1000))
-	fi
+        total_hashrate=`echo $stats_raw | awk '{print $7, $8}' | to_khs`
 
-        #GPU Status
-        gpu_stats=$(< $GPU_STATS_JSON)
-
-        readarray -t gpu_stats < <( jq --slurp -r -c '.[] | .busids, .brand, .temp, .fan | join(" ")' $GPU_STATS_JSON 2>/dev/null)
-        busids=(${gpu_stats[0]})
-        brands=(${gpu_stats[1]})
-        temps=(${gpu_stats[2]})
-        fans=(${gpu_stats[3]})
-        gpu_count=${#busids[@]}
+        # Latest line of every device. The miner logs the PCI address and sensors of each GPU after its hashrate,
+        # e.g. "Device #0 (GeForce RTX 3080): 1.02 Ghash/s (64°C, 182W, fan 55%, bus 0000:01:00.0)"
+        readarray -t device_lines < <(tail -n 1000 $CUSTOM_LOG_BASENAME.log | grep "\] Device .*hash/s" | tac \
+                | awk '{ device = $0; sub(/^[^]]*\] Device /, "", device); sub(/:[ 0-9].*$/, "", device); if (!seen[device]++) print }' | tac)
 
         hash_arr=()
         busid_arr=()
         fan_arr=()
         temp_arr=()
-        lines=()
-
-        if [ $(gpu-detect NVIDIA) -gt 0 ]; then
-                brand_gpu_count=$(gpu-detect NVIDIA)
-                BRAND_MINER="nvidia"
-        elif [ $(gpu-detect AMD) -gt 0 ]; then
-                brand_gpu_count=$(gpu-detect AMD)
-                BRAND_MINER="amd"
-        fi
-
-        for(( i=0; i < gpu_count; i++ )); do
-                [[ "${brands[i]}" != $BRAND_MINER ]] && continue
-                [[ "${busids[i]}" =~ ^([A-Fa-f0-9]+): ]]
-                busid_arr+=($((16#${BASH_REMATCH[1]})))
-                temp_arr+=(${temps[i]})
-                fan_arr+=(${fans[i]})
-                gpu_raw=`cat $CUSTOM_LOG_BASENAME.log | grep -w "Device #"$i | tail -n 1 `
-                hashrate=`echo $gpu_raw | awk '{print $(NF-1)}' | cut -d "." -f 1,2 --output-delimiter='' | sed 's/$/0/'`
-                if [[ $gpu_raw == *"Ghash"* ]]; then
-                        hashrate=$(($hashrate*1000))
-                fi
-                hash_arr+=($hashrate)
+
+        for line in "${device_lines[@]}"; do
+                # Devices without a PCI address, such as the CPU, only count in the total
+                busid=`echo "$line" | grep -oE 'bus [0-9a-fA-F]+:[0-9a-fA-F]+:[0-9a-fA-F]+\.[0-9a-fA-F]+' | cut -d ' ' -f 2 | cut -d ':' -f 2-`
+                [[ -z $busid ]] && continue
+                busid_arr+=($((16#${busid%%:*})))
+                hash_arr+=(`echo "$line" | grep -oE '[0-9.]+ [KMGT]?hash/s' | head -n 1 | to_khs`)
+
+                # Readings the miner could not take are looked up by PCI address in the stats of Hive
+                temp=`echo "$line" | grep -oE '[0-9]+°C' | tr -dc '0-9'`
+                [[ -z $temp ]] && temp=`hive_stat temp $busid`
+                fan=`echo "$line" | grep -oE 'fan [0-9]+%' | tr -dc '0-9'`
+                [[ -z $fan ]] && fan=`hive_stat fan $busid`
+                temp_arr+=(${temp:-0})
+                fan_arr+=(${fan:-0})
         done
 
         hash_json=`printf '%s\n' "${hash_arr[@]}" | jq -cs '.'`
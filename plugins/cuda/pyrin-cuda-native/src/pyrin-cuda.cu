@@ -21,6 +21,9 @@ typedef union _uint256_t {
 #define RANDOM_LEAN 0
 #define RANDOM_XOSHIRO 1
 
+// Solutions kept per launch, after the count in final_nonces[0]
+#define MAX_RESULTS 16
+
 #define LT_U256(X,Y) (X.number[3] != Y.number[3] ? X.number[3] < Y.number[3] : X.number[2] != Y.number[2] ? X.number[2] < Y.number[2] : X.number[1] != Y.number[1] ? X.number[1] < Y.number[1] : X.number[0] < Y.number[0])
 
 __constant__ uint8_t matrix[MATRIX_SIZE][MATRIX_SIZE];
@@ -55,11 +58,10 @@ __device__ __inline__ void amul4bit(uint32_t packed_vec1[32], uint32_t packed_ve
 extern "C" {
 
 
-    __global__ void heavy_hash(const uint64_t nonce_mask, const uint64_t nonce_fixed, const uint64_t nonces_len, uint8_t random_type, void* states, uint64_t *final_nonce) {
+    __global__ void heavy_hash(const uint64_t nonce_mask, const uint64_t nonce_fixed, const uint64_t nonces_len, uint8_t random_type, void* states, uint64_t *final_nonces) {
         // assuming header_len is 72
         int nonceId = threadIdx.x + blockIdx.x*blockDim.x;
         if (nonceId < nonces_len) {
-            if (nonceId == 0) *final_nonce = 0;
             uint64_t nonce;
             switch (random_type) {
                 case RANDOM_LEAN:
@@ -119,7 +121,8 @@ extern "C" {
             blake3_hasher_update(&heavy_hasher, input, 32);
             blake3_hasher_finalize(&heavy_hasher, hash_.hash, BLAKE3_KEY_LEN);
             if (LT_U256(hash_, target)){
-                atomicCAS((unsigned long long int*) final_nonce, 0, (unsigned long long int) nonce);
+                uint64_t slot = atomicAdd((unsigned long long int*) final_nonces, 1);
+                if (slot < MAX_RESULTS) final_nonces[1 + slot] = nonce;
             }
         }
     }
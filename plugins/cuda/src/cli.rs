@@ -1,4 +1,5 @@
 use crate::Error;
+use pyrin_miner::device::DeviceSelector;
 use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -22,20 +23,39 @@ impl FromStr for NonceGenEnum {
 #[cfg(feature = "overclock")]
 #[derive(clap::Args, Debug, Default)]
 pub struct OverClock {
-    #[clap(long = "cuda-lock-mem-clocks", use_delimiter = true, help = "Lock mem clocks eg: ,810, [default: 0]")]
-    pub cuda_lock_mem_clocks: Option<Vec<u32>>,
-    #[clap(long = "cuda-lock-core-clocks", use_delimiter = true, help = "Lock core clocks eg: ,1200, [default: 0]")]
-    pub cuda_lock_core_clocks: Option<Vec<u32>>,
-    #[clap(long = "cuda-power-limits", use_delimiter = true, help = "Lock power limits eg: ,150, [default: 0]")]
-    pub cuda_power_limits: Option<Vec<u32>>,
+    #[clap(
+        long = "cuda-lock-mem-clocks",
+        use_delimiter = true,
+        help = "Lock mem clocks eg: ,810, or by device eg: 01:00.0=810 [default: 0]"
+    )]
+    pub cuda_lock_mem_clocks: Vec<String>,
+    #[clap(
+        long = "cuda-lock-core-clocks",
+        use_delimiter = true,
+        help = "Lock core clocks eg: ,1200, or by device eg: 01:00.0=1200 [default: 0]"
+    )]
+    pub cuda_lock_core_clocks: Vec<String>,
+    #[clap(
+        long = "cuda-power-limits",
+        use_delimiter = true,
+        help = "Lock power limits eg: ,150, or by device eg: 01:00.0=150 [default: 0]"
+    )]
+    pub cuda_power_limits: Vec<String>,
 }
 
 #[derive(clap::Args, Debug)]
 pub struct CudaOpt {
-    #[clap(long = "cuda-device", use_delimiter = true, help = "Which CUDA GPUs to use [default: all]")]
-    pub cuda_device: Option<Vec<u16>>,
-    #[clap(long = "cuda-workload", help = "Ratio of nonces to GPU possible parrallel run [default: 64]")]
-    pub cuda_workload: Option<Vec<f32>>,
+    #[clap(
+        long = "cuda-device",
+        use_delimiter = true,
+        help = "Which CUDA GPUs to use, by index, PCI address (e.g. 01:00.0) or UUID [default: all]"
+    )]
+    pub cuda_device: Option<Vec<DeviceSelector>>,
+    #[clap(
+        long = "cuda-workload",
+        help = "Ratio of nonces to GPU possible parrallel run, in device order or as DEVICE=WORKLOAD [default: 64]"
+    )]
+    pub cuda_workload: Vec<String>,
     #[clap(
         long = "cuda-workload-absolute",
         help = "The values given by workload are not ratio, but absolute number of nonces [default: false]"
@@ -55,6 +75,16 @@ pub struct CudaOpt {
         default_value = "lean"
     )]
     pub cuda_nonce_gen: NonceGenEnum,
+    #[clap(
+        long = "cuda-autotune",
+        help = "Find the best workload for each GPU and save it for later runs. Overrides --cuda-workload"
+    )]
+    pub cuda_autotune: bool,
+    #[clap(
+        long = "cuda-autotune-budget",
+        help = "Longest time in ms a single GPU batch may take when auto-tuning [default: 500]"
+    )]
+    pub cuda_autotune_budget: Option<f32>,
 
     #[cfg(feature = "overclock")]
     #[clap(flatten)]
@@ -2,13 +2,16 @@
 extern crate pyrin_miner;
 
 use clap::{ArgMatches, FromArgMatches};
+use cust::device::DeviceAttribute;
 use cust::prelude::*;
-use pyrin_miner::{Plugin, Worker, WorkerSpec};
-use log::LevelFilter;
+use pyrin_miner::tuning::{tune_workload, ProfileStore, DEFAULT_LATENCY_BUDGET_MS};
+use pyrin_miner::device::{select_devices, PerDevice};
+use pyrin_miner::{DeviceIdentity, DeviceInfo, Plugin, Worker, WorkerSpec};
+use log::{info, warn, LevelFilter};
 use std::error::Error as StdError;
 #[cfg(feature = "overclock")]
 use {
-    log::{error, info},
+    log::error,
     nvml_wrapper::Device as NvmlDevice,
     nvml_wrapper::Nvml,
 };
@@ -19,7 +22,7 @@ mod cli;
 mod worker;
 
 use crate::cli::{CudaOpt, NonceGenEnum};
-use crate::worker::CudaGPUWorker;
+use crate::worker::{base_workload, identity, ptx, CudaGPUWorker};
 
 const DEFAULT_WORKLOAD_SCALE: f32 = 1024.;
 
@@ -33,7 +36,7 @@ pub struct CudaPlugin {
 impl CudaPlugin {
     fn new() -> Result<Self, Error> {
         cust::init(CudaFlags::empty())?;
-        env_logger::builder().filter_level(LevelFilter::Info).parse_default_env().init();
+        pyrin_miner::logging::init(LevelFilter::Info);
         Ok(Self {
             specs: Vec::new(),
             _enabled: false,
@@ -53,98 +56,147 @@ impl Plugin for CudaPlugin {
     }
 
     fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
-        self.specs.iter().map(|spec| Box::new(*spec) as Box<dyn WorkerSpec>).collect::<Vec<Box<dyn WorkerSpec>>>()
+        self.specs.iter().map(|spec| Box::new(spec.clone()) as Box<dyn WorkerSpec>).collect()
     }
 
     //noinspection RsTypeCheck
     fn process_option(&mut self, matches: &ArgMatches) -> Result<usize, pyrin_miner::Error> {
+        pyrin_miner::logging::configure(matches)?;
         let opts: CudaOpt = CudaOpt::from_arg_matches(matches)?;
 
         self._enabled = !opts.cuda_disable;
         if self._enabled {
-            let gpus: Vec<u16> = match &opts.cuda_device {
-                Some(devices) => devices.clone(),
-                None => {
-                    let gpu_count = Device::num_devices().unwrap() as u16;
-                    (0..gpu_count).collect()
-                }
-            };
+            let identities = (0..Device::num_devices()?)
+                .map(|index| -> Result<DeviceIdentity, Error> { Ok(identity(&Device::get_device(index)?)) })
+                .collect::<Result<Vec<DeviceIdentity>, Error>>()?;
+            let gpus = select_devices(opts.cuda_device.as_deref(), &identities)?;
+            let workloads = PerDevice::<f32>::parse(&opts.cuda_workload)?;
+            #[cfg(feature = "overclock")]
+            let lock_core_clocks = PerDevice::<u32>::parse(&opts.overclock.cuda_lock_core_clocks)?;
 
             // if any of cuda_lock_core_clocks / cuda_lock_mem_clocks / cuda_power_limit is valid, init nvml and try to apply
             #[cfg(feature = "overclock")]
-            if opts.overclock.cuda_lock_core_clocks.is_some()
-                || opts.overclock.cuda_lock_mem_clocks.is_some()
-                || opts.overclock.cuda_power_limits.is_some()
             {
-                for i in 0..gpus.len() {
-                    let lock_mem_clock: Option<u32> = match &opts.overclock.cuda_lock_mem_clocks {
-                        Some(mem_clocks) if i < mem_clocks.len() => Some(mem_clocks[i]),
-                        Some(mem_clocks) if !mem_clocks.is_empty() => Some(*mem_clocks.last().unwrap()),
-                        _ => None,
-                    };
-
-                    let lock_core_clock: Option<u32> = match &opts.overclock.cuda_lock_core_clocks {
-                        Some(core_clocks) if i < core_clocks.len() => Some(core_clocks[i]),
-                        Some(core_clocks) if !core_clocks.is_empty() => Some(*core_clocks.last().unwrap()),
-                        _ => None,
-                    };
-
-                    let power_limit: Option<u32> = match &opts.overclock.cuda_power_limits {
-                        Some(power_limits) if i < power_limits.len() => Some(power_limits[i]),
-                        Some(power_limits) if !power_limits.is_empty() => Some(*power_limits.last().unwrap()),
-                        _ => None,
-                    };
-
-                    let mut nvml_device: NvmlDevice = self.nvml_instance.device_by_index(gpus[i] as u32)?;
-
-                    if let Some(lmc) = lock_mem_clock {
-                        match nvml_device.set_mem_locked_clocks(lmc, lmc) {
-                            Err(e) => error!("set mem locked clocks {:?}", e),
-                            _ => info!("GPU #{} #{} lock mem clock at {} Mhz", i, &nvml_device.name()?, &lmc),
+                let lock_mem_clocks = PerDevice::<u32>::parse(&opts.overclock.cuda_lock_mem_clocks)?;
+                let power_limits = PerDevice::<u32>::parse(&opts.overclock.cuda_power_limits)?;
+                if !lock_core_clocks.is_empty() || !lock_mem_clocks.is_empty() || !power_limits.is_empty() {
+                    for (i, &index) in gpus.iter().enumerate() {
+                        let identity = &identities[index];
+                        // NVML may number the devices differently than CUDA, the PCI address names the same card
+                        let mut nvml_device: NvmlDevice = match &identity.pci_bus_id {
+                            Some(pci_bus_id) => self.nvml_instance.device_by_pci_bus_id(pci_bus_id.as_str())?,
+                            None => self.nvml_instance.device_by_index(index as u32)?,
                         };
-                    }
 
-                    if let Some(lcc) = lock_core_clock {
-                        match nvml_device.set_gpu_locked_clocks(lcc, lcc) {
-                            Err(e) => error!("set gpu locked clocks {:?}", e),
-                            _ => info!("GPU #{} #{} lock core clock at {} Mhz", i, &nvml_device.name()?, &lcc),
+                        if let Some(lmc) = lock_mem_clocks.get(i, index, identity) {
+                            match nvml_device.set_mem_locked_clocks(lmc, lmc) {
+                                Err(e) => error!("set mem locked clocks {:?}", e),
+                                _ => info!("GPU #{} #{} lock mem clock at {} Mhz", index, &nvml_device.name()?, &lmc),
+                            };
+                        }
+
+                        if let Some(lcc) = lock_core_clocks.get(i, index, identity) {
+                            match nvml_device.set_gpu_locked_clocks(lcc, lcc) {
+                                Err(e) => error!("set gpu locked clocks {:?}", e),
+                                _ => info!("GPU #{} #{} lock core clock at {} Mhz", index, &nvml_device.name()?, &lcc),
+                            };
                         };
-                    };
 
-                    if let Some(pl) = power_limit {
-                        match nvml_device.set_power_management_limit(pl * 1000) {
-                            Err(e) => error!("set power limit {:?}", e),
-                            _ => info!("GPU #{} #{} power limit at {} W", i, &nvml_device.name()?, &pl),
+                        if let Some(pl) = power_limits.get(i, index, identity) {
+                            match nvml_device.set_power_management_limit(pl * 1000) {
+                                Err(e) => error!("set power limit {:?}", e),
+                                _ => info!("GPU #{} #{} power limit at {} W", index, &nvml_device.name()?, &pl),
+                            };
                         };
-                    };
+                    }
                 }
             }
 
-            self.specs = (0..gpus.len())
-                .map(|i| CudaWorkerSpec {
-                    device_id: gpus[i] as u32,
-                    workload: match &opts.cuda_workload {
-                        Some(workload) if i < workload.len() => workload[i],
-                        Some(workload) if !workload.is_empty() => *workload.last().unwrap(),
-                        _ => DEFAULT_WORKLOAD_SCALE,
-                    },
+            self.specs = gpus
+                .iter()
+                .enumerate()
+                .map(|(i, &index)| CudaWorkerSpec {
+                    device_id: index as u32,
+                    workload: workloads.get(i, index, &identities[index]).unwrap_or(DEFAULT_WORKLOAD_SCALE),
                     is_absolute: opts.cuda_workload_absolute,
                     blocking_sync: !opts.cuda_no_blocking_sync,
                     random: opts.cuda_nonce_gen,
+                    #[cfg(feature = "overclock")]
+                    lock_core_clock: lock_core_clocks.get(i, index, &identities[index]),
+                    autotune: match opts.cuda_autotune {
+                        true => Some(opts.cuda_autotune_budget.unwrap_or(DEFAULT_LATENCY_BUDGET_MS)),
+                        false => None,
+                    },
+                    identity: identities[index].clone(),
                 })
                 .collect();
         }
         Ok(self.specs.len())
     }
+
+    fn list_devices(&self) -> Result<Vec<DeviceInfo>, pyrin_miner::Error> {
+        (0..Device::num_devices()?)
+            .map(|index| -> Result<DeviceInfo, Error> {
+                let device = Device::get_device(index)?;
+                let major = device.get_attribute(DeviceAttribute::ComputeCapabilityMajor)?;
+                let minor = device.get_attribute(DeviceAttribute::ComputeCapabilityMinor)?;
+                let ptx = ptx(major, minor);
+                Ok(DeviceInfo {
+                    backend: "cuda".into(),
+                    index: index as usize,
+                    name: device.name()?,
+                    arch: Some(format!("{}.{}", major, minor)),
+                    memory_bytes: device.total_memory().ok().map(|bytes| bytes as u64),
+                    identity: identity(&device),
+                    precompiled: ptx.is_some(),
+                    kernel: match ptx {
+                        Some((tier, _)) => format!("PTX {}", tier),
+                        None => "none, compute capability not supported".into(),
+                    },
+                    default_workload: ptx
+                        .and_then(|(_, ptx)| base_workload(device, ptx).ok())
+                        .map(|workload| (workload as f32 * DEFAULT_WORKLOAD_SCALE) as usize),
+                    ..DeviceInfo::default()
+                })
+            })
+            .collect()
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 struct CudaWorkerSpec {
     device_id: u32,
     workload: f32,
     is_absolute: bool,
     blocking_sync: bool,
     random: NonceGenEnum,
+    #[cfg(feature = "overclock")]
+    lock_core_clock: Option<u32>,
+    autotune: Option<f32>,
+    identity: DeviceIdentity,
+}
+
+impl CudaWorkerSpec {
+    /**
+    Loads the saved workload of the device, or sweeps for one and saves it
+    */
+    fn tuned_workload(&self, budget_ms: f32) -> Result<usize, Error> {
+        let store = ProfileStore::new(ProfileStore::default_path("cuda"));
+        if let Some(profile) = store.get(&self.identity) {
+            info!("GPU #{} using saved workload {}", self.device_id, profile.workload);
+            return Ok(profile.workload);
+        }
+
+        info!("GPU #{} is tuning its workload. This may take some time.", self.device_id);
+        let mut worker = CudaGPUWorker::new(self.device_id, 1., false, self.blocking_sync, NonceGenEnum::Lean)?;
+        let base = worker.workload;
+        let profile = tune_workload(&mut worker, base, budget_ms)?;
+        info!("GPU #{} tuned workload: {} ({:.2}ms per batch)", self.device_id, profile.workload, profile.kernel_ms);
+        if let Err(e) = store.insert(&self.identity, profile) {
+            warn!("GPU #{} could not save its workload profile: {}", self.device_id, e);
+        }
+        Ok(profile.workload)
+    }
 }
 
 impl WorkerSpec for CudaWorkerSpec {
@@ -153,12 +205,25 @@ impl WorkerSpec for CudaWorkerSpec {
         format!("#{} ({})", self.device_id, device.name().unwrap())
     }
 
-    fn build(&self) -> Box<dyn Worker> {
-        Box::new(
-            CudaGPUWorker::new(self.device_id, self.workload, self.is_absolute, self.blocking_sync, self.random)
-                .unwrap(),
-        )
+    fn build(&self) -> Result<Box<dyn Worker>, Error> {
+        let (workload, is_absolute) = match self.autotune.map(|budget| self.tuned_workload(budget)) {
+            Some(Ok(workload)) => (workload as f32, true),
+            Some(Err(e)) => {
+                warn!("GPU #{} auto-tune failed, using the configured workload: {}", self.device_id, e);
+                (self.workload, self.is_absolute)
+            }
+            None => (self.workload, self.is_absolute),
+        };
+        #[allow(unused_mut)]
+        let mut worker = CudaGPUWorker::new(self.device_id, workload, is_absolute, self.blocking_sync, self.random)?;
+        #[cfg(feature = "overclock")]
+        worker.track_core_clock(self.lock_core_clock);
+        Ok(Box::new(worker))
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        self.identity.clone()
     }
 }
 
-declare_plugin!(CudaPlugin, CudaPlugin::new, CudaOpt);
+declare_plugin!(CudaPlugin, CudaPlugin::new, CudaOpt, "cuda");
@@ -2,22 +2,39 @@ use crate::{Error, NonceGenEnum};
 use cust::context::CurrentContext;
 use cust::device::DeviceAttribute;
 use cust::function::Function;
+use cust::error::{CudaError, CudaResult};
+use cust::memory::{AsyncCopyDestination, DeviceCopy, DevicePointer, DeviceSlice};
 use cust::module::{ModuleJitOption, OptLevel};
 use cust::prelude::*;
+use cust::sys::{cuDeviceGetUuid, cuFuncGetModule, cuModuleGetGlobal_v2, cudaError_enum, CUuuid};
 use pyrin_miner::xoshiro256starstar::Xoshiro256StarStar;
-use pyrin_miner::Worker;
-use log::{error, info};
+use pyrin_miner::{DeviceIdentity, Telemetry, Worker, WorkerError};
+use log::{error, info, warn};
 use rand::{Fill, RngCore};
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::sync::{Arc, Weak};
+use std::{mem, ptr};
+#[cfg(feature = "overclock")]
+use nvml_wrapper::{
+    enum_wrappers::device::{Clock, TemperatureSensor},
+    Nvml,
+};
 
-static BPS: f32 = 1.;
+// How much a locked core clock is lowered on every hardware error backoff, and how low it may go
+#[cfg(feature = "overclock")]
+const CORE_CLOCK_STEP: u32 = 50;
+#[cfg(feature = "overclock")]
+const MIN_CORE_CLOCK: u32 = 500;
+
+// Batches queued on the stream at once, so the GPU never waits for the host between them
+const PIPELINE_DEPTH: usize = 2;
+// Nonces kept per batch after their count, as MAX_RESULTS in the kernel
+const MAX_RESULTS: usize = 16;
 
 static PTX_86: &str = include_str!("../resources/pyrin-cuda-sm86.ptx");
 static PTX_75: &str = include_str!("../resources/pyrin-cuda-sm75.ptx");
 static PTX_61: &str = include_str!("../resources/pyrin-cuda-sm61.ptx");
-static PTX_30: &str = include_str!("../resources/pyrin-cuda-sm30.ptx");
-static PTX_20: &str = include_str!("../resources/pyrin-cuda-sm20.ptx");
 
 pub struct Kernel<'kernel> {
     func: Arc<Function<'kernel>>,
@@ -51,22 +68,58 @@ impl<'kernel> Kernel<'kernel> {
     }
 }
 
+// Result and timing of one batch in flight
+struct Batch {
+    final_nonce_buff: DeviceBuffer<u64>,
+    start_event: Event,
+    stop_event: Event,
+}
+
+impl Batch {
+    fn new() -> Result<Self, Error> {
+        Ok(Self {
+            final_nonce_buff: DeviceBuffer::<u64>::zeroed(1 + MAX_RESULTS)?,
+            start_event: Event::new(EventFlags::DEFAULT)?,
+            stop_event: Event::new(EventFlags::DEFAULT)?,
+        })
+    }
+
+    fn nonces(&self) -> Result<Vec<u64>, WorkerError> {
+        let mut results = [0u64; 1 + MAX_RESULTS];
+        self.final_nonce_buff.copy_to(&mut results[..]).map_err(device_error)?;
+        let count = (results[0] as usize).min(MAX_RESULTS);
+        Ok(results[1..=count].to_vec())
+    }
+}
+
 pub struct CudaGPUWorker<'gpu> {
     // NOTE: The order is important! context must be closed last
     heavy_hash_kernel: Kernel<'gpu>,
     stream: Stream,
-    start_event: Event,
-    stop_event: Event,
+    batches: Vec<Batch>,
+    // Batches launched and not collected yet, oldest first
+    in_flight: VecDeque<usize>,
+    next_batch: usize,
+    last_collected: usize,
+    hash_header_ptr: DevicePointer<[u8; 72]>,
+    matrix_ptr: DevicePointer<[[u8; 64]; 64]>,
+    target_ptr: DevicePointer<[u64; 4]>,
     _module: Arc<Module>,
 
     rand_state: DeviceBuffer<u64>,
-    final_nonce_buff: DeviceBuffer<u64>,
 
     device_id: u32,
     pub workload: usize,
     _context: Context,
 
     random: NonceGenEnum,
+
+    #[cfg(feature = "overclock")]
+    locked_core_clock: Option<(Nvml, u32)>,
+    pci_bus_id: Option<String>,
+    // Sensors of the device, found by its PCI address since NVML may order devices differently than CUDA
+    #[cfg(feature = "overclock")]
+    nvml: Option<Nvml>,
 }
 
 impl<'gpu> Worker for CudaGPUWorker<'gpu> {
@@ -75,31 +128,47 @@ impl<'gpu> Worker for CudaGPUWorker<'gpu> {
         format!("#{} ({})", self.device_id, device.name().unwrap())
     }
 
-    fn load_block_constants(&mut self, hash_header: &[u8; 72], matrix: &[[u16; 64]; 64], target: &[u64; 4]) {
+    fn load_block_constants(
+        &mut self,
+        hash_header: &[u8; 72],
+        matrix: &[[u16; 64]; 64],
+        target: &[u64; 4],
+    ) -> Result<(), WorkerError> {
+        // Queued on the stream, so batches already launched still hash the previous job
         let u8matrix: Arc<[[u8; 64]; 64]> = Arc::new(matrix.map(|row| row.map(|v| v as u8)));
-        let mut hash_header_gpu = self._module.get_global::<[u8; 72]>(&CString::new("hash_header").unwrap()).unwrap();
-        hash_header_gpu.copy_from(hash_header).map_err(|e| e.to_string()).unwrap();
-
-        let mut matrix_gpu = self._module.get_global::<[[u8; 64]; 64]>(&CString::new("matrix").unwrap()).unwrap();
-        matrix_gpu.copy_from(&u8matrix).map_err(|e| e.to_string()).unwrap();
-
-        let mut target_gpu = self._module.get_global::<[u64; 4]>(&CString::new("target").unwrap()).unwrap();
-        target_gpu.copy_from(target).map_err(|e| e.to_string()).unwrap();
+        copy_async(&self.stream, self.hash_header_ptr, hash_header).map_err(device_error)?;
+        copy_async(&self.stream, self.matrix_ptr, u8matrix.as_ref()).map_err(device_error)?;
+        copy_async(&self.stream, self.target_ptr, target).map_err(device_error)?;
+        Ok(())
     }
 
     #[inline(always)]
-    fn calculate_hash(&mut self, _nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64) {
+    fn calculate_hash(
+        &mut self,
+        _nonces: Option<&Vec<u64>>,
+        nonce_mask: u64,
+        nonce_fixed: u64,
+    ) -> Result<(), WorkerError> {
         let func = &self.heavy_hash_kernel.func;
         let stream = &self.stream;
         let random: u8 = match self.random {
             NonceGenEnum::Lean => {
-                self.rand_state.copy_from(&[rand::thread_rng().next_u64()]).unwrap();
+                let seed = rand::thread_rng().next_u64();
+                copy_async(stream, self.rand_state.as_device_ptr(), &seed).map_err(device_error)?;
                 0
             }
             NonceGenEnum::Xoshiro => 1,
         };
 
-        self.start_event.record(stream).unwrap();
+        // A full pipeline drops its oldest batch, the stream still runs it before reusing its buffer
+        if self.in_flight.len() == self.batches.len() {
+            self.in_flight.pop_front();
+        }
+        let index = self.next_batch;
+        let batch = &self.batches[index];
+
+        copy_async(stream, batch.final_nonce_buff.as_device_ptr(), &0u64).map_err(device_error)?;
+        batch.start_event.record(stream).map_err(device_error)?;
         unsafe {
             launch!(
                 func<<<
@@ -110,36 +179,250 @@ impl<'gpu> Worker for CudaGPUWorker<'gpu> {
                     self.workload,
                     random,
                     self.rand_state.as_device_ptr(),
-                    self.final_nonce_buff.as_device_ptr()
+                    batch.final_nonce_buff.as_device_ptr()
                 )
             )
-            .unwrap(); // We see errors in sync
+            .map_err(device_error)?; // Errors of the kernel itself show up in sync
         }
-        self.stop_event.record(stream).unwrap();
+        batch.stop_event.record(stream).map_err(device_error)?;
+        self.next_batch = (index + 1) % self.batches.len();
+        self.in_flight.push_back(index);
+        Ok(())
     }
 
     #[inline(always)]
     fn sync(&self) -> Result<(), Error> {
-        //self.stream.synchronize()?;
-        self.stop_event.synchronize()?;
-        if self.stop_event.elapsed_time_f32(&self.start_event)? > 1000. / BPS {
-            return Err("Cuda takes longer then block rate. Please reduce your workload.".into());
-        }
+        self.stream.synchronize().map_err(device_error)?;
         Ok(())
     }
 
+    fn pipeline_depth(&self) -> usize {
+        self.batches.len()
+    }
+
+    fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        nonces[0] = self.collect_nonces()?.first().copied().unwrap_or(0);
+        Ok(())
+    }
+
+    fn collect_nonces(&mut self) -> Result<Vec<u64>, Error> {
+        let index = self.in_flight.pop_front().ok_or("No batch in flight")?;
+        let batch = &self.batches[index];
+        batch.stop_event.synchronize().map_err(device_error)?;
+        let nonces = batch.nonces()?;
+        self.last_collected = index;
+        Ok(nonces)
+    }
+
     fn get_workload(&self) -> usize {
         self.workload
     }
 
+    fn kernel_time_ms(&self) -> Option<f32> {
+        let batch = &self.batches[self.last_collected];
+        batch.stop_event.elapsed_time_f32(&batch.start_event).ok()
+    }
+
+    fn set_workload(&mut self, workload: usize) -> Result<(), Error> {
+        let workload = match self.random {
+            // Every thread needs its own xoshiro state
+            NonceGenEnum::Xoshiro => workload.min(self.rand_state.len() / 4),
+            NonceGenEnum::Lean => workload,
+        }
+        .clamp(1, u32::MAX as usize);
+        self.heavy_hash_kernel.set_workload(workload as u32);
+        self.workload = workload;
+        Ok(())
+    }
+
+    #[cfg(feature = "overclock")]
+    fn backoff(&mut self) -> bool {
+        let (nvml, clock) = match &mut self.locked_core_clock {
+            Some(locked) => locked,
+            None => return false,
+        };
+        let lowered = clock.saturating_sub(CORE_CLOCK_STEP);
+        if lowered < MIN_CORE_CLOCK {
+            return false;
+        }
+        let locked =
+            nvml.device_by_index(self.device_id).and_then(|mut device| device.set_gpu_locked_clocks(lowered, lowered));
+        match locked {
+            Ok(()) => {
+                info!("GPU #{} lock core clock at {} Mhz", self.device_id, lowered);
+                *clock = lowered;
+                true
+            }
+            Err(e) => {
+                error!("set gpu locked clocks {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn telemetry(&self) -> Option<Telemetry> {
+        Some(Telemetry { pci_bus_id: self.pci_bus_id.clone(), ..self.sensors() })
+    }
+
     #[inline(always)]
     fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
-        self.final_nonce_buff.copy_to(nonces)?;
+        let index = *self.in_flight.back().ok_or("No batch in flight")?;
+        nonces[0] = self.batches[index].nonces()?.first().copied().unwrap_or(0);
         Ok(())
     }
 }
 
+/**
+ Queues a copy from the host after the work already on the stream. Pageable memory is staged before this returns
+*/
+fn copy_async<T: DeviceCopy>(stream: &Stream, dst: DevicePointer<T>, src: &T) -> CudaResult<()> {
+    unsafe { DeviceSlice::from_raw_parts_mut(dst, 1).async_copy_from(std::slice::from_ref(src), stream) }
+}
+
+/**
+ The device address of a global in the module of `function`, so copies to it can be queued on a stream
+*/
+fn global_ptr<T: DeviceCopy>(function: &Function, name: &str) -> Result<DevicePointer<T>, Error> {
+    let name = CString::new(name)?;
+    let mut module = ptr::null_mut();
+    let mut address = 0;
+    let mut size = 0;
+    // cust's Symbol only copies synchronously, and Module does not expose its handle
+    let result = unsafe {
+        match cuFuncGetModule(&mut module, function.to_raw()) {
+            cudaError_enum::CUDA_SUCCESS => cuModuleGetGlobal_v2(&mut address, &mut size, module, name.as_ptr()),
+            e => e,
+        }
+    };
+    match result {
+        cudaError_enum::CUDA_SUCCESS if size == mem::size_of::<T>() => Ok(DevicePointer::from_raw(address)),
+        cudaError_enum::CUDA_SUCCESS => {
+            Err(format!("{:?} is {} bytes on the device, expected {}", name, size, mem::size_of::<T>()).into())
+        }
+        e => Err(format!("Cannot find {:?} in the kernel: {:?}", name, e).into()),
+    }
+}
+
+/**
+ Sorts a driver error by whether the device can go on with the same context
+*/
+fn device_error(e: CudaError) -> WorkerError {
+    match e {
+        // Sticky errors, every later call on the context fails as well
+        CudaError::IllegalAddress
+        | CudaError::LaunchFailed
+        | CudaError::LaunchTimeout
+        | CudaError::HardwareStackError
+        | CudaError::IllegalInstruction
+        | CudaError::MisalignedAddress
+        | CudaError::InvalidAddressSpace
+        | CudaError::InvalidProgramCounter
+        | CudaError::AssertError
+        | CudaError::EccUncorrectable
+        | CudaError::NvlinkUncorrectable
+        | CudaError::ContextIsDestroyed
+        | CudaError::InvalidContext
+        | CudaError::Deinitialized => WorkerError::DeviceLost(e.into()),
+        CudaError::NoDevice | CudaError::InvalidDevice | CudaError::NotSupported | CudaError::NotPermitted => {
+            WorkerError::Fatal(e.into())
+        }
+        _ => WorkerError::Transient(e.into()),
+    }
+}
+
+/**
+ The PCI address and the UUID of a GPU, both as NVML writes them
+*/
+pub fn identity(device: &Device) -> DeviceIdentity {
+    // cust has no wrapper for the UUID of a device
+    let mut raw = CUuuid::default();
+    let uuid = match unsafe { cuDeviceGetUuid(&mut raw, device.as_raw()) } {
+        cudaError_enum::CUDA_SUCCESS => {
+            let hex = raw.bytes.iter().map(|&byte| format!("{:02x}", byte as u8)).collect::<String>();
+            Some(format!("GPU-{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+        }
+        _ => None,
+    };
+    DeviceIdentity { pci_bus_id: pci_bus_id(device).ok(), uuid }
+}
+
+/**
+ The PCI address of a GPU as NVML writes it, e.g. `0000:01:00.0`
+*/
+fn pci_bus_id(device: &Device) -> Result<String, Error> {
+    Ok(format!(
+        "{:04x}:{:02x}:{:02x}.0",
+        device.get_attribute(DeviceAttribute::PciDomainId)?,
+        device.get_attribute(DeviceAttribute::PciBusId)?,
+        device.get_attribute(DeviceAttribute::PciDeviceId)?
+    ))
+}
+
+/**
+ The PTX tier a compute capability mines with, e.g. `sm_86`, and its source. None if the capability is too old
+*/
+pub fn ptx(major: i32, minor: i32) -> Option<(&'static str, &'static str)> {
+    if major > 8 || (major == 8 && minor >= 6) {
+        Some(("sm_86", PTX_86))
+    } else if major > 7 || (major == 7 && minor >= 5) {
+        Some(("sm_75", PTX_75))
+    } else if major > 6 || (major == 6 && minor >= 1) {
+        Some(("sm_61", PTX_61))
+    } else {
+        None
+    }
+}
+
+/**
+ Nonces per batch that fill the device, the base of a relative workload. Loads the kernel in a context of its own
+*/
+pub fn base_workload(device: Device, ptx: &str) -> Result<u32, Error> {
+    let _context = Context::new(device)?;
+    let module = Arc::new(Module::from_ptx(ptx, &[ModuleJitOption::OptLevel(OptLevel::O4)])?);
+    let workload = Kernel::new(Arc::downgrade(&module), "heavy_hash")?.get_workload();
+    Ok(workload)
+}
+
 impl<'gpu> CudaGPUWorker<'gpu> {
+    /**
+    Remembers the core clock locked through `--cuda-lock-core-clocks`, so it can be lowered on hardware errors
+    */
+    #[cfg(feature = "overclock")]
+    pub fn track_core_clock(&mut self, clock: Option<u32>) {
+        self.locked_core_clock = clock.and_then(|clock| match Nvml::init() {
+            Ok(nvml) => Some((nvml, clock)),
+            Err(e) => {
+                warn!("GPU #{} cannot lower its clocks on hardware errors: {:?}", self.device_id, e);
+                None
+            }
+        });
+    }
+
+    #[cfg(feature = "overclock")]
+    fn sensors(&self) -> Telemetry {
+        let device = match (&self.nvml, &self.pci_bus_id) {
+            (Some(nvml), Some(pci_bus_id)) => nvml.device_by_pci_bus_id(pci_bus_id.as_str()),
+            _ => return Telemetry::default(),
+        };
+        match device {
+            Ok(device) => Telemetry {
+                temperature_c: device.temperature(TemperatureSensor::Gpu).ok().map(|celsius| celsius as f32),
+                power_w: device.power_usage().ok().map(|milliwatts| milliwatts as f32 / 1000.),
+                fan_percent: device.fan_speed(0).ok().map(|percent| percent as f32),
+                core_clock_mhz: device.clock_info(Clock::Graphics).ok(),
+                memory_clock_mhz: device.clock_info(Clock::Memory).ok(),
+                pci_bus_id: None,
+            },
+            Err(_) => Telemetry::default(),
+        }
+    }
+
+    // Only the PCI address is known without NVML
+    #[cfg(not(feature = "overclock"))]
+    fn sensors(&self) -> Telemetry {
+        Telemetry::default()
+    }
+
     pub fn new(
         device_id: u32,
         workload: f32,
@@ -158,36 +441,12 @@ impl<'gpu> CudaGPUWorker<'gpu> {
 
         let major = device.get_attribute(DeviceAttribute::ComputeCapabilityMajor)?;
         let minor = device.get_attribute(DeviceAttribute::ComputeCapabilityMinor)?;
-        let _module: Arc<Module>;
         info!("Device #{} compute version is {}.{}", device_id, major, minor);
-        if major > 8 || (major == 8 && minor >= 6) {
-            _module = Arc::new(Module::from_ptx(PTX_86, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
-                error!("Error loading PTX. Make sure you have the updated driver for you devices");
-                e
-            })?);
-        } else if major > 7 || (major == 7 && minor >= 5) {
-            _module = Arc::new(Module::from_ptx(PTX_75, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
-                error!("Error loading PTX. Make sure you have the updated driver for you devices");
-                e
-            })?);
-        } else if major > 6 || (major == 6 && minor >= 1) {
-            _module = Arc::new(Module::from_ptx(PTX_61, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
-                error!("Error loading PTX. Make sure you have the updated driver for you devices");
-                e
-            })?);
-        }/* else if major >= 3 {
-            _module = Arc::new(Module::from_ptx(PTX_30, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
-                error!("Error loading PTX. Make sure you have the updated driver for you devices");
-                e
-            })?);
-        } else if major >= 2 {
-            _module = Arc::new(Module::from_ptx(PTX_20, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
-                error!("Error loading PTX. Make sure you have the updated driver for you devices");
-                e
-            })?);
-        }*/ else {
-            return Err("Cuda compute version not supported".into());
-        }
+        let (_, ptx) = ptx(major, minor).ok_or("Cuda compute version not supported")?;
+        let _module = Arc::new(Module::from_ptx(ptx, &[ModuleJitOption::OptLevel(OptLevel::O4)]).map_err(|e| {
+            error!("Error loading PTX. Make sure you have the updated driver for you devices");
+            e
+        })?);
 
         let stream = Stream::new(StreamFlags::NON_BLOCKING, None)?;
 
@@ -206,8 +465,6 @@ impl<'gpu> CudaGPUWorker<'gpu> {
         info!("GPU #{} Chosen workload: {}", device_id, chosen_workload);
         heavy_hash_kernel.set_workload(chosen_workload);
 
-        let final_nonce_buff = vec![0u64; 1].as_slice().as_dbuf()?;
-
         let rand_state: DeviceBuffer<u64> = match random {
             NonceGenEnum::Xoshiro => {
                 info!("Using xoshiro for nonce-generation");
@@ -237,15 +494,36 @@ impl<'gpu> CudaGPUWorker<'gpu> {
         Ok(Self {
             device_id,
             _context,
+            batches: (0..PIPELINE_DEPTH).map(|_| Batch::new()).collect::<Result<_, _>>()?,
+            in_flight: VecDeque::with_capacity(PIPELINE_DEPTH),
+            next_batch: 0,
+            last_collected: 0,
+            hash_header_ptr: global_ptr::<[u8; 72]>(&heavy_hash_kernel.func, "hash_header")?,
+            matrix_ptr: global_ptr::<[[u8; 64]; 64]>(&heavy_hash_kernel.func, "matrix")?,
+            target_ptr: global_ptr::<[u64; 4]>(&heavy_hash_kernel.func, "target")?,
             _module,
-            start_event: Event::new(EventFlags::DEFAULT)?,
-            stop_event: Event::new(EventFlags::DEFAULT)?,
             workload: chosen_workload as usize,
             stream,
             rand_state,
-            final_nonce_buff,
             heavy_hash_kernel,
             random,
+            #[cfg(feature = "overclock")]
+            locked_core_clock: None,
+            pci_bus_id: match pci_bus_id(&device) {
+                Ok(pci_bus_id) => Some(pci_bus_id),
+                Err(e) => {
+                    warn!("GPU #{} cannot read its PCI address: {}", device_id, e);
+                    None
+                }
+            },
+            #[cfg(feature = "overclock")]
+            nvml: match Nvml::init() {
+                Ok(nvml) => Some(nvml),
+                Err(e) => {
+                    warn!("GPU #{} cannot read its sensors: {:?}", device_id, e);
+                    None
+                }
+            },
         })
     }
 }
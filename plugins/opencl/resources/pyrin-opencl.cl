@@ -17,173 +17,72 @@ typedef int int32_t;
 typedef ulong uint64_t;
 typedef long int64_t;
 
-/* TINY KECCAK */
-/** libkeccak-tiny
- *
- * A single-file implementation of SHA-3 and SHAKE.
- *
- * Implementor: David Leon Gil
- * License: CC0, attribution kindly requested. Blame taken too,
- * but not liability.
- */
-
-/******** The Keccak-f[1600] permutation ********/
-
-/*** Constants. ***/
-constant STATIC const uint8_t rho[24] = \
-  { 1,  3,   6, 10, 15, 21,
-    28, 36, 45, 55,  2, 14,
-    27, 41, 56,  8, 25, 43,
-    62, 18, 39, 61, 20, 44};
-constant STATIC const uint8_t pi[24] = \
-  {10,  7, 11, 17, 18, 3,
-    5, 16,  8, 21, 24, 4,
-   15, 23, 19, 13, 12, 2,
-   20, 14, 22,  9, 6,  1};
-
-constant STATIC const uint64_t RC[24] = \
-  {1UL, 0x8082UL, 0x800000000000808aUL, 0x8000000080008000UL,
-   0x808bUL, 0x80000001UL, 0x8000000080008081UL, 0x8000000000008009UL,
-   0x8aUL, 0x88UL, 0x80008009UL, 0x8000000aUL,
-   0x8000808bUL, 0x800000000000008bUL, 0x8000000000008089UL, 0x8000000000008003UL,
-   0x8000000000008002UL, 0x8000000000000080UL, 0x800aUL, 0x800000008000000aUL,
-   0x8000000080008081UL, 0x8000000000008080UL, 0x80000001UL, 0x8000000080008008UL};
-
-
-/** Magic from fancyIX/sgminer-phi2-branch **/
-#if defined(OPENCL_PLATFORM_AMD)
-#pragma OPENCL EXTENSION cl_amd_media_ops : enable
-#define dataType uint2
-#define as_dataType as_uint2
-static inline uint2 rol(const uint2 vv, const int r)
-{
-	if (r <= 32)
-	{
-		return amd_bitalign((vv).xy, (vv).yx, 32 - r);
-	}
-	else
-	{
-		return amd_bitalign((vv).yx, (vv).xy, 64 - r);
-	}
-}
-#else
-#define dataType ulong
-#define as_dataType as_ulong
-#define rol(x, s) (((x) << s) | ((x) >> (64 - s)))
-#endif
-
-/*** Helper macros to unroll the permutation. ***/
-#define REPEAT6(e) e e e e e e
-#define REPEAT24(e) REPEAT6(e e e e)
-#define REPEAT23(e) REPEAT6(e e e) e e e e e
-#define REPEAT5(e) e e e e e
-#define FOR5(v, s, e) \
-  v = 0;            \
-  REPEAT5(e; v += s;)
-
-/*** Keccak-f[1600] ***/
-STATIC inline void keccakf(void *state) {
-  dataType *a = (dataType *)state;
-  dataType b[5] = {0};
-  dataType t = 0, v = 0;
-  uint8_t x, y;
-
-#if defined(cl_amd_media_ops)
+/* BLAKE3 */
+// Only what the PoW needs: an input that fits in one chunk, a whole number of words long, hashed to 32 bytes
+
+constant STATIC const uint32_t IV[8] = {
+    0x6A09E667U, 0xBB67AE85U, 0x3C6EF372U, 0xA54FF53AU, 0x510E527FU, 0x9B05688CU, 0x1F83D9ABU, 0x5BE0CD19U};
+
+constant STATIC const uint8_t MSG_SCHEDULE[7][16] = {
+    {0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15},
+    {2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8},
+    {3, 4, 10, 12, 13, 2, 7, 14, 6, 5, 9, 0, 11, 15, 8, 1},
+    {10, 7, 12, 9, 14, 3, 13, 15, 4, 0, 11, 2, 5, 8, 1, 6},
+    {12, 13, 9, 11, 15, 10, 14, 8, 7, 2, 5, 3, 0, 1, 6, 4},
+    {9, 14, 11, 5, 8, 12, 15, 1, 13, 3, 0, 10, 2, 6, 4, 7},
+    {11, 15, 5, 0, 1, 9, 8, 6, 14, 10, 2, 12, 3, 4, 7, 13}};
+
+#define CHUNK_START 1
+#define CHUNK_END 2
+#define ROOT 8
+
+// rotate() turns left, so these are the right rotations by 16, 12, 8 and 7 of the reference
+#define G(a, b, c, d, x, y)                                      \
+  a = a + b + (x); d = rotate(d ^ a, 16U); c = c + d; b = rotate(b ^ c, 20U); \
+  a = a + b + (y); d = rotate(d ^ a, 24U); c = c + d; b = rotate(b ^ c, 25U);
+
+STATIC inline void compress(uint32_t cv[8], const uint32_t block[16], uint32_t block_len, uint32_t flags) {
+  // The counter stays 0, every input is a single chunk
+  uint32_t s[16] = {cv[0], cv[1], cv[2], cv[3], cv[4], cv[5], cv[6], cv[7],
+                    IV[0], IV[1], IV[2], IV[3], 0, 0, block_len, flags};
   #pragma unroll
-#endif
-  for (int i = 0; i < 23; i++) {
-    // Theta
-    FOR5(x, 1,
-      b[x] = a[x] ^ a[x+5] ^ a[x+10] ^ a[x+15] ^ a[x+20];)
-
-    v = b[4]; t = b[0];
-    b[4] = b[4] ^ rol(b[1], 1);
-    b[0] = b[0] ^ rol(b[2], 1);
-    b[1] = b[1] ^ rol(b[3], 1);
-    b[2] = b[2] ^ rol(v, 1);
-    b[3] = b[3] ^ rol(t, 1);
-
-    FOR5(x, 1,
-      FOR5(y, 5, a[y + x] ^= b[(x + 4) % 5]; ))
-
-    // Rho and pi
-    t = a[1];
-    x = 23;
-    REPEAT23(a[pi[x]] = rol(a[pi[x-1]], rho[x]); x--; )
-    a[pi[ 0]] = rol(        t, rho[ 0]);
-
-    // Chi
-    FOR5(y, 5, 
-      v = a[y]; t = a[y+1];
-      a[y  ] = bitselect(a[y  ] ^ a[y+2], a[y  ], a[y+1]);
-      a[y+1] = bitselect(a[y+1] ^ a[y+3], a[y+1], a[y+2]);
-      a[y+2] = bitselect(a[y+2] ^ a[y+4], a[y+2], a[y+3]);
-      a[y+3] = bitselect(a[y+3] ^      v, a[y+3], a[y+4]);
-      a[y+4] = bitselect(a[y+4] ^      t, a[y+4], v);
-    )
-
-    // Iota
-    a[0] ^= as_dataType(RC[i]);
-}
-  /*******************************************************/
-      // Theta
-    FOR5(x, 1,
-      b[x] = a[x] ^ a[x+5] ^ a[x+10] ^ a[x+15] ^ a[x+20];)
-
-    v = b[4]; t = b[0];
-    b[4] = b[4] ^ rol(b[1], 1);
-    b[0] = b[0] ^ rol(b[2], 1);
-    b[1] = b[1] ^ rol(b[3], 1);
-    b[2] = b[2] ^ rol(v, 1);
-    b[3] = b[3] ^ rol(t, 1);
-
-    a[0] ^= b[4];
-    a[1] ^= b[0]; a[6] ^= b[0];
-    a[2] ^= b[1]; a[12] ^= b[1];
-    a[3] ^= b[2]; a[18] ^= b[2];
-    a[4] ^= b[3]; a[24] ^= b[3];
-
-    // Rho and pi
-    a[1]=rol(a[pi[22]], rho[23]);
-    a[2]=rol(a[pi[16]], rho[17]);
-    a[4]=rol(a[pi[10]], rho[11]);
-    a[3]=rol(a[pi[ 4]], rho[ 5]);
-
-    // Chi
-    v = a[0];
-
-    a[0] = bitselect(a[0] ^ a[2], a[0], a[1]); 
-    a[1] = bitselect(a[1] ^ a[3], a[1], a[2]); 
-    a[2] = bitselect(a[2] ^ a[4], a[2], a[3]); 
-    a[3] = bitselect(a[3] ^    v, a[3], a[4]); 
-
-    // Iota
-    a[0] ^= as_dataType(RC[23]);
+  for (int r = 0; r < 7; r++) {
+    constant const uint8_t *m = MSG_SCHEDULE[r];
+    G(s[0], s[4], s[8],  s[12], block[m[0]],  block[m[1]]);
+    G(s[1], s[5], s[9],  s[13], block[m[2]],  block[m[3]]);
+    G(s[2], s[6], s[10], s[14], block[m[4]],  block[m[5]]);
+    G(s[3], s[7], s[11], s[15], block[m[6]],  block[m[7]]);
+    G(s[0], s[5], s[10], s[15], block[m[8]],  block[m[9]]);
+    G(s[1], s[6], s[11], s[12], block[m[10]], block[m[11]]);
+    G(s[2], s[7], s[8],  s[13], block[m[12]], block[m[13]]);
+    G(s[3], s[4], s[9],  s[14], block[m[14]], block[m[15]]);
+  }
+  #pragma unroll
+  for (int i = 0; i < 8; i++) cv[i] = s[i] ^ s[i + 8];
 }
 
-/******** The FIPS202-defined functions. ********/
-
-/*** Some helper macros. ***/
-
-
-#define P keccakf
-#define Plen 200
-
-constant const ulong powP[25] = { 0x113cff0da1f6d83dUL, 0x29bf8855b7027e3cUL, 0x1e5f2e720efb44d2UL, 0x1ba5a4a3f59869a0UL, 0x7b2fafca875e2d65UL, 0x4aef61d629dce246UL, 0x183a981ead415b10UL, 0x776bf60c789bc29cUL, 0xf8ebf13388663140UL, 0x2e651c3c43285ff0UL, 0x0f96070540f14a0aUL, 0x44e367875b299152UL, 0xec70f1a425b13715UL, 0xe6c85d8f82e9da89UL, 0xb21a601f85b4b223UL, 0x3485549064a36a46UL, 0x0f06dd1c7a2f851aUL, 0xc1a2021d563bb142UL, 0xba1de5e4451668e4UL, 0xd102574105095f8dUL, 0x89ca4e849bcecf4aUL, 0x48b09427a8742edbUL, 0xb1fcce9ce78b5272UL, 0x5d1129cf82afa5bcUL, 0x02b97c786f824383UL };
-constant const ulong heavyP[25] = { 0x3ad74c52b2248509UL, 0x79629b0e2f9f4216UL, 0x7a14ff4816c7f8eeUL, 0x11a75f4c80056498UL, 0xe720e0df44eecedaUL, 0x72c7d82e14f34069UL, 0xc100ff2a938935baUL, 0x5e219040250fc462UL, 0x8039f9a60dcf6a48UL, 0xa0bcaa9f792a3d0cUL, 0xf431c05dd0a9a226UL, 0xd31f4cc354c18c3fUL, 0x6c6b7d01a769cc3dUL, 0x2ec65bd3562493e4UL, 0x4ef74b3a99cdb044UL, 0x774c86835434f2b0UL, 0x07e961b036bc9416UL, 0x7e8f1db17765cc07UL, 0xea8fdb80bac46d39UL, 0xb992f2d37b34ca58UL, 0xc776c5048481b957UL, 0x47c39f675112c22eUL, 0x92bb399db5290c0aUL, 0x549ae0312f9fc615UL, 0x1619327d10b9da35UL };
-
-/** The sponge-based hash construction. **/
-STATIC inline void hash(constant const ulong *initP, const ulong* in, ulong4* out) {
-  private ulong a[25];
-  // Xor in the last block.
+/** Hashes the first `len` bytes of `in`, `len` being a multiple of 4 up to 1024 **/
+STATIC inline void hash(const ulong *in, const uint32_t len, ulong4 *out) {
+  const uint32_t *words = (const uint32_t *)in;
+  uint32_t cv[8], block[16];
   #pragma unroll
-  for (size_t i = 0; i < 10; i++) a[i] = initP[i] ^ in[i];
+  for (int i = 0; i < 8; i++) cv[i] = IV[i];
+  uint32_t flags = CHUNK_START;
+  uint32_t offset = 0;
+  // Every block but the last is full
+  for (; offset + 64 < len; offset += 64) {
+    #pragma unroll
+    for (int i = 0; i < 16; i++) block[i] = words[offset / 4 + i];
+    compress(cv, block, 64, flags);
+    flags = 0;
+  }
   #pragma unroll
-  for (size_t i = 10; i < 25; i++) a[i] = initP[i];
-  // Apply P
-  P(a);
-  // Squeeze output.
-  *out = ((ulong4 *)(a))[0];
+  for (int i = 0; i < 16; i++) block[i] = offset + 4 * i < len ? words[offset / 4 + i] : 0;
+  compress(cv, block, len - offset, flags | CHUNK_END | ROOT);
+  out->x = (ulong)cv[0] | ((ulong)cv[1] << 32);
+  out->y = (ulong)cv[2] | ((ulong)cv[3] << 32);
+  out->z = (ulong)cv[4] | ((ulong)cv[5] << 32);
+  out->w = (ulong)cv[6] | ((ulong)cv[7] << 32);
 }
 
 /* RANDOM NUMBER GENERATOR BASED ON MWC64X                          */
@@ -248,11 +147,10 @@ typedef union _Hash {
 #define RANDOM_TYPE_LEAN 0
 #define RANDOM_TYPE_XOSHIRO 1
 
-#define LT_U256(X,Y) (X.w != Y->w ? X.w < Y->w : X.z != Y->z ? X.z < Y->z : X.y != Y->y ? X.y < Y->y : X.x < Y->x)
+// Solutions kept per launch, after the count in final_nonces[0]
+#define MAX_RESULTS 16
 
-#ifndef cl_khr_int64_base_atomics
-global int lock = false;
-#endif
+#define LT_U256(X,Y) (X.w != Y->w ? X.w < Y->w : X.z != Y->z ? X.z < Y->z : X.y != Y->y ? X.y < Y->y : X.x < Y->x)
 
 #if defined(NVIDIA_CUDA) && (__COMPUTE_MAJOR__ > 6 || (__COMPUTE_MAJOR__ == 6 && __COMPUTE_MINOR__ >= 1))
 #define amul4bit(X,Y,Z) _amul4bit((constant uint32_t*)(X), (private uint32_t*)(Y), (uint32_t *)(Z))
@@ -321,7 +219,7 @@ kernel void heavy_hash(
     __constant const ulong4 *target,
     const uint8_t random_type,
     global void * restrict random_state,
-    volatile global uint64_t *final_nonce,
+    volatile global uint64_t *final_nonces,
     volatile global ulong4 *final_hash
 ) {
     #if defined(PAL)
@@ -330,12 +228,6 @@ kernel void heavy_hash(
     int nonceId = get_global_id(0);
     #endif
 
-    #ifndef cl_khr_int64_base_atomics
-    if (nonceId == 0)
-       lock = 0;
-    work_group_barrier(CLK_GLOBAL_MEM_FENCE);
-    #endif
-
     private uint64_t nonce;
     switch (random_type){
       case RANDOM_TYPE_LEAN:
@@ -357,7 +249,7 @@ kernel void heavy_hash(
     buffer[9] = nonce;
 
     Hash hash_, hash2_;
-    hash(powP, (const ulong*)buffer, &hash_.hash);
+    hash((const ulong*)buffer, 80, &hash_.hash);
     #if __FORCE_AMD_V_DOT8_U32_U4__ == 1
     #else
     private uchar hash_part[64];
@@ -382,30 +274,22 @@ kernel void heavy_hash(
         amul4bit(matrix + 128*rowId, hash_part, &product1);
         amul4bit(matrix + 128*rowId+64, hash_part, &product2);
     #endif
-        product1 >>= 10;
-        product2 >>= 10;
-//        hash2_.bytes[rowId] = hash_.bytes[rowId] ^ bitselect(product1, product2, 0x0000000FU);
-        hash2_.bytes[rowId] = hash_.bytes[rowId] ^ ((uint8_t)((product1 << 4) | (uint8_t)(product2)));
+        product1 = (product1 ^ (product1 >> 4) ^ (product1 >> 8)) & 0xF;
+        product2 = (product2 ^ (product2 >> 4) ^ (product2 >> 8)) & 0xF;
+        hash2_.bytes[rowId] = hash_.bytes[rowId] ^ ((uint8_t)((product1 << 4) | product2));
     }
     buffer[0] = hash2_.hash.x;
     buffer[1] = hash2_.hash.y;
     buffer[2] = hash2_.hash.z;
     buffer[3] = hash2_.hash.w;
-    #pragma unroll
-    for(int i=4; i<10; i++) buffer[i] = 0;
 
-    hash(heavyP, (const ulong*)buffer, &hash_.hash);
+    hash((const ulong*)buffer, 32, &hash_.hash);
 
     if (LT_U256(hash_.hash, target)){
         //printf("%lu: %lu < %lu: %d %d\n", nonce, ((uint64_t *)hash_)[3], target[3], ((uint64_t *)hash_)[3] < target[3], LT_U256((uint64_t *)hash_, target));
-        #ifdef cl_khr_int64_base_atomics
-        atom_cmpxchg(final_nonce, 0, nonce);
-        #else
-        if (!atom_cmpxchg(&lock, 0, 1)) {
-            *final_nonce = nonce;
-            //for(int i=0;i<4;i++) final_hash[i] = ((uint64_t volatile *)hash_)[i];
-        }
-        #endif
+        // The count stays below 2^32, so the 32 bit atomic on its low half works without 64 bit atomics
+        uint slot = atomic_inc((volatile global uint *)final_nonces);
+        if (slot < MAX_RESULTS) final_nonces[1 + slot] = nonce;
     }
     /*if (nonceId==1) {
         //printf("%lu: %lu < %lu: %d %d\n", nonce, ((uint64_t *)hash2_)[3], target[3], ((uint64_t *)hash_)[3] < target[3]);
@@ -0,0 +1,87 @@
+use crate::Error;
+use rand::RngCore;
+use std::env::current_exe;
+use std::fs;
+use std::path::PathBuf;
+
+/**
+ Compiled programs kept on disk, so a device builds the kernel once rather than on every start. A program is filed
+ under a hash of all that shapes it: the device name, the driver version, the build options and the kernel source.
+ A change to any of them misses the cache and builds the program again
+*/
+#[derive(Clone, Debug)]
+pub struct ProgramCache {
+    dir: PathBuf,
+}
+
+impl ProgramCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /**
+    The cache directory next to the miner executable
+    */
+    pub fn default_dir() -> PathBuf {
+        let mut path = current_exe().unwrap_or_default();
+        path.pop(); // Getting the parent directory
+        path.join("opencl-cache")
+    }
+
+    pub fn key(device_name: &str, driver_version: &str, options: &str, source: &str) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for part in [device_name, driver_version, options, source] {
+            hasher.update(part.as_bytes());
+            // Keeps the parts apart, so moving text from one to the next changes the key
+            hasher.update(&[0]);
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(key)).ok().filter(|binary| !binary.is_empty())
+    }
+
+    pub fn insert(&self, key: &str, binary: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        // Workers of the same model build at the same time, a program only shows up once it is written whole
+        let temp = self.dir.join(format!("{}.{:08x}.tmp", key, rand::thread_rng().next_u32()));
+        fs::write(&temp, binary)?;
+        fs::rename(&temp, self.path(key)).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            e.into()
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProgramCache;
+
+    #[test]
+    fn test_key() {
+        let key = ProgramCache::key("gfx1030", "3513.0 (HSA1.1,LC)", "-cl-mad-enable ", "kernel");
+        assert_eq!(key, ProgramCache::key("gfx1030", "3513.0 (HSA1.1,LC)", "-cl-mad-enable ", "kernel"));
+        assert_ne!(key, ProgramCache::key("gfx1031", "3513.0 (HSA1.1,LC)", "-cl-mad-enable ", "kernel"));
+        assert_ne!(key, ProgramCache::key("gfx1030", "3570.0 (HSA1.1,LC)", "-cl-mad-enable ", "kernel"));
+        assert_ne!(key, ProgramCache::key("gfx1030", "3513.0 (HSA1.1,LC)", "", "kernel"));
+        assert_ne!(key, ProgramCache::key("gfx1030", "3513.0 (HSA1.1,LC)", "-cl-mad-enable ", "kernel "));
+        assert_ne!(ProgramCache::key("ab", "c", "", ""), ProgramCache::key("a", "bc", "", ""));
+    }
+
+    #[test]
+    fn test_insert() {
+        let dir = std::env::temp_dir().join(format!("pyrin-opencl-cache-{}", std::process::id()));
+        let cache = ProgramCache::new(&dir);
+        let key = ProgramCache::key("gfx1030", "3513.0", "", "kernel");
+        assert_eq!(cache.get(&key), None);
+        cache.insert(&key, b"program").unwrap();
+        assert_eq!(cache.get(&key).as_deref(), Some(&b"program"[..]));
+        assert_eq!(cache.get(&ProgramCache::key("gfx1030", "3570.0", "", "kernel")), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
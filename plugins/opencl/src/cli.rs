@@ -1,4 +1,8 @@
 use crate::Error;
+use pyrin_miner::device::DeviceSelector;
+use pyrin_miner::DeviceIdentity;
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -19,25 +23,80 @@ impl FromStr for NonceGenEnum {
     }
 }
 
+/**
+ An OpenCL device as `[PLATFORM/]DEVICE`, e.g. `1/0` or `0/01:00.0`. A device without a platform is looked for on
+ every platform mined on
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlatformDevice {
+    pub platform: Option<usize>,
+    pub device: DeviceSelector,
+}
+
+impl PlatformDevice {
+    pub fn matches(&self, platform: usize, index: usize, identity: &DeviceIdentity) -> bool {
+        self.platform.map_or(true, |selected| selected == platform) && self.device.matches(index, identity)
+    }
+}
+
+impl fmt::Display for PlatformDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.platform {
+            Some(platform) => write!(f, "{}/{}", platform, self.device),
+            None => write!(f, "{}", self.device),
+        }
+    }
+}
+
+impl FromStr for PlatformDevice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((platform, device)) => Ok(Self {
+                platform: Some(platform.trim().parse().map_err(|_| format!("Unknown OpenCL platform {}", platform))?),
+                device: device.parse()?,
+            }),
+            None => Ok(Self { platform: None, device: s.parse()? }),
+        }
+    }
+}
+
 #[derive(clap::Args, Debug)]
 pub struct OpenCLOpt {
-    #[clap(long = "opencl-platform", help = "Which OpenCL platform to use (limited to one per executable)")]
-    pub opencl_platform: Option<u16>,
-    #[clap(long = "opencl-device", use_delimiter = true, help = "Which OpenCL GPUs to use on a specific platform")]
-    pub opencl_device: Option<Vec<u16>>,
-    #[clap(long = "opencl-workload", help = "Ratio of nonces to GPU possible parrallel run in OpenCL [default: 512]")]
-    pub opencl_workload: Option<Vec<f32>>,
+    #[clap(
+        long = "opencl-platform",
+        use_delimiter = true,
+        help = "Which OpenCL platforms to use, by index, e.g. 0,1 [default: the AMD platform]"
+    )]
+    pub opencl_platform: Option<Vec<usize>>,
+    #[clap(
+        long = "opencl-device",
+        use_delimiter = true,
+        help = "Which OpenCL GPUs to use, as [PLATFORM/]DEVICE by index or PCI address (e.g. 1/0 or 01:00.0)"
+    )]
+    pub opencl_device: Option<Vec<PlatformDevice>>,
+    #[clap(
+        long = "opencl-workload",
+        help = "Ratio of nonces to GPU possible parrallel run in OpenCL, in device order or as [PLATFORM/]DEVICE=WORKLOAD [default: 512]"
+    )]
+    pub opencl_workload: Vec<String>,
     #[clap(
         long = "opencl-workload-absolute",
         help = "The values given by workload are not ratio, but absolute number of nonces in OpenCL [default: false]"
     )]
     pub opencl_workload_absolute: bool,
-    #[clap(long = "opencl-enable", help = "Enable opencl, and take all devices of the chosen platform")]
+    #[clap(long = "opencl-enable", help = "Enable opencl, and take all devices of the chosen platforms")]
     pub opencl_enable: bool,
     #[clap(long = "opencl-amd-disable", help = "Disables AMD mining (does not override opencl-enable)")]
     pub opencl_amd_disable: bool,
-    #[clap(long = "opencl-no-amd-binary", help = "Disable fetching of precompiled AMD kernel (if exists)")]
-    pub opencl_no_amd_binary: bool,
+    #[clap(
+        long = "opencl-cache-dir",
+        help = "Where kernels compiled for each device are kept between runs [default: opencl-cache next to the miner]"
+    )]
+    pub opencl_cache_dir: Option<PathBuf>,
+    #[clap(long = "opencl-no-cache", help = "Compile the OpenCL kernel on every start instead of caching it")]
+    pub opencl_no_cache: bool,
     #[clap(
         long = "experimental-amd",
         help = "Uses SMID instructions in AMD. Miner will crash if instruction is not supported"
@@ -50,4 +109,14 @@ pub struct OpenCLOpt {
         default_value = "lean"
     )]
     pub opencl_nonce_gen: NonceGenEnum,
+    #[clap(
+        long = "opencl-autotune",
+        help = "Find the best workload for each GPU and save it for later runs. Overrides --opencl-workload"
+    )]
+    pub opencl_autotune: bool,
+    #[clap(
+        long = "opencl-autotune-budget",
+        help = "Longest time in ms a single GPU batch may take when auto-tuning [default: 500]"
+    )]
+    pub opencl_autotune_budget: Option<f32>,
 }
@@ -2,20 +2,23 @@
 extern crate pyrin_miner;
 
 use clap::{ArgMatches, FromArgMatches};
-use pyrin_miner::{Plugin, Worker, WorkerSpec};
-use log::{info, LevelFilter};
+use pyrin_miner::tuning::{tune_workload, ProfileStore, DEFAULT_LATENCY_BUDGET_MS};
+use pyrin_miner::device::PerDevice;
+use pyrin_miner::{DeviceIdentity, DeviceInfo, Plugin, Worker, WorkerSpec};
+use log::{info, warn, LevelFilter};
 use opencl3::device::{Device, CL_DEVICE_TYPE_ALL};
 use opencl3::platform::{get_platforms, Platform};
-use opencl3::types::cl_device_id;
 use std::error::Error as StdError;
 
 pub type Error = Box<dyn StdError + Send + Sync + 'static>;
 
+mod cache;
 mod cli;
 mod worker;
 
-use crate::cli::{NonceGenEnum, OpenCLOpt};
-use crate::worker::OpenCLGPUWorker;
+use crate::cache::ProgramCache;
+use crate::cli::{NonceGenEnum, OpenCLOpt, PlatformDevice};
+use crate::worker::{base_workload, gfx_target, identity, OpenCLGPUWorker};
 
 const DEFAULT_WORKLOAD_SCALE: f32 = 512.;
 
@@ -26,7 +29,7 @@ pub struct OpenCLPlugin {
 
 impl OpenCLPlugin {
     fn new() -> Result<Self, Error> {
-        env_logger::builder().filter_level(LevelFilter::Info).parse_default_env().init();
+        pyrin_miner::logging::init(LevelFilter::Info);
         Ok(Self { specs: Vec::new(), _enabled: false })
     }
 }
@@ -41,11 +44,12 @@ impl Plugin for OpenCLPlugin {
     }
 
     fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
-        self.specs.iter().map(|spec| Box::new(*spec) as Box<dyn WorkerSpec>).collect::<Vec<Box<dyn WorkerSpec>>>()
+        self.specs.iter().map(|spec| Box::new(spec.clone()) as Box<dyn WorkerSpec>).collect()
     }
 
     //noinspection RsTypeCheck
     fn process_option(&mut self, matches: &ArgMatches) -> Result<usize, pyrin_miner::Error> {
+        pyrin_miner::logging::configure(matches)?;
         let opts: OpenCLOpt = OpenCLOpt::from_arg_matches(matches)?;
 
         self._enabled = opts.opencl_enable;
@@ -63,97 +67,318 @@ impl Plugin for OpenCLPlugin {
             let num_devices = platform.get_devices(CL_DEVICE_TYPE_ALL).unwrap_or_default().len();
             info!("{}: {} ({} devices available)", vendor, name, num_devices);
         }
-        let amd_platforms = (&platforms)
-            .iter()
-            .filter(|p| {
-                p.vendor().unwrap_or_else(|_| "Unk".into()) == "Advanced Micro Devices, Inc."
-                    && !p.get_devices(CL_DEVICE_TYPE_ALL).unwrap_or_default().is_empty()
-            })
-            .collect::<Vec<&Platform>>();
-        let _platform: &Platform = match opts.opencl_platform {
-            Some(idx) => {
-                self._enabled = true;
-                &platforms[idx as usize]
-            }
-            None if !opts.opencl_amd_disable && !amd_platforms.is_empty() => {
-                self._enabled = true;
-                amd_platforms[0]
+        let amd_platform = platforms.iter().position(|p| {
+            p.vendor().unwrap_or_else(|_| "Unk".into()) == "Advanced Micro Devices, Inc."
+                && !p.get_devices(CL_DEVICE_TYPE_ALL).unwrap_or_default().is_empty()
+        });
+        // Platforms named by a device count as chosen
+        let mut chosen = opts.opencl_platform.clone().unwrap_or_default();
+        for platform in opts.opencl_device.iter().flatten().filter_map(|selector| selector.platform) {
+            if !chosen.contains(&platform) {
+                chosen.push(platform);
             }
-            None => &platforms[0],
-        };
+        }
+        if !chosen.is_empty() {
+            self._enabled = true;
+        } else if let Some(amd_platform) = amd_platform.filter(|_| !opts.opencl_amd_disable) {
+            self._enabled = true;
+            chosen.push(amd_platform);
+        } else {
+            chosen.push(0);
+        }
         if self._enabled {
-            info!(
-                "Chose to mine on {}: {}.",
-                &_platform.vendor().unwrap_or_else(|_| "Unk".into()),
-                &_platform.name().unwrap_or_else(|_| "Unk".into())
-            );
-
-            let device_ids = _platform.get_devices(CL_DEVICE_TYPE_ALL).unwrap();
-            let gpus = match opts.opencl_device {
-                Some(dev) => {
-                    self._enabled = true;
-                    dev.iter().map(|d| device_ids[*d as usize]).collect::<Vec<cl_device_id>>()
-                }
-                None => device_ids,
+            let mut platform_devices = Vec::new();
+            for &index in &chosen {
+                let platform = platforms
+                    .get(index)
+                    .ok_or_else(|| format!("No OpenCL platform #{}, {} platforms found", index, platforms.len()))?;
+                info!(
+                    "Chose to mine on {}: {}.",
+                    &platform.vendor().unwrap_or_else(|_| "Unk".into()),
+                    &platform.name().unwrap_or_else(|_| "Unk".into())
+                );
+                let device_ids = platform.get_devices(CL_DEVICE_TYPE_ALL).map_err(|e| e.to_string())?;
+                platform_devices.push((index, *platform, device_ids.into_iter().map(Device::new).collect::<Vec<_>>()));
+            }
+            let identities = platform_devices
+                .iter()
+                .map(|(index, _, devices)| (*index, devices.iter().map(identity).collect::<Vec<DeviceIdentity>>()))
+                .collect::<Vec<_>>();
+            let gpus = select_platform_devices(opts.opencl_device.as_deref(), &identities)?;
+            let workloads = PerDevice::<f32, PlatformDevice>::parse(&opts.opencl_workload)?;
+            let matches = |selector: &PlatformDevice, (n, index): (usize, usize)| {
+                selector.matches(platform_devices[n].0, index, &identities[n].1[index])
             };
+            if let Some(selector) =
+                workloads.selectors().find(|selector| !gpus.iter().any(|&gpu| matches(selector, gpu)))
+            {
+                return Err(format!("No selected OpenCL device matches the workload of {}", selector).into());
+            }
+            let cache_dir = opts.opencl_cache_dir.clone().unwrap_or_else(ProgramCache::default_dir);
+            let cache = (!opts.opencl_no_cache).then(|| ProgramCache::new(cache_dir));
 
-            self.specs = (0..gpus.len())
-                .map(|i| OpenCLWorkerSpec {
-                    _platform: *_platform,
-                    index: i,
-                    device_id: Device::new(gpus[i]),
-                    workload: match &opts.opencl_workload {
-                        Some(workload) if i < workload.len() => workload[i],
-                        Some(workload) if !workload.is_empty() => *workload.last().unwrap(),
-                        _ => DEFAULT_WORKLOAD_SCALE,
-                    },
+            self.specs = gpus
+                .iter()
+                .enumerate()
+                .map(|(i, &(n, index))| OpenCLWorkerSpec {
+                    _platform: platform_devices[n].1,
+                    // Device indexes repeat across platforms, the id names the platform once there are several
+                    platform: (chosen.len() > 1).then(|| platform_devices[n].0),
+                    index,
+                    device_id: platform_devices[n].2[index],
+                    workload: workloads
+                        .get_matching(i, |selector| matches(selector, (n, index)))
+                        .unwrap_or(DEFAULT_WORKLOAD_SCALE),
                     is_absolute: opts.opencl_workload_absolute,
                     experimental_amd: opts.experimental_amd,
-                    use_amd_binary: !opts.opencl_no_amd_binary,
                     random: opts.opencl_nonce_gen,
+                    autotune: match opts.opencl_autotune {
+                        true => Some(opts.opencl_autotune_budget.unwrap_or(DEFAULT_LATENCY_BUDGET_MS)),
+                        false => None,
+                    },
+                    identity: identities[n].1[index].clone(),
+                    cache: cache.clone(),
                 })
                 .collect();
         }
         Ok(self.specs.len())
     }
+
+    fn list_devices(&self) -> Result<Vec<DeviceInfo>, pyrin_miner::Error> {
+        let mut devices = Vec::new();
+        for (platform_index, platform) in get_platforms().map_err(|e| e.to_string())?.iter().enumerate() {
+            // Platforms without devices fail to list them
+            let device_ids = platform.get_devices(CL_DEVICE_TYPE_ALL).unwrap_or_default();
+            for (index, device) in device_ids.into_iter().map(Device::new).enumerate() {
+                devices.push(DeviceInfo {
+                    backend: "opencl".into(),
+                    platform: Some(platform_index),
+                    platform_name: platform.name().ok(),
+                    index,
+                    name: device
+                        .board_name_amd()
+                        .unwrap_or_else(|_| device.name().unwrap_or_else(|_| "Unknown Device".into())),
+                    arch: match (device.compute_capability_major_nv(), device.compute_capability_minor_nv()) {
+                        (Ok(major), Ok(minor)) => Some(format!("{}.{}", major, minor)),
+                        _ if device.pcie_id_amd().is_ok() => Some(gfx_target(&device)),
+                        _ => None,
+                    },
+                    memory_bytes: device.global_mem_size().ok(),
+                    identity: identity(&device),
+                    precompiled: false,
+                    kernel: "compiled from source".into(),
+                    default_workload: base_workload(&device)
+                        .ok()
+                        .map(|workload| (DEFAULT_WORKLOAD_SCALE * workload as f32) as usize),
+                });
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/**
+ The devices the selectors name as `(n, index)`, with `n` the position of their platform in `platforms` and `index`
+ the position of the device on it, or every device without selectors. A selector without a platform picks the devices
+ it matches on each platform, and has to match one on at least one of them. A GPU two platforms expose, e.g. through
+ two drivers, is picked once, on the platform it is found on first
+*/
+fn select_platform_devices(
+    selectors: Option<&[PlatformDevice]>,
+    platforms: &[(usize, Vec<DeviceIdentity>)],
+) -> Result<Vec<(usize, usize)>, Error> {
+    let mut selected: Vec<(usize, usize)> = Vec::new();
+    let mut select = |n: usize, index: usize| {
+        let identity = &platforms[n].1[index];
+        match selected
+            .iter()
+            .find(|&&(m, other)| (m, other) == (n, index) || same_gpu(&platforms[m].1[other], identity))
+        {
+            Some(&(m, other)) if (m, other) != (n, index) => info!(
+                "Device #{}/{} is the same GPU as #{}/{}, mining on it once",
+                platforms[n].0, index, platforms[m].0, other
+            ),
+            Some(_) => {}
+            None => selected.push((n, index)),
+        }
+    };
+    let selectors = match selectors {
+        Some(selectors) => selectors,
+        None => {
+            for (n, (_, identities)) in platforms.iter().enumerate() {
+                (0..identities.len()).for_each(|index| select(n, index));
+            }
+            return Ok(selected);
+        }
+    };
+    for selector in selectors {
+        let mut found = false;
+        for (n, (platform, identities)) in platforms.iter().enumerate() {
+            let device = identities
+                .iter()
+                .enumerate()
+                .position(|(index, identity)| selector.matches(*platform, index, identity));
+            if let Some(index) = device {
+                found = true;
+                select(n, index);
+            }
+        }
+        if !found {
+            return Err(format!("No OpenCL device matches {}", selector).into());
+        }
+    }
+    Ok(selected)
 }
 
-#[derive(Copy, Clone)]
+/**
+ Whether two devices are one GPU, by the PCI address or the UUID. Devices that cannot tell are never the same
+*/
+fn same_gpu(a: &DeviceIdentity, b: &DeviceIdentity) -> bool {
+    let same =
+        |a: &Option<String>, b: &Option<String>| matches!((a, b), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b));
+    same(&a.pci_bus_id, &b.pci_bus_id) || same(&a.uuid, &b.uuid)
+}
+
+#[derive(Clone)]
 struct OpenCLWorkerSpec {
     _platform: Platform,
+    platform: Option<usize>,
     index: usize,
     device_id: Device,
     workload: f32,
     is_absolute: bool,
     experimental_amd: bool,
-    use_amd_binary: bool,
     random: NonceGenEnum,
+    autotune: Option<f32>,
+    identity: DeviceIdentity,
+    cache: Option<ProgramCache>,
+}
+
+impl OpenCLWorkerSpec {
+    /**
+    Loads the saved workload of the device, or sweeps for one and saves it
+    */
+    fn tuned_workload(&self, budget_ms: f32) -> Result<usize, Error> {
+        let store = ProfileStore::new(ProfileStore::default_path("opencl"));
+        if let Some(profile) = store.get(&self.identity) {
+            info!("{}: Using saved workload {}", self.id(), profile.workload);
+            return Ok(profile.workload);
+        }
+
+        info!("{}: Tuning workload. This may take some time.", self.id());
+        let mut worker = OpenCLGPUWorker::new(
+            self.id(),
+            self.device_id,
+            1.,
+            false,
+            self.experimental_amd,
+            &NonceGenEnum::Lean,
+            self.cache.as_ref(),
+        )?;
+        let base = worker.get_workload();
+        let profile = tune_workload(&mut worker, base, budget_ms)?;
+        info!("{}: Tuned workload is {} ({:.2}ms per batch)", self.id(), profile.workload, profile.kernel_ms);
+        if let Err(e) = store.insert(&self.identity, profile) {
+            warn!("{}: Could not save workload profile: {}", self.id(), e);
+        }
+        Ok(profile.workload)
+    }
 }
 
 impl WorkerSpec for OpenCLWorkerSpec {
     fn id(&self) -> String {
+        let index = match self.platform {
+            Some(platform) => format!("{}/{}", platform, self.index),
+            None => self.index.to_string(),
+        };
         format!(
             "#{} {}",
-            self.index,
+            index,
             self.device_id
                 .board_name_amd()
                 .unwrap_or_else(|_| self.device_id.name().unwrap_or_else(|_| "Unknown Device".into()))
         )
     }
 
-    fn build(&self) -> Box<dyn Worker> {
-        Box::new(
-            OpenCLGPUWorker::new(
-                self.device_id,
-                self.workload,
-                self.is_absolute,
-                self.experimental_amd,
-                self.use_amd_binary,
-                &self.random,
-            )
-            .unwrap(),
-        )
+    fn build(&self) -> Result<Box<dyn Worker>, Error> {
+        let (workload, is_absolute) = match self.autotune.map(|budget| self.tuned_workload(budget)) {
+            Some(Ok(workload)) => (workload as f32, true),
+            Some(Err(e)) => {
+                warn!("{}: Auto-tune failed, using the configured workload: {}", self.id(), e);
+                (self.workload, self.is_absolute)
+            }
+            None => (self.workload, self.is_absolute),
+        };
+        Ok(Box::new(OpenCLGPUWorker::new(
+            self.id(),
+            self.device_id,
+            workload,
+            is_absolute,
+            self.experimental_amd,
+            &self.random,
+            self.cache.as_ref(),
+        )?))
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        self.identity.clone()
     }
 }
 
-declare_plugin!(OpenCLPlugin, OpenCLPlugin::new, OpenCLOpt);
+declare_plugin!(OpenCLPlugin, OpenCLPlugin::new, OpenCLOpt, "opencl");
+
+#[cfg(test)]
+mod tests {
+    use super::select_platform_devices;
+    use crate::cli::PlatformDevice;
+    use pyrin_miner::device::PerDevice;
+    use pyrin_miner::DeviceIdentity;
+
+    fn identity(pci_bus_id: &str) -> DeviceIdentity {
+        DeviceIdentity { pci_bus_id: Some(pci_bus_id.into()), uuid: None }
+    }
+
+    #[test]
+    fn test_select_platform_devices() {
+        let platforms =
+            [(0, vec![identity("0000:01:00.0"), identity("0000:02:00.0")]), (2, vec![identity("0000:03:00.0")])];
+        assert_eq!(select_platform_devices(None, &platforms).unwrap(), vec![(0, 0), (0, 1), (1, 0)]);
+        let selectors = ["2/0", "02:00.0", "0/0"].map(|s| s.parse::<PlatformDevice>().unwrap());
+        assert_eq!(select_platform_devices(Some(&selectors), &platforms).unwrap(), vec![(1, 0), (0, 1), (0, 0)]);
+        // An index without a platform picks that device on each platform
+        let selectors = ["0".parse::<PlatformDevice>().unwrap()];
+        assert_eq!(select_platform_devices(Some(&selectors), &platforms).unwrap(), vec![(0, 0), (1, 0)]);
+        let selectors = ["1/0".parse::<PlatformDevice>().unwrap()];
+        assert!(select_platform_devices(Some(&selectors), &platforms).is_err());
+        let selectors = ["2/01:00.0".parse::<PlatformDevice>().unwrap()];
+        assert!(select_platform_devices(Some(&selectors), &platforms).is_err());
+        assert!("amd/0".parse::<PlatformDevice>().is_err());
+    }
+
+    #[test]
+    fn test_select_same_gpu() {
+        let platforms = [
+            (0, vec![identity("0000:01:00.0"), identity("0000:02:00.0")]),
+            (1, vec![identity("0000:02:00.0"), DeviceIdentity::default()]),
+            (2, vec![DeviceIdentity::default()]),
+        ];
+        assert_eq!(select_platform_devices(None, &platforms).unwrap(), vec![(0, 0), (0, 1), (1, 1), (2, 0)]);
+        let selectors = ["1/0", "0/02:00.0"].map(|s| s.parse::<PlatformDevice>().unwrap());
+        assert_eq!(select_platform_devices(Some(&selectors), &platforms).unwrap(), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn test_platform_workloads() {
+        let values = ["256", "1/0=1024", "0=512"].map(String::from);
+        let workloads = PerDevice::<f32, PlatformDevice>::parse(&values).unwrap();
+        let (first, second) = (identity("0000:01:00.0"), identity("0000:02:00.0"));
+        assert_eq!(workloads.get_matching(0, |selector| selector.matches(0, 0, &first)), Some(512.));
+        assert_eq!(workloads.get_matching(1, |selector| selector.matches(1, 0, &second)), Some(512.));
+        assert_eq!(workloads.get_matching(1, |selector| selector.matches(1, 1, &second)), Some(256.));
+        let values = ["1/0=1024".to_string()];
+        let workloads = PerDevice::<f32, PlatformDevice>::parse(&values).unwrap();
+        assert_eq!(workloads.get_matching(0, |selector| selector.matches(0, 0, &first)), None);
+        assert_eq!(workloads.get_matching(0, |selector| selector.matches(1, 0, &second)), Some(1024.));
+        assert!(PerDevice::<f32, PlatformDevice>::parse(&["amd/0=512".into()]).is_err());
+    }
+}
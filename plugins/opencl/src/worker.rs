@@ -1,56 +1,90 @@
+use crate::cache::ProgramCache;
 use crate::cli::NonceGenEnum;
 use crate::Error;
-use include_dir::{include_dir, Dir};
 use pyrin_miner::xoshiro256starstar::Xoshiro256StarStar;
-use pyrin_miner::Worker;
+use pyrin_miner::{DeviceIdentity, Telemetry, Worker, WorkerError};
 use log::{info, warn};
-use opencl3::command_queue::{CommandQueue, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE};
+use opencl3::command_queue::{CommandQueue, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_PROFILING_ENABLE};
 use opencl3::context::Context;
 use opencl3::device::Device;
-use opencl3::event::{release_event, retain_event, wait_for_events};
+use opencl3::error_codes::{
+    ClError, CL_DEVICE_NOT_AVAILABLE, CL_DEVICE_NOT_FOUND, CL_INVALID_COMMAND_QUEUE, CL_INVALID_CONTEXT,
+    CL_INVALID_DEVICE, CL_OUT_OF_RESOURCES,
+};
+use opencl3::event::Event;
 use opencl3::kernel::{ExecuteKernel, Kernel};
 use opencl3::memory::{Buffer, ClMem, CL_MAP_WRITE, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE, CL_MEM_WRITE_ONLY};
 use opencl3::platform::Platform;
 use opencl3::program::{Program, CL_FINITE_MATH_ONLY, CL_MAD_ENABLE, CL_STD_2_0};
-use opencl3::types::{cl_event, cl_uchar, cl_ulong, CL_BLOCKING};
+use opencl3::types::{cl_event, cl_uchar, cl_ulong, CL_BLOCKING, CL_NON_BLOCKING};
 use rand::{thread_rng, Fill, RngCore};
-use std::borrow::Borrow;
+use std::collections::VecDeque;
 use std::ffi::c_void;
+use std::fs;
+use std::path::PathBuf;
 use std::ptr;
 use std::sync::Arc;
 
-static BINARY_DIR: Dir = include_dir!("./plugins/opencl/resources/bin/");
 static PROGRAM_SOURCE: &str = include_str!("../resources/pyrin-opencl.cl");
 
+// Batches queued at once, so the GPU never waits for the host between them
+const PIPELINE_DEPTH: usize = 2;
+// Nonces kept per batch after their count, as MAX_RESULTS in the kernel
+const MAX_RESULTS: usize = 16;
+
+// Result of one batch in flight
+struct Batch {
+    final_nonce: Buffer<cl_ulong>,
+    kernel_event: Option<Event>,
+}
+
 pub struct OpenCLGPUWorker {
+    id: String,
     context: Arc<Context>,
     random: NonceGenEnum,
     local_size: usize,
     workload: usize,
+    max_workload: usize,
+    kernel_time_ms: Option<f32>,
 
     heavy_hash: Kernel,
 
     queue: CommandQueue,
 
     random_state: Buffer<cl_ulong>,
-    final_nonce: Buffer<cl_ulong>,
     final_hash: Buffer<[cl_ulong; 4]>,
 
+    batches: Vec<Batch>,
+    // Batches launched and not collected yet, oldest first. Kernels run one after the other
+    in_flight: VecDeque<usize>,
+    next_batch: usize,
+
     hash_header: Buffer<cl_uchar>,
     matrix: Buffer<cl_uchar>,
     target: Buffer<cl_ulong>,
+    // The writes of the constants read from these host copies until they complete
+    hash_header_host: [cl_uchar; 72],
+    matrix_host: Vec<cl_uchar>,
+    target_host: [cl_ulong; 4],
+    constant_writes: Vec<Event>,
 
-    events: Vec<cl_event>,
     experimental_amd: bool,
+    pci_bus_id: Option<String>,
+    // Sysfs directory of the sensors of the device, AMD on Linux only
+    hwmon: Option<PathBuf>,
 }
 
 impl Worker for OpenCLGPUWorker {
     fn id(&self) -> String {
-        let device = Device::new(self.context.default_device());
-        device.name().unwrap()
+        self.id.clone()
     }
 
-    fn load_block_constants(&mut self, hash_header: &[u8; 72], matrix: &[[u16; 64]; 64], target: &[u64; 4]) {
+    fn load_block_constants(
+        &mut self,
+        hash_header: &[u8; 72],
+        matrix: &[[u16; 64]; 64],
+        target: &[u64; 4],
+    ) -> Result<(), WorkerError> {
         let cl_uchar_matrix = match self.experimental_amd {
             true => matrix
                 .iter()
@@ -58,45 +92,71 @@ impl Worker for OpenCLGPUWorker {
                 .collect::<Vec<cl_uchar>>(),
             false => matrix.iter().flat_map(|row| row.map(|v| v as cl_uchar)).collect::<Vec<cl_uchar>>(),
         };
-        self.queue
-            .enqueue_write_buffer(&mut self.final_nonce, CL_BLOCKING, 0, &[0], &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        self.queue
-            .enqueue_write_buffer(&mut self.hash_header, CL_BLOCKING, 0, hash_header, &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        self.queue
-            .enqueue_write_buffer(&mut self.matrix, CL_BLOCKING, 0, cl_uchar_matrix.as_slice(), &[])
-            .map_err(|e| e.to_string())
-            .unwrap()
-            .wait()
-            .unwrap();
-        let copy_target = self
-            .queue
-            .enqueue_write_buffer(&mut self.target, CL_BLOCKING, 0, target, &[])
-            .map_err(|e| e.to_string())
-            .unwrap();
-
-        self.events = vec![copy_target.get()];
-        for event in &self.events {
-            retain_event(*event).unwrap();
+        for write in self.constant_writes.drain(..) {
+            write.wait().map_err(device_error)?;
         }
+        self.hash_header_host = *hash_header;
+        self.matrix_host = cl_uchar_matrix;
+        self.target_host = *target;
+
+        // Batches already launched still hash the previous job
+        let queued = self.last_kernel().into_iter().collect::<Vec<cl_event>>();
+        self.constant_writes = vec![
+            self.queue
+                .enqueue_write_buffer(&mut self.hash_header, CL_NON_BLOCKING, 0, &self.hash_header_host, &queued)
+                .map_err(device_error)?,
+            self.queue
+                .enqueue_write_buffer(&mut self.matrix, CL_NON_BLOCKING, 0, self.matrix_host.as_slice(), &queued)
+                .map_err(device_error)?,
+            self.queue
+                .enqueue_write_buffer(&mut self.target, CL_NON_BLOCKING, 0, &self.target_host, &queued)
+                .map_err(device_error)?,
+        ];
+        Ok(())
     }
 
-    fn calculate_hash(&mut self, _nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64) {
+    fn calculate_hash(
+        &mut self,
+        _nonces: Option<&Vec<u64>>,
+        nonce_mask: u64,
+        nonce_fixed: u64,
+    ) -> Result<(), WorkerError> {
+        // A full pipeline drops its oldest batch
+        if self.in_flight.len() == self.batches.len() {
+            self.in_flight.pop_front();
+        }
+        let previous = self.last_kernel();
+        let index = self.next_batch;
+        if let Some(event) = self.batches[index].kernel_event.take() {
+            event.wait().map_err(device_error)?;
+        }
+
+        // Resets the solution count. Fill patterns are copied when enqueued, unlike the data of non blocking writes
+        let mut writes = vec![self
+            .queue
+            .enqueue_fill_buffer(&mut self.batches[index].final_nonce, &[0], 0, 8, &[])
+            .map_err(device_error)?];
         if self.random == NonceGenEnum::Lean {
-            self.queue
-                .enqueue_write_buffer(&mut self.random_state, CL_BLOCKING, 0, &[thread_rng().next_u64()], &[])
-                .map_err(|e| e.to_string())
-                .unwrap()
-                .wait()
-                .unwrap();
+            writes.push(
+                self.queue
+                    .enqueue_fill_buffer(
+                        &mut self.random_state,
+                        &[thread_rng().next_u64()],
+                        0,
+                        8,
+                        &previous.into_iter().collect::<Vec<cl_event>>(),
+                    )
+                    .map_err(device_error)?,
+            );
         }
+        let wait_list = self
+            .constant_writes
+            .iter()
+            .chain(writes.iter())
+            .map(|event| event.get())
+            .chain(previous)
+            .collect::<Vec<cl_event>>();
+
         let random_type: cl_uchar = match self.random {
             NonceGenEnum::Lean => 0,
             NonceGenEnum::Xoshiro => 1,
@@ -110,15 +170,16 @@ impl Worker for OpenCLGPUWorker {
             .set_arg(&self.target)
             .set_arg(&random_type)
             .set_arg(&self.random_state)
-            .set_arg(&self.final_nonce)
+            .set_arg(&self.batches[index].final_nonce)
             .set_arg(&self.final_hash)
             .set_global_work_size(self.workload)
-            .set_event_wait_list(self.events.borrow())
+            .set_event_wait_list(&wait_list)
             .enqueue_nd_range(&self.queue)
-            .map_err(|e| e.to_string())
-            .unwrap();
-
-        kernel_event.wait().unwrap();
+            .map_err(device_error)?;
+        self.queue.flush().map_err(device_error)?;
+        self.batches[index].kernel_event = Some(kernel_event);
+        self.next_batch = (index + 1) % self.batches.len();
+        self.in_flight.push_back(index);
 
         /*let mut nonces = [0u64; 1];
         let mut hash = [[0u64; 4]];
@@ -131,41 +192,183 @@ impl Worker for OpenCLGPUWorker {
         let event = kernel_event.get();
         self.events = vec!(event);
         retain_event(event);*/
+        Ok(())
     }
 
     fn sync(&self) -> Result<(), Error> {
-        wait_for_events(&self.events).map_err(|e| format!("waiting error code {}", e))?;
-        for event in &self.events {
-            release_event(*event).unwrap();
-        }
+        self.queue.finish().map_err(device_error)?;
+        Ok(())
+    }
+
+    fn pipeline_depth(&self) -> usize {
+        self.batches.len()
+    }
+
+    fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        nonces[0] = self.collect_nonces()?.first().copied().unwrap_or(0);
         Ok(())
     }
 
+    fn collect_nonces(&mut self) -> Result<Vec<u64>, Error> {
+        let index = self.in_flight.pop_front().ok_or("No batch in flight")?;
+        let kernel_event = self.batches[index].kernel_event.as_ref().ok_or("No batch in flight")?;
+        kernel_event.wait().map_err(device_error)?;
+        self.kernel_time_ms = match (kernel_event.profiling_command_start(), kernel_event.profiling_command_end()) {
+            (Ok(start), Ok(end)) => Some(end.saturating_sub(start) as f32 / 1e6),
+            _ => None,
+        };
+        self.read_nonces(index)
+    }
+
     fn get_workload(&self) -> usize {
         self.workload as usize
     }
 
+    fn kernel_time_ms(&self) -> Option<f32> {
+        self.kernel_time_ms
+    }
+
+    fn set_workload(&mut self, workload: usize) -> Result<(), Error> {
+        self.workload = workload.clamp(1, self.max_workload);
+        Ok(())
+    }
+
+    fn telemetry(&self) -> Option<Telemetry> {
+        let read = |file: &str| -> Option<f32> {
+            fs::read_to_string(self.hwmon.as_ref()?.join(file)).ok()?.trim().parse().ok()
+        };
+        Some(Telemetry {
+            temperature_c: read("temp1_input").map(|millidegrees| millidegrees / 1000.),
+            power_w: read("power1_average").or_else(|| read("power1_input")).map(|microwatts| microwatts / 1e6),
+            // The fan duty cycle, from 0 to pwm1_max
+            fan_percent: match (read("pwm1"), read("pwm1_max")) {
+                (Some(pwm), Some(max)) if max > 0. => Some(pwm * 100. / max),
+                _ => None,
+            },
+            core_clock_mhz: read("freq1_input").map(|hertz| (hertz / 1e6) as u32),
+            memory_clock_mhz: read("freq2_input").map(|hertz| (hertz / 1e6) as u32),
+            pci_bus_id: self.pci_bus_id.clone(),
+        })
+    }
+
     fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
-        self.queue
-            .enqueue_read_buffer(&self.final_nonce, CL_BLOCKING, 0, nonces, &[])
-            .map_err(|e| e.to_string())
-            .unwrap();
+        let index = *self.in_flight.back().ok_or("No batch in flight")?;
+        nonces[0] = self.read_nonces(index)?.first().copied().unwrap_or(0);
         Ok(())
     }
 }
 
+impl OpenCLGPUWorker {
+    // Nonces found by a batch, from its count and result slots
+    fn read_nonces(&self, index: usize) -> Result<Vec<u64>, Error> {
+        let batch = &self.batches[index];
+        let queued = batch.kernel_event.iter().map(|event| event.get()).collect::<Vec<cl_event>>();
+        let mut results = [0u64; 1 + MAX_RESULTS];
+        self.queue
+            .enqueue_read_buffer(&batch.final_nonce, CL_BLOCKING, 0, &mut results, &queued)
+            .map_err(device_error)?;
+        let count = (results[0] as usize).min(MAX_RESULTS);
+        Ok(results[1..=count].to_vec())
+    }
+
+    // Kernel of the latest batch still in flight
+    fn last_kernel(&self) -> Option<cl_event> {
+        let batch = &self.batches[*self.in_flight.back()?];
+        batch.kernel_event.as_ref().map(|event| event.get())
+    }
+}
+
+/**
+ Sorts a queue error by whether the device can go on with the same context
+*/
+fn device_error(e: ClError) -> WorkerError {
+    let error = format!("{} ({})", e, e.0).into();
+    match e.0 {
+        // Drivers report a faulted kernel as missing resources on the next call
+        CL_OUT_OF_RESOURCES | CL_DEVICE_NOT_AVAILABLE | CL_INVALID_COMMAND_QUEUE | CL_INVALID_CONTEXT => {
+            WorkerError::DeviceLost(error)
+        }
+        CL_DEVICE_NOT_FOUND | CL_INVALID_DEVICE => WorkerError::Fatal(error),
+        _ => WorkerError::Transient(error),
+    }
+}
+
+/**
+ The PCI address of a GPU, e.g. `0000:01:00.0`, from the AMD or NVIDIA extensions. Neither has the PCI domain,
+ GPUs sit on the first one on nearly every machine
+*/
+fn pci_bus_id(device: &Device) -> Option<String> {
+    if let Ok(topology) = device.topology_amd() {
+        return Some(format!(
+            "0000:{:02x}:{:02x}.{:x}",
+            topology.bus as u8, topology.device as u8, topology.function as u8
+        ));
+    }
+    match (device.pci_bus_id_nv(), device.pci_slot_id_nv()) {
+        (Ok(bus), Ok(slot)) => Some(format!("0000:{:02x}:{:02x}.0", bus, slot)),
+        _ => None,
+    }
+}
+
+/**
+ Names a GPU by its PCI address, OpenCL gives no UUID that NVML would recognize
+*/
+pub fn identity(device: &Device) -> DeviceIdentity {
+    DeviceIdentity { pci_bus_id: pci_bus_id(device), uuid: None }
+}
+
+/**
+ The hwmon directory of an AMD GPU, found from its PCI address and checked against its PCI device id
+*/
+#[cfg(target_os = "linux")]
+fn hwmon_dir(device: &Device, pci_bus_id: Option<&str>) -> Option<PathBuf> {
+    let pci = PathBuf::from("/sys/bus/pci/devices").join(pci_bus_id?);
+    let id = fs::read_to_string(pci.join("device")).ok()?;
+    if u32::from_str_radix(id.trim().trim_start_matches("0x"), 16).ok()? != device.pcie_id_amd().ok()? {
+        return None;
+    }
+    fs::read_dir(pci.join("hwmon")).ok()?.filter_map(Result::ok).map(|entry| entry.path()).next()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hwmon_dir(_device: &Device, _pci_bus_id: Option<&str>) -> Option<PathBuf> {
+    None
+}
+
+/**
+ The name of the device in lowercase, up to any `:`. That is the gfx target on AMD, e.g. `gfx1030` out of
+ `gfx1030:xnack-`
+*/
+pub fn gfx_target(device: &Device) -> String {
+    let device_name = device.name().unwrap_or_else(|_| "Unknown".into()).to_lowercase();
+    match device_name.split_once(':') {
+        Some((target, _)) => target.to_string(),
+        None => device_name,
+    }
+}
+
+/**
+ Nonces per batch that fill every compute unit, the base of a relative workload
+*/
+pub fn base_workload(device: &Device) -> Result<usize, Error> {
+    let local_size = device.max_work_group_size().map_err(|e| e.to_string())?;
+    Ok(local_size * device.max_compute_units().map_err(|e| e.to_string())? as usize)
+}
+
 impl OpenCLGPUWorker {
     pub fn new(
+        id: String,
         device: Device,
         workload: f32,
         is_absolute: bool,
         experimental_amd: bool,
-        mut use_binary: bool,
         random: &NonceGenEnum,
+        cache: Option<&ProgramCache>,
     ) -> Result<Self, Error> {
         let name =
             device.board_name_amd().unwrap_or_else(|_| device.name().unwrap_or_else(|_| "Unknown Device".into()));
         info!("{}: Using OpenCL", name);
+        let pci_bus_id = pci_bus_id(&device);
         let version = device.version().unwrap_or_else(|_| "unkown version".into());
         info!(
             "{}: Device supports {} with extensions: {}",
@@ -177,15 +380,10 @@ impl OpenCLGPUWorker {
         let local_size = device.max_work_group_size().map_err(|e| e.to_string())?;
         let chosen_workload = match is_absolute {
             true => workload as usize,
-            false => {
-                let max_work_group_size =
-                    (local_size * (device.max_compute_units().map_err(|e| e.to_string())? as usize)) as f32;
-                (workload * max_work_group_size) as usize
-            }
+            false => (workload * base_workload(&device)? as f32) as usize,
         };
         info!("{}: Chosen workload is {}", name, chosen_workload);
-        let context =
-            Arc::new(Context::from_device(&device).unwrap_or_else(|_| panic!("{}::Context::from_device failed", name)));
+        let context = Arc::new(Context::from_device(&device).map_err(device_error)?);
         let context_ref = unsafe { Arc::as_ptr(&context).as_ref().unwrap() };
 
         let options = match experimental_amd {
@@ -199,52 +397,45 @@ impl OpenCLGPUWorker {
             "tahiti" | "ellesmere" | "gfx1010" | "gfx906" | "gfx908"
         );
 
-        let program = match use_binary {
-            true => {
-                let mut device_name = device.name().unwrap_or_else(|_| "Unknown".into()).to_lowercase();
-                if device_name.contains(':') {
-                    device_name = device_name.split_once(':').expect("We checked for `:`").0.to_string();
-                }
-                info!("{}: Looking for binary for {}", name, device_name);
-                match BINARY_DIR.get_file(format!("{}_pyrin-opencl.bin", device_name)) {
-                    Some(binary) => {
-                        Program::create_and_build_from_binary(&context, &[binary.contents()], "").unwrap_or_else(|e|{
-                        //Program::create_and_build_from_binary(&context, &[include_bytes!("../resources/pyrin-opencl-linked.bc")], "").unwrap_or_else(|e|{
-                            warn!("{}::Program::create_and_build_from_source failed: {}. Reverting to compiling from source", name, e);
-                            use_binary = false;
-                            from_source(&context, &device, options).unwrap_or_else(|e| panic!("{}::Program::create_and_build_from_binary failed: {}", name, e))
-                        })
-                    }
-                    None => {
-                        warn!("Binary file not found for {}. Reverting to compiling from source.", device_name);
-                        use_binary = false;
-                        from_source(&context, &device, options)
-                            .unwrap_or_else(|e| panic!("{}::Program::create_and_build_from_binary failed: {}", name, e))
-                    }
-                }
-            }
-            false => from_source(&context, &device, options)
-                .unwrap_or_else(|e| panic!("{}::Program::create_and_build_from_binary failed: {}", name, e)),
+        // A kernel that does not build will not build on the next attempt either
+        let build_error = |e: String| {
+            WorkerError::Fatal(format!("{}::Program::create_and_build_from_source failed: {}", name, e).into())
         };
+        let program = from_source(&context, &device, options, cache).map_err(build_error)?;
         info!("Kernels: {:?}", program.kernel_names());
-        let heavy_hash =
-            Kernel::create(&program, "heavy_hash").unwrap_or_else(|_| panic!("{}::Kernel::create failed", name));
+        let heavy_hash = Kernel::create(&program, "heavy_hash").map_err(device_error)?;
 
-        let queue =
-            CommandQueue::create_with_properties(&context, device.id(), CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, 0)
-                .unwrap_or_else(|_| panic!("{}::CommandQueue::create_with_properties failed", name));
+        let queue = CommandQueue::create_with_properties(
+            &context,
+            device.id(),
+            CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE | CL_QUEUE_PROFILING_ENABLE,
+            0,
+        )
+        .map_err(device_error)?;
 
-        let final_nonce = Buffer::<cl_ulong>::create(context_ref, CL_MEM_READ_WRITE, 1, ptr::null_mut())
-            .expect("Buffer allocation failed");
+        let batches = (0..PIPELINE_DEPTH)
+            .map(|_| {
+                Ok(Batch {
+                    final_nonce: Buffer::<cl_ulong>::create(
+                        context_ref,
+                        CL_MEM_READ_WRITE,
+                        1 + MAX_RESULTS,
+                        ptr::null_mut(),
+                    )
+                    .map_err(device_error)?,
+                    kernel_event: None,
+                })
+            })
+            .collect::<Result<_, WorkerError>>()?;
         let final_hash = Buffer::<[cl_ulong; 4]>::create(context_ref, CL_MEM_WRITE_ONLY, 1, ptr::null_mut())
-            .expect("Buffer allocation failed");
+            .map_err(device_error)?;
 
-        let hash_header = Buffer::<cl_uchar>::create(context_ref, CL_MEM_READ_ONLY, 72, ptr::null_mut())
-            .expect("Buffer allocation failed");
+        let hash_header =
+            Buffer::<cl_uchar>::create(context_ref, CL_MEM_READ_ONLY, 72, ptr::null_mut()).map_err(device_error)?;
         let matrix = Buffer::<cl_uchar>::create(context_ref, CL_MEM_READ_ONLY, 64 * 64, ptr::null_mut())
-            .expect("Buffer allocation failed");
-        let target = Buffer::<cl_ulong>::create(context_ref, CL_MEM_READ_ONLY, 4, ptr::null_mut())
-            .expect("Buffer allocation failed");
+            .map_err(device_error)?;
+        let target =
+            Buffer::<cl_ulong>::create(context_ref, CL_MEM_READ_ONLY, 4, ptr::null_mut()).map_err(device_error)?;
 
         let mut seed = [1u64; 4];
         seed.try_fill(&mut rand::thread_rng())?;
@@ -254,7 +445,7 @@ impl OpenCLGPUWorker {
                 info!("Using xoshiro for nonce-generation");
                 let random_state =
                     Buffer::<cl_ulong>::create(context_ref, CL_MEM_READ_WRITE, 4 * chosen_workload, ptr::null_mut())
-                        .expect("Buffer allocation failed");
+                        .map_err(device_error)?;
                 let rand_state =
                     Xoshiro256StarStar::new(&seed).iter_jump_state().take(chosen_workload).collect::<Vec<[u64; 4]>>();
                 let mut random_state_local: *mut c_void = std::ptr::null_mut::<c_void>();
@@ -270,9 +461,9 @@ impl OpenCLGPUWorker {
                         &mut random_state_local,
                         &[],
                     )
-                    .map_err(|e| e.to_string())?
+                    .map_err(device_error)?
                     .wait()
-                    .unwrap();
+                    .map_err(device_error)?;
                 if random_state_local.is_null() {
                     return Err(format!("{}::could not load random state vector to memory. Consider changing random or lowering workload", name).into());
                 }
@@ -282,48 +473,70 @@ impl OpenCLGPUWorker {
                 // queue.enqueue_svm_unmap(&random_state,&[]).map_err(|e| e.to_string())?;
                 queue
                     .enqueue_unmap_mem_object(random_state.get(), random_state_local, &[])
-                    .map_err(|e| e.to_string())
-                    .unwrap()
+                    .map_err(device_error)?
                     .wait()
-                    .unwrap();
+                    .map_err(device_error)?;
                 info!("{}: Done generating initial seed", name);
                 random_state
             }
             NonceGenEnum::Lean => {
                 info!("Using lean nonce-generation");
                 let mut random_state = Buffer::<cl_ulong>::create(context_ref, CL_MEM_READ_WRITE, 1, ptr::null_mut())
-                    .expect("Buffer allocation failed");
+                    .map_err(device_error)?;
                 queue
                     .enqueue_write_buffer(&mut random_state, CL_BLOCKING, 0, &[thread_rng().next_u64()], &[])
-                    .map_err(|e| e.to_string())
-                    .unwrap()
+                    .map_err(device_error)?
                     .wait()
-                    .unwrap();
+                    .map_err(device_error)?;
                 random_state
             }
         };
         Ok(Self {
+            id,
             context,
             local_size,
             workload: chosen_workload,
+            max_workload: match random {
+                // Every thread needs its own xoshiro state
+                NonceGenEnum::Xoshiro => chosen_workload,
+                NonceGenEnum::Lean => usize::MAX,
+            },
+            kernel_time_ms: None,
             random: *random,
             heavy_hash,
             random_state,
             queue,
-            final_nonce,
             final_hash,
+            batches,
+            in_flight: VecDeque::with_capacity(PIPELINE_DEPTH),
+            next_batch: 0,
             hash_header,
             matrix,
             target,
-            events: Vec::<cl_event>::new(),
-            experimental_amd: ((experimental_amd | use_binary) & experimental_amd_use),
+            hash_header_host: [0; 72],
+            matrix_host: Vec::new(),
+            target_host: [0; 4],
+            constant_writes: Vec::new(),
+            experimental_amd: experimental_amd & experimental_amd_use,
+            hwmon: hwmon_dir(&device, pci_bus_id.as_deref()),
+            pci_bus_id,
         })
     }
 }
 
-fn from_source(context: &Context, device: &Device, options: &str) -> Result<Program, String> {
+/**
+ Builds the kernel from source with the options the device needs, or loads it from the cache if it was built with
+ the same ones before
+*/
+fn from_source(
+    context: &Context,
+    device: &Device,
+    options: &str,
+    cache: Option<&ProgramCache>,
+) -> Result<Program, String> {
     let version = device.version()?;
-    let v = version.split(' ').nth(1).unwrap();
+    // "OpenCL <major.minor> <platform specific>"
+    let v = version.split(' ').nth(1).unwrap_or_default();
     let mut compile_options = options.to_string();
     compile_options += CL_MAD_ENABLE;
     compile_options += CL_FINITE_MATH_ONLY;
@@ -331,7 +544,7 @@ fn from_source(context: &Context, device: &Device, options: &str) -> Result<Prog
         info!("Compiling with OpenCl 2");
         compile_options += CL_STD_2_0;
     }
-    compile_options += &match Platform::new(device.platform().unwrap()).name() {
+    compile_options += &match Platform::new(device.platform()?).name() {
         Ok(name) => format!(
             "-D{} ",
             name.chars()
@@ -362,7 +575,31 @@ fn from_source(context: &Context, device: &Device, options: &str) -> Result<Prog
         Err(_) => String::new(),
     };
 
+    let device_name = device.name()?;
+    let key =
+        ProgramCache::key(&device_name, &device.driver_version().unwrap_or_default(), &compile_options, PROGRAM_SOURCE);
+    if let Some(binary) = cache.and_then(|cache| cache.get(&key)) {
+        match Program::create_and_build_from_binary(context, &[binary.as_slice()], "") {
+            Ok(program) => {
+                info!("{}: Loaded the compiled kernel from the cache", device_name);
+                return Ok(program);
+            }
+            Err(e) => warn!("{}: Cannot load the cached kernel, building it again: {}", device_name, e),
+        }
+    }
+
     info!("Build OpenCL with {}", compile_options);
 
-    Program::create_and_build_from_source(context, PROGRAM_SOURCE, compile_options.as_str())
+    let program = Program::create_and_build_from_source(context, PROGRAM_SOURCE, compile_options.as_str())?;
+    if let Some(cache) = cache {
+        // The context holds a single device, so the program has a single binary
+        let saved = program
+            .get_binaries()
+            .map_err(|e| Error::from(e.to_string()))
+            .and_then(|binaries| cache.insert(&key, binaries.first().ok_or("The program has no binary")?));
+        if let Err(e) = saved {
+            warn!("{}: Cannot cache the compiled kernel: {}", device_name, e);
+        }
+    }
+    Ok(program)
 }
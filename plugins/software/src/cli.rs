@@ -0,0 +1,47 @@
+use crate::Error;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NonceGenEnum {
+    Lean,
+    Xoshiro,
+}
+
+impl FromStr for NonceGenEnum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lean" => Ok(Self::Lean),
+            "xoshiro" => Ok(Self::Xoshiro),
+            _ => Err("Unknown string".into()),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SoftwareOpt {
+    #[clap(
+        long = "software-enable",
+        help = "Enable software workers, which hash on the CPU the way a GPU worker does. Meant for testing"
+    )]
+    pub software_enable: bool,
+    #[clap(
+        long = "software-workers",
+        help = "Number of software workers, each on its own thread",
+        default_value = "1"
+    )]
+    pub software_workers: u16,
+    #[clap(
+        long = "software-workload",
+        help = "Nonces hashed in each batch of a software worker",
+        default_value = "4096"
+    )]
+    pub software_workload: usize,
+    #[clap(
+        long = "software-nonce-gen",
+        help = "The random method used to generate nonces, as in --cuda-nonce-gen. Options: xoshiro, lean",
+        default_value = "lean"
+    )]
+    pub software_nonce_gen: NonceGenEnum,
+}
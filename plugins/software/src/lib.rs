@@ -0,0 +1,81 @@
+#[macro_use]
+extern crate pyrin_miner;
+
+use clap::{ArgMatches, FromArgMatches};
+use log::LevelFilter;
+use pyrin_miner::{Plugin, Worker, WorkerSpec};
+use std::error::Error as StdError;
+
+pub type Error = Box<dyn StdError + Send + Sync + 'static>;
+
+mod cli;
+mod worker;
+
+use crate::cli::{NonceGenEnum, SoftwareOpt};
+use crate::worker::SoftwareWorker;
+
+/**
+ Workers hashing on the CPU through the same calls as the GPU workers, to run the plugin path and the mining
+ loop without a GPU. Also a small reference for writing plugins
+*/
+pub struct SoftwarePlugin {
+    specs: Vec<SoftwareWorkerSpec>,
+    _enabled: bool,
+}
+
+impl SoftwarePlugin {
+    fn new() -> Result<Self, Error> {
+        pyrin_miner::logging::init(LevelFilter::Info);
+        Ok(Self { specs: Vec::new(), _enabled: false })
+    }
+}
+
+impl Plugin for SoftwarePlugin {
+    fn name(&self) -> &'static str {
+        "Software Worker"
+    }
+
+    fn enabled(&self) -> bool {
+        self._enabled
+    }
+
+    fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
+        self.specs.iter().map(|spec| Box::new(*spec) as Box<dyn WorkerSpec>).collect::<Vec<Box<dyn WorkerSpec>>>()
+    }
+
+    fn process_option(&mut self, matches: &ArgMatches) -> Result<usize, pyrin_miner::Error> {
+        pyrin_miner::logging::configure(matches)?;
+        let opts: SoftwareOpt = SoftwareOpt::from_arg_matches(matches)?;
+
+        self._enabled = opts.software_enable;
+        if self._enabled {
+            self.specs = (0..opts.software_workers as usize)
+                .map(|index| SoftwareWorkerSpec {
+                    index,
+                    workload: opts.software_workload,
+                    random: opts.software_nonce_gen,
+                })
+                .collect();
+        }
+        Ok(self.specs.len())
+    }
+}
+
+#[derive(Copy, Clone)]
+struct SoftwareWorkerSpec {
+    index: usize,
+    workload: usize,
+    random: NonceGenEnum,
+}
+
+impl WorkerSpec for SoftwareWorkerSpec {
+    fn id(&self) -> String {
+        format!("#{} (software)", self.index)
+    }
+
+    fn build(&self) -> Result<Box<dyn Worker>, Error> {
+        Ok(Box::new(SoftwareWorker::new(self.index, self.workload, self.random)?))
+    }
+}
+
+declare_plugin!(SoftwarePlugin, SoftwarePlugin::new, SoftwareOpt, "software");
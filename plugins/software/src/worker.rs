@@ -0,0 +1,231 @@
+use crate::{Error, NonceGenEnum};
+use pyrin_miner::pow::{Matrix, PowHasher};
+use pyrin_miner::target::Uint256;
+use pyrin_miner::xoshiro256starstar::Xoshiro256StarStar;
+use pyrin_miner::{Hash, Worker, WorkerError};
+use rand::{Fill, RngCore};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+// Batches kept for collection at once, as the batches a GPU worker keeps in flight
+const PIPELINE_DEPTH: usize = 2;
+// Nonces kept per batch after their count, as MAX_RESULTS in the kernels
+const MAX_RESULTS: usize = 16;
+
+// The job as the kernels keep it in constant memory, ready for the reference PoW
+struct Constants {
+    hasher: PowHasher,
+    matrix: Matrix,
+    target: Uint256,
+}
+
+// Result of one batch, laid out as the final nonce buffer of the kernels: the count, then the nonces
+struct Batch {
+    final_nonces: [u64; 1 + MAX_RESULTS],
+    elapsed_ms: f32,
+}
+
+impl Batch {
+    fn nonces(&self) -> Vec<u64> {
+        let count = (self.final_nonces[0] as usize).min(MAX_RESULTS);
+        self.final_nonces[1..=count].to_vec()
+    }
+}
+
+/**
+ Hashes every batch on the calling thread as soon as it is launched, so constants loaded afterwards only apply to
+ the batches launched after them, like on a GPU stream
+*/
+pub struct SoftwareWorker {
+    index: usize,
+    constants: Option<Box<Constants>>,
+    batches: Vec<Batch>,
+    // Batches launched and not collected yet, oldest first
+    in_flight: VecDeque<usize>,
+    next_batch: usize,
+    last_collected: usize,
+    // One state per nonce of a batch, as each GPU thread has its own
+    rand_state: Vec<Xoshiro256StarStar>,
+    workload: usize,
+    random: NonceGenEnum,
+}
+
+impl Worker for SoftwareWorker {
+    fn id(&self) -> String {
+        format!("#{} (software)", self.index)
+    }
+
+    fn load_block_constants(
+        &mut self,
+        hash_header: &[u8; 72],
+        matrix: &[[u16; 64]; 64],
+        target: &[u64; 4],
+    ) -> Result<(), WorkerError> {
+        // PRE_POW_HASH || TIME || 32 zero byte padding
+        let pre_pow_hash = Hash::from_le_bytes(hash_header[..32].try_into().expect("32 bytes"));
+        let timestamp = u64::from_le_bytes(hash_header[32..40].try_into().expect("8 bytes"));
+        self.constants = Some(Box::new(Constants {
+            hasher: PowHasher::new(pre_pow_hash, timestamp),
+            matrix: Matrix(*matrix),
+            target: Uint256::new(*target),
+        }));
+        Ok(())
+    }
+
+    fn calculate_hash(
+        &mut self,
+        _nonces: Option<&Vec<u64>>,
+        nonce_mask: u64,
+        nonce_fixed: u64,
+    ) -> Result<(), WorkerError> {
+        let constants = self.constants.as_ref().ok_or_else(|| WorkerError::Fatal("No job was loaded".into()))?;
+        let start = Instant::now();
+        let seed = rand::thread_rng().next_u64();
+
+        // A full pipeline drops its oldest batch, as a GPU worker reuses its buffer
+        if self.in_flight.len() == self.batches.len() {
+            self.in_flight.pop_front();
+        }
+        let index = self.next_batch;
+        let final_nonces = &mut self.batches[index].final_nonces;
+        final_nonces[0] = 0;
+        for lane in 0..self.workload {
+            let nonce = match self.random {
+                NonceGenEnum::Lean => seed ^ lane as u64,
+                NonceGenEnum::Xoshiro => self.rand_state[lane].next_u64(),
+            };
+            let nonce = (nonce & nonce_mask) | nonce_fixed;
+            // The pow hash must be less or equal than the target
+            if constants.matrix.heavy_hash(constants.hasher.finalize_with_nonce(nonce)) <= constants.target {
+                let slot = final_nonces[0] as usize;
+                final_nonces[0] += 1;
+                if slot < MAX_RESULTS {
+                    final_nonces[1 + slot] = nonce;
+                }
+            }
+        }
+        self.batches[index].elapsed_ms = start.elapsed().as_secs_f32() * 1000.;
+        self.next_batch = (index + 1) % self.batches.len();
+        self.in_flight.push_back(index);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        // Batches are done by the time calculate_hash returns
+        Ok(())
+    }
+
+    fn pipeline_depth(&self) -> usize {
+        self.batches.len()
+    }
+
+    fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        nonces[0] = self.collect_nonces()?.first().copied().unwrap_or(0);
+        Ok(())
+    }
+
+    fn collect_nonces(&mut self) -> Result<Vec<u64>, Error> {
+        let index = self.in_flight.pop_front().ok_or("No batch in flight")?;
+        self.last_collected = index;
+        Ok(self.batches[index].nonces())
+    }
+
+    fn get_workload(&self) -> usize {
+        self.workload
+    }
+
+    fn kernel_time_ms(&self) -> Option<f32> {
+        Some(self.batches[self.last_collected].elapsed_ms)
+    }
+
+    fn set_workload(&mut self, workload: usize) -> Result<(), Error> {
+        let workload = workload.clamp(1, u32::MAX as usize);
+        if self.random == NonceGenEnum::Xoshiro && workload > self.rand_state.len() {
+            // The new states jump on from the last one, so no two of them overlap
+            let last = *self.rand_state.last().ok_or("No xoshiro state to extend")?;
+            let extra = workload - self.rand_state.len();
+            self.rand_state.extend(last.iter_jump_state().take(extra).map(|state| Xoshiro256StarStar::new(&state)));
+        }
+        self.workload = workload;
+        Ok(())
+    }
+
+    fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        let index = *self.in_flight.back().ok_or("No batch in flight")?;
+        nonces[0] = self.batches[index].nonces().first().copied().unwrap_or(0);
+        Ok(())
+    }
+}
+
+impl SoftwareWorker {
+    pub fn new(index: usize, workload: usize, random: NonceGenEnum) -> Result<Self, Error> {
+        let workload = workload.clamp(1, u32::MAX as usize);
+        let rand_state = match random {
+            NonceGenEnum::Xoshiro => {
+                let mut seed = [1u64; 4];
+                seed.try_fill(&mut rand::thread_rng())?;
+                Xoshiro256StarStar::new(&seed)
+                    .iter_jump_state()
+                    .take(workload)
+                    .map(|state| Xoshiro256StarStar::new(&state))
+                    .collect()
+            }
+            NonceGenEnum::Lean => Vec::new(),
+        };
+        Ok(Self {
+            index,
+            constants: None,
+            batches: (0..PIPELINE_DEPTH)
+                .map(|_| Batch { final_nonces: [0; 1 + MAX_RESULTS], elapsed_ms: 0. })
+                .collect(),
+            in_flight: VecDeque::with_capacity(PIPELINE_DEPTH),
+            next_batch: 0,
+            last_collected: 0,
+            rand_state,
+            workload,
+            random,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SoftwareWorker, MAX_RESULTS};
+    use crate::NonceGenEnum;
+    use pyrin_miner::pow::State;
+    use pyrin_miner::Worker;
+
+    #[test]
+    fn test_known_answer() {
+        let state = State::known_answer().unwrap();
+        let mut worker = SoftwareWorker::new(0, 4096, NonceGenEnum::Lean).unwrap();
+        state.load_to_gpu(&mut worker).unwrap();
+        state.pow_gpu(&mut worker).unwrap();
+        // About one in 256 hashes is below the target of the job
+        let nonces = worker.collect_nonces().unwrap();
+        assert!(!nonces.is_empty());
+        assert!(nonces.iter().all(|&nonce| state.check_pow(nonce)));
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut worker = SoftwareWorker::new(0, 256, NonceGenEnum::Xoshiro).unwrap();
+        // Every hash is below the largest target, so the batch keeps as many nonces as it can
+        worker.load_block_constants(&[42; 72], &[[1; 64]; 64], &[u64::MAX; 4]).unwrap();
+        worker.calculate_hash(None, 0xffff_0000, 0x1234).unwrap();
+        // Constants loaded after a launch only apply to the batches launched after them
+        worker.load_block_constants(&[42; 72], &[[1; 64]; 64], &[0; 4]).unwrap();
+        worker.calculate_hash(None, u64::MAX, 0).unwrap();
+
+        let nonces = worker.collect_nonces().unwrap();
+        assert_eq!(nonces.len(), MAX_RESULTS);
+        assert!(nonces.iter().all(|nonce| nonce & !0xffff_0000 == 0x1234));
+        assert!(worker.collect_nonces().unwrap().is_empty());
+        assert!(worker.collect_nonces().is_err());
+
+        worker.set_workload(512).unwrap();
+        assert_eq!(worker.get_workload(), 512);
+        worker.calculate_hash(None, u64::MAX, 0).unwrap();
+        assert!(worker.collect_nonces().unwrap().is_empty());
+    }
+}
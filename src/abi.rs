@@ -0,0 +1,984 @@
+use crate::logging::{self, LogFormat, LogOpt};
+use crate::{DeviceIdentity, DeviceInfo, Error, Plugin, Telemetry, Worker, WorkerError, WorkerSpec};
+use clap::{App, Arg, ArgMatches, Args, FromArgMatches, ValueSource};
+use libloading::{Library, Symbol};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/**
+ Version of the interface between the miner and its plugins. Any change to `PluginDeclaration` or to one of the
+ vtables bumps it, and plugins built for another version are refused before anything else in them is called
+*/
+pub const ABI_VERSION: u32 = 5;
+
+/// `u32` exported by every plugin, see `declare_plugin!`
+pub const ABI_VERSION_SYMBOL: &[u8] = b"PYRIN_PLUGIN_ABI_VERSION\0";
+/// `PluginDeclaration` exported by every plugin, see `declare_plugin!`
+pub const DECLARATION_SYMBOL: &[u8] = b"PYRIN_PLUGIN_DECLARATION\0";
+
+/// Opaque object owned by the plugin, only ever passed back to the vtable it came from
+pub type Handle = *mut c_void;
+
+/// Outcome of a fallible call. Anything but `STATUS_OK` comes with a message written to the error sink
+pub type Status = u32;
+pub const STATUS_OK: Status = 0;
+pub const STATUS_TRANSIENT: Status = 1;
+pub const STATUS_DEVICE_LOST: Status = 2;
+pub const STATUS_FATAL: Status = 3;
+
+/**
+ Callback the callee writes its output to. The items are only lent for the duration of the call, so nothing
+ allocated on one side of the boundary is ever freed on the other
+*/
+#[repr(C)]
+pub struct Sink<T> {
+    ctx: *mut c_void,
+    write: unsafe extern "C" fn(*mut c_void, *const T, usize),
+}
+
+impl<T: Copy> Sink<T> {
+    fn new(target: &mut Vec<T>) -> Self {
+        Sink { ctx: target as *mut Vec<T> as *mut c_void, write: extend::<T> }
+    }
+}
+
+impl<T> Sink<T> {
+    fn send(&self, items: &[T]) {
+        unsafe { (self.write)(self.ctx, items.as_ptr(), items.len()) }
+    }
+}
+
+unsafe extern "C" fn extend<T: Copy>(ctx: *mut c_void, items: *const T, len: usize) {
+    if len > 0 {
+        (*(ctx as *mut Vec<T>)).extend_from_slice(slice::from_raw_parts(items, len));
+    }
+}
+
+/**
+ Miner function a plugin hands its log lines to while they are captured for `--tui`
+*/
+pub type LogCallback = unsafe extern "C" fn(Str);
+
+/**
+ UTF-8 string lent to the callee
+*/
+#[repr(C)]
+pub struct Str {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Str {
+    fn new(s: &str) -> Self {
+        Str { ptr: s.as_ptr(), len: s.len() }
+    }
+
+    unsafe fn as_str<'a>(&self) -> Result<&'a str, Error> {
+        Ok(std::str::from_utf8(slice::from_raw_parts(self.ptr, self.len))?)
+    }
+}
+
+/**
+ Everything the miner knows about a plugin library. Exported as `PYRIN_PLUGIN_DECLARATION` by `declare_plugin!`
+*/
+#[repr(C)]
+pub struct PluginDeclaration {
+    /// Nul terminated package name of the plugin
+    pub name: *const c_char,
+    /// Nul terminated package version of the plugin
+    pub version: *const c_char,
+    /// Nul terminated, comma separated backends the plugin mines with, e.g. `cuda`
+    pub backends: *const c_char,
+    pub plugin: PluginVTable,
+    pub spec: SpecVTable,
+    pub worker: WorkerVTable,
+}
+
+// Only holds pointers to static strings and functions
+unsafe impl Sync for PluginDeclaration {}
+
+#[repr(C)]
+pub struct PluginVTable {
+    /// Returns a null handle and writes the reason to the sink if the plugin cannot run on this machine
+    pub create: unsafe extern "C" fn(Sink<u8>) -> Handle,
+    pub destroy: unsafe extern "C" fn(Handle),
+    pub name: unsafe extern "C" fn(Handle, Sink<u8>),
+    /// Writes the options of the plugin as a JSON list of `OptionSpec`
+    pub options: unsafe extern "C" fn(Sink<u8>),
+    /// Takes the command line of the plugin as a JSON list of strings, and writes how many workers it found
+    pub configure: unsafe extern "C" fn(Handle, Str, *mut usize, Sink<u8>) -> Status,
+    pub enabled: unsafe extern "C" fn(Handle) -> bool,
+    /// Writes one handle per worker spec, each released with `SpecVTable::destroy`
+    pub worker_specs: unsafe extern "C" fn(Handle, Sink<Handle>),
+    /// Writes every device of the backend as a JSON list of `DeviceInfo`
+    pub list_devices: unsafe extern "C" fn(Handle, Sink<u8>, Sink<u8>) -> Status,
+    /// Called once before `create`, with where the plugin sends its captured log lines
+    pub set_log_callback: unsafe extern "C" fn(LogCallback),
+}
+
+#[repr(C)]
+pub struct SpecVTable {
+    pub destroy: unsafe extern "C" fn(Handle),
+    pub id: unsafe extern "C" fn(Handle, Sink<u8>),
+    /// Returns a null handle and writes the reason to the sink if the worker could not be built
+    pub build: unsafe extern "C" fn(Handle, Sink<u8>) -> Handle,
+    /// Writes the `DeviceIdentity` of the spec as JSON
+    pub identity: unsafe extern "C" fn(Handle, Sink<u8>),
+}
+
+/**
+ The `Worker` trait, method by method. Nonces passed as a pointer and a length are never resized by the plugin
+*/
+#[repr(C)]
+pub struct WorkerVTable {
+    pub destroy: unsafe extern "C" fn(Handle),
+    pub id: unsafe extern "C" fn(Handle, Sink<u8>),
+    pub load_block_constants:
+        unsafe extern "C" fn(Handle, *const [u8; 72], *const [[u16; 64]; 64], *const [u64; 4], Sink<u8>) -> Status,
+    /// The nonces are only given to the worker when the flag is set
+    pub calculate_hash: unsafe extern "C" fn(Handle, *const u64, usize, bool, u64, u64, Sink<u8>) -> Status,
+    pub sync: unsafe extern "C" fn(Handle, Sink<u8>) -> Status,
+    pub get_workload: unsafe extern "C" fn(Handle) -> usize,
+    pub copy_output_to: unsafe extern "C" fn(Handle, *mut u64, usize, Sink<u8>) -> Status,
+    pub pipeline_depth: unsafe extern "C" fn(Handle) -> usize,
+    pub finish_batch: unsafe extern "C" fn(Handle, *mut u64, usize, Sink<u8>) -> Status,
+    pub collect_nonces: unsafe extern "C" fn(Handle, Sink<u64>, Sink<u8>) -> Status,
+    /// Returns false if the worker cannot measure it
+    pub kernel_time_ms: unsafe extern "C" fn(Handle, *mut f32) -> bool,
+    pub set_workload: unsafe extern "C" fn(Handle, usize, Sink<u8>) -> Status,
+    pub backoff: unsafe extern "C" fn(Handle) -> bool,
+    /// Writes the readings as a JSON `Telemetry`, or nothing if the worker cannot read its sensors
+    pub telemetry: unsafe extern "C" fn(Handle, Sink<u8>),
+}
+
+/**
+ A command line option of a plugin. The miner adds it to its own command line and hands the values back as text,
+ so the plugin parses them with its own clap
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OptionSpec {
+    pub long: String,
+    pub help: Option<String>,
+    pub long_help: Option<String>,
+    pub takes_value: bool,
+    pub multiple: bool,
+    pub delimited: bool,
+    pub default_value: Option<String>,
+    pub possible_values: Vec<String>,
+}
+
+impl OptionSpec {
+    /**
+    Describes the long options of a clap app
+    */
+    pub fn from_app(app: &App) -> Vec<OptionSpec> {
+        app.get_arguments()
+            .filter_map(|arg| {
+                Some(OptionSpec {
+                    long: arg.get_long()?.to_string(),
+                    help: arg.get_help().map(String::from),
+                    long_help: arg.get_long_help().map(String::from),
+                    takes_value: arg.is_takes_value_set(),
+                    multiple: arg.is_multiple_occurrences_set(),
+                    delimited: arg.is_use_value_delimiter_set(),
+                    default_value: arg.get_default_values().first().map(|value| value.to_string_lossy().into_owned()),
+                    possible_values: arg
+                        .get_possible_values()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|value| value.get_name().to_string())
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    fn to_arg<'help>(&self) -> Arg<'help> {
+        // The miner keeps its command line until it exits
+        fn leak(s: &str) -> &'static str {
+            Box::leak(s.to_string().into_boxed_str())
+        }
+        let long = leak(&self.long);
+        #[allow(deprecated)]
+        let mut arg = Arg::new(long)
+            .long(long)
+            .takes_value(self.takes_value)
+            .multiple_occurrences(self.multiple)
+            .use_value_delimiter(self.delimited);
+        if let Some(help) = &self.help {
+            arg = arg.help(leak(help));
+        }
+        if let Some(long_help) = &self.long_help {
+            arg = arg.long_help(leak(long_help));
+        }
+        if let Some(default_value) = &self.default_value {
+            arg = arg.default_value(leak(default_value));
+        }
+        if !self.possible_values.is_empty() {
+            arg = arg.possible_values(self.possible_values.iter().map(|value| leak(value)));
+        }
+        arg
+    }
+
+    /**
+    Writes the option back as command line arguments, if it was given. Defaults are left to the plugin
+    */
+    fn command_line(&self, matches: &ArgMatches, args: &mut Vec<String>) {
+        if matches.value_source(&self.long) != Some(ValueSource::CommandLine) {
+            return;
+        }
+        if !self.takes_value {
+            args.push(format!("--{}", self.long));
+            return;
+        }
+        #[allow(deprecated)]
+        let values = matches.values_of(&self.long).map(|values| values.collect::<Vec<&str>>()).unwrap_or_default();
+        match self.multiple {
+            true => args.extend(values.iter().map(|value| format!("--{}={}", self.long, value))),
+            false => args.push(format!("--{}={}", self.long, values.join(","))),
+        }
+    }
+}
+
+/**
+ Name, version and backends of a loaded plugin
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: String,
+    pub backends: Vec<String>,
+}
+
+/**
+ A plugin created through its declaration
+*/
+pub(crate) struct LoadedPlugin {
+    handle: Handle,
+    declaration: *const PluginDeclaration,
+    pub(crate) name: String,
+    pub(crate) metadata: PluginMetadata,
+    options: Vec<OptionSpec>,
+}
+
+// The plugin object behind the handle is a `Plugin`, which is Send and Sync
+unsafe impl Send for LoadedPlugin {}
+unsafe impl Sync for LoadedPlugin {}
+
+impl LoadedPlugin {
+    /**
+    Checks the ABI version of a library before reading its declaration and creating its plugin
+    */
+    pub(crate) unsafe fn load(lib: &Library) -> Result<Self, Error> {
+        let version: Symbol<*const u32> = lib.get(ABI_VERSION_SYMBOL).map_err(|e| {
+            format!("Not a plugin, or built for a miner older than plugin ABI version {}: {}", ABI_VERSION, e)
+        })?;
+        if **version != ABI_VERSION {
+            return Err(format!("Built for plugin ABI version {}, the miner uses {}", **version, ABI_VERSION).into());
+        }
+        let declaration: Symbol<*const PluginDeclaration> = lib.get(DECLARATION_SYMBOL)?;
+        Self::from_declaration(*declaration)
+    }
+
+    pub(crate) unsafe fn from_declaration(declaration: *const PluginDeclaration) -> Result<Self, Error> {
+        let vtable = &(*declaration).plugin;
+        let metadata = PluginMetadata {
+            name: CStr::from_ptr((*declaration).name).to_string_lossy().into_owned(),
+            version: CStr::from_ptr((*declaration).version).to_string_lossy().into_owned(),
+            backends: CStr::from_ptr((*declaration).backends)
+                .to_string_lossy()
+                .split(',')
+                .filter(|backend| !backend.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        let options = serde_json::from_str(&read_string(|sink| (vtable.options)(sink)))?;
+        (vtable.set_log_callback)(capture_log_line);
+
+        let mut error = Vec::new();
+        let handle = (vtable.create)(Sink::new(&mut error));
+        if handle.is_null() {
+            return Err(String::from_utf8_lossy(&error).into_owned().into());
+        }
+        let name = read_string(|sink| (vtable.name)(handle, sink));
+        Ok(Self { handle, declaration, name, metadata, options })
+    }
+
+    fn declaration(&self) -> &PluginDeclaration {
+        unsafe { &*self.declaration }
+    }
+
+    pub(crate) fn augment_args<'help>(&self, app: App<'help>) -> App<'help> {
+        app.args(self.options.iter().map(OptionSpec::to_arg))
+    }
+
+    /**
+    Hands the options of the plugin, and the log options shared by all, back to it
+    */
+    pub(crate) fn configure(&mut self, matches: &ArgMatches) -> Result<usize, Error> {
+        let mut args = Vec::new();
+        // Applications embedding the miner may leave the log options out of their command line
+        if matches.try_contains_id("log-format").unwrap_or(false) {
+            let log = LogOpt::from_arg_matches(matches)?;
+            args.push(format!("--log-format={}", if log.log_format == LogFormat::Json { "json" } else { "text" }));
+            if log.tui {
+                args.push("--tui".into());
+            }
+        }
+        for option in &self.options {
+            option.command_line(matches, &mut args);
+        }
+        let args = serde_json::to_string(&args)?;
+        let mut count = 0usize;
+        call(|error| unsafe { (self.declaration().plugin.configure)(self.handle, Str::new(&args), &mut count, error) })
+            .map_err(into_error)?;
+        Ok(count)
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        unsafe { (self.declaration().plugin.enabled)(self.handle) }
+    }
+
+    pub(crate) fn worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
+        let mut handles = Vec::new();
+        unsafe { (self.declaration().plugin.worker_specs)(self.handle, Sink::new(&mut handles)) };
+        handles
+            .into_iter()
+            .map(|handle| Box::new(AbiWorkerSpec { handle, declaration: self.declaration }) as Box<dyn WorkerSpec>)
+            .collect()
+    }
+
+    pub(crate) fn list_devices(&self) -> Result<Vec<DeviceInfo>, Error> {
+        let mut json = Vec::new();
+        call(|error| unsafe { (self.declaration().plugin.list_devices)(self.handle, Sink::new(&mut json), error) })
+            .map_err(into_error)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+impl Drop for LoadedPlugin {
+    fn drop(&mut self) {
+        unsafe { (self.declaration().plugin.destroy)(self.handle) }
+    }
+}
+
+struct AbiWorkerSpec {
+    handle: Handle,
+    declaration: *const PluginDeclaration,
+}
+
+// The spec behind the handle is a `WorkerSpec`, which is Send and Sync
+unsafe impl Send for AbiWorkerSpec {}
+unsafe impl Sync for AbiWorkerSpec {}
+
+impl AbiWorkerSpec {
+    fn vtable(&self) -> &SpecVTable {
+        unsafe { &(*self.declaration).spec }
+    }
+}
+
+impl WorkerSpec for AbiWorkerSpec {
+    fn id(&self) -> String {
+        read_string(|sink| unsafe { (self.vtable().id)(self.handle, sink) })
+    }
+
+    fn build(&self) -> Result<Box<dyn Worker>, Error> {
+        let mut error = Vec::new();
+        let handle = unsafe { (self.vtable().build)(self.handle, Sink::new(&mut error)) };
+        if handle.is_null() {
+            return Err(String::from_utf8_lossy(&error).into_owned().into());
+        }
+        Ok(Box::new(AbiWorker { handle, declaration: self.declaration }))
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        let json = read_string(|sink| unsafe { (self.vtable().identity)(self.handle, sink) });
+        serde_json::from_str(&json).unwrap_or_default()
+    }
+}
+
+impl Drop for AbiWorkerSpec {
+    fn drop(&mut self) {
+        unsafe { (self.vtable().destroy)(self.handle) }
+    }
+}
+
+struct AbiWorker {
+    handle: Handle,
+    declaration: *const PluginDeclaration,
+}
+
+impl AbiWorker {
+    fn vtable(&self) -> &WorkerVTable {
+        unsafe { &(*self.declaration).worker }
+    }
+}
+
+impl Worker for AbiWorker {
+    fn id(&self) -> String {
+        read_string(|sink| unsafe { (self.vtable().id)(self.handle, sink) })
+    }
+
+    fn load_block_constants(
+        &mut self,
+        hash_header: &[u8; 72],
+        matrix: &[[u16; 64]; 64],
+        target: &[u64; 4],
+    ) -> Result<(), WorkerError> {
+        call(|error| unsafe { (self.vtable().load_block_constants)(self.handle, hash_header, matrix, target, error) })
+    }
+
+    fn calculate_hash(
+        &mut self,
+        nonces: Option<&Vec<u64>>,
+        nonce_mask: u64,
+        nonce_fixed: u64,
+    ) -> Result<(), WorkerError> {
+        let (ptr, len) = nonces.map_or((ptr::null(), 0), |nonces| (nonces.as_ptr(), nonces.len()));
+        call(|error| unsafe {
+            (self.vtable().calculate_hash)(self.handle, ptr, len, nonces.is_some(), nonce_mask, nonce_fixed, error)
+        })
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        call(|error| unsafe { (self.vtable().sync)(self.handle, error) }).map_err(into_error)
+    }
+
+    fn get_workload(&self) -> usize {
+        unsafe { (self.vtable().get_workload)(self.handle) }
+    }
+
+    fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        call(|error| unsafe { (self.vtable().copy_output_to)(self.handle, nonces.as_mut_ptr(), nonces.len(), error) })
+            .map_err(into_error)
+    }
+
+    fn pipeline_depth(&self) -> usize {
+        unsafe { (self.vtable().pipeline_depth)(self.handle) }
+    }
+
+    fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        call(|error| unsafe { (self.vtable().finish_batch)(self.handle, nonces.as_mut_ptr(), nonces.len(), error) })
+            .map_err(into_error)
+    }
+
+    fn collect_nonces(&mut self) -> Result<Vec<u64>, Error> {
+        let mut nonces = Vec::new();
+        call(|error| unsafe { (self.vtable().collect_nonces)(self.handle, Sink::new(&mut nonces), error) })
+            .map_err(into_error)?;
+        Ok(nonces)
+    }
+
+    fn kernel_time_ms(&self) -> Option<f32> {
+        let mut kernel_ms = 0f32;
+        unsafe { (self.vtable().kernel_time_ms)(self.handle, &mut kernel_ms) }.then_some(kernel_ms)
+    }
+
+    fn set_workload(&mut self, workload: usize) -> Result<(), Error> {
+        call(|error| unsafe { (self.vtable().set_workload)(self.handle, workload, error) }).map_err(into_error)
+    }
+
+    fn backoff(&mut self) -> bool {
+        unsafe { (self.vtable().backoff)(self.handle) }
+    }
+
+    fn telemetry(&self) -> Option<Telemetry> {
+        let json = read_string(|sink| unsafe { (self.vtable().telemetry)(self.handle, sink) });
+        serde_json::from_str(&json).ok()
+    }
+}
+
+impl Drop for AbiWorker {
+    fn drop(&mut self) {
+        unsafe { (self.vtable().destroy)(self.handle) }
+    }
+}
+
+unsafe extern "C" fn capture_log_line(line: Str) {
+    guard_or((), || logging::capture(String::from_utf8_lossy(slice::from_raw_parts(line.ptr, line.len)).into_owned()))
+}
+
+fn read_string(f: impl FnOnce(Sink<u8>)) -> String {
+    let mut bytes = Vec::new();
+    f(Sink::new(&mut bytes));
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn call(f: impl FnOnce(Sink<u8>) -> Status) -> Result<(), WorkerError> {
+    let mut message = Vec::new();
+    let status = f(Sink::new(&mut message));
+    let error: Error = String::from_utf8_lossy(&message).into_owned().into();
+    match status {
+        STATUS_OK => Ok(()),
+        STATUS_DEVICE_LOST => Err(WorkerError::DeviceLost(error)),
+        STATUS_FATAL => Err(WorkerError::Fatal(error)),
+        _ => Err(WorkerError::Transient(error)),
+    }
+}
+
+// Keeps the kind of the error for `WorkerError::classify` on the miner side
+fn into_error(error: WorkerError) -> Error {
+    match error {
+        WorkerError::Transient(e) => e,
+        error => Box::new(error),
+    }
+}
+
+/*
+ Plugin side of the interface, compiled into each plugin through `declare_plugin!`. Every call is guarded, so a
+ panic in a plugin is returned as a fatal error instead of unwinding into the miner
+*/
+
+/**
+ Creates the plugin and names its arguments, implemented by `declare_plugin!`
+*/
+pub trait PluginEntry {
+    type Args: Args;
+    fn create() -> Result<Box<dyn Plugin>, Error>;
+}
+
+impl PluginVTable {
+    pub const fn of<E: PluginEntry>() -> Self {
+        PluginVTable {
+            create: plugin_create::<E>,
+            destroy: plugin_destroy,
+            name: plugin_name,
+            options: plugin_options::<E>,
+            configure: plugin_configure::<E>,
+            enabled: plugin_enabled,
+            worker_specs: plugin_worker_specs,
+            list_devices: plugin_list_devices,
+            set_log_callback: plugin_set_log_callback,
+        }
+    }
+}
+
+impl SpecVTable {
+    pub const fn new() -> Self {
+        SpecVTable { destroy: spec_destroy, id: spec_id, build: spec_build, identity: spec_identity }
+    }
+}
+
+impl Default for SpecVTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorkerVTable {
+    pub const fn new() -> Self {
+        WorkerVTable {
+            destroy: worker_destroy,
+            id: worker_id,
+            load_block_constants: worker_load_block_constants,
+            calculate_hash: worker_calculate_hash,
+            sync: worker_sync,
+            get_workload: worker_get_workload,
+            copy_output_to: worker_copy_output_to,
+            pipeline_depth: worker_pipeline_depth,
+            finish_batch: worker_finish_batch,
+            collect_nonces: worker_collect_nonces,
+            kernel_time_ms: worker_kernel_time_ms,
+            set_workload: worker_set_workload,
+            backoff: worker_backoff,
+            telemetry: worker_telemetry,
+        }
+    }
+}
+
+impl Default for WorkerVTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => format!("panicked: {}", message),
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => format!("panicked: {}", message),
+            Err(_) => "panicked".into(),
+        },
+    }
+}
+
+fn guard(error: Sink<u8>, f: impl FnOnce() -> Result<(), WorkerError>) -> Status {
+    let result =
+        catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| Err(WorkerError::Fatal(panic_message(panic).into())));
+    let (status, message) = match result {
+        Ok(()) => return STATUS_OK,
+        Err(WorkerError::Transient(e)) => (STATUS_TRANSIENT, e.to_string()),
+        Err(WorkerError::DeviceLost(e)) => (STATUS_DEVICE_LOST, e.to_string()),
+        Err(WorkerError::Fatal(e)) => (STATUS_FATAL, e.to_string()),
+    };
+    error.send(message.as_bytes());
+    status
+}
+
+fn guard_or<T>(default: T, f: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+unsafe fn plugin<'a>(handle: Handle) -> &'a mut Box<dyn Plugin> {
+    &mut *(handle as *mut Box<dyn Plugin>)
+}
+
+unsafe fn spec<'a>(handle: Handle) -> &'a dyn WorkerSpec {
+    (*(handle as *mut Box<dyn WorkerSpec>)).as_ref()
+}
+
+unsafe fn worker<'a>(handle: Handle) -> &'a mut dyn Worker {
+    (*(handle as *mut Box<dyn Worker>)).as_mut()
+}
+
+unsafe extern "C" fn plugin_create<E: PluginEntry>(error: Sink<u8>) -> Handle {
+    let message = match catch_unwind(E::create) {
+        Ok(Ok(plugin)) => return Box::into_raw(Box::new(plugin)) as Handle,
+        Ok(Err(e)) => e.to_string(),
+        Err(panic) => panic_message(panic),
+    };
+    error.send(message.as_bytes());
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn plugin_destroy(handle: Handle) {
+    guard_or((), || drop(Box::from_raw(handle as *mut Box<dyn Plugin>)));
+}
+
+unsafe extern "C" fn plugin_name(handle: Handle, sink: Sink<u8>) {
+    sink.send(guard_or("", || plugin(handle).name()).as_bytes());
+}
+
+unsafe extern "C" fn plugin_options<E: PluginEntry>(sink: Sink<u8>) {
+    let options = guard_or(Vec::new(), || OptionSpec::from_app(&E::Args::augment_args(App::new("plugin"))));
+    sink.send(serde_json::to_string(&options).unwrap_or_else(|_| "[]".into()).as_bytes());
+}
+
+unsafe extern "C" fn plugin_configure<E: PluginEntry>(
+    handle: Handle,
+    args: Str,
+    count: *mut usize,
+    error: Sink<u8>,
+) -> Status {
+    guard(error, || {
+        let configure = || -> Result<usize, Error> {
+            let args: Vec<String> = serde_json::from_str(args.as_str()?)?;
+            let app = LogOpt::augment_args(E::Args::augment_args(App::new("plugin").no_binary_name(true)));
+            plugin(handle).process_option(&app.try_get_matches_from(args)?)
+        };
+        *count = configure().map_err(WorkerError::classify)?;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn plugin_enabled(handle: Handle) -> bool {
+    guard_or(false, || plugin(handle).enabled())
+}
+
+unsafe extern "C" fn plugin_worker_specs(handle: Handle, sink: Sink<Handle>) {
+    let specs = guard_or(Vec::new(), || plugin(handle).get_worker_specs());
+    let handles = specs.into_iter().map(|spec| Box::into_raw(Box::new(spec)) as Handle).collect::<Vec<Handle>>();
+    sink.send(&handles);
+}
+
+unsafe extern "C" fn plugin_list_devices(handle: Handle, sink: Sink<u8>, error: Sink<u8>) -> Status {
+    guard(error, || {
+        let devices = plugin(handle).list_devices().map_err(WorkerError::classify)?;
+        sink.send(serde_json::to_string(&devices).map_err(|e| WorkerError::Fatal(e.into()))?.as_bytes());
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn plugin_set_log_callback(callback: LogCallback) {
+    guard_or((), || logging::forward_captured(move |line| unsafe { callback(Str::new(line)) }));
+}
+
+unsafe extern "C" fn spec_destroy(handle: Handle) {
+    guard_or((), || drop(Box::from_raw(handle as *mut Box<dyn WorkerSpec>)));
+}
+
+unsafe extern "C" fn spec_id(handle: Handle, sink: Sink<u8>) {
+    sink.send(guard_or(String::new(), || spec(handle).id()).as_bytes());
+}
+
+unsafe extern "C" fn spec_build(handle: Handle, error: Sink<u8>) -> Handle {
+    let message = match catch_unwind(AssertUnwindSafe(|| spec(handle).build())) {
+        Ok(Ok(worker)) => return Box::into_raw(Box::new(worker)) as Handle,
+        Ok(Err(e)) => e.to_string(),
+        Err(panic) => panic_message(panic),
+    };
+    error.send(message.as_bytes());
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn spec_identity(handle: Handle, sink: Sink<u8>) {
+    let identity = guard_or(DeviceIdentity::default(), || spec(handle).identity());
+    sink.send(serde_json::to_string(&identity).unwrap_or_default().as_bytes());
+}
+
+unsafe extern "C" fn worker_destroy(handle: Handle) {
+    guard_or((), || drop(Box::from_raw(handle as *mut Box<dyn Worker>)));
+}
+
+unsafe extern "C" fn worker_id(handle: Handle, sink: Sink<u8>) {
+    sink.send(guard_or(String::new(), || worker(handle).id()).as_bytes());
+}
+
+unsafe extern "C" fn worker_load_block_constants(
+    handle: Handle,
+    hash_header: *const [u8; 72],
+    matrix: *const [[u16; 64]; 64],
+    target: *const [u64; 4],
+    error: Sink<u8>,
+) -> Status {
+    guard(error, || worker(handle).load_block_constants(&*hash_header, &*matrix, &*target))
+}
+
+unsafe extern "C" fn worker_calculate_hash(
+    handle: Handle,
+    nonces: *const u64,
+    len: usize,
+    has_nonces: bool,
+    nonce_mask: u64,
+    nonce_fixed: u64,
+    error: Sink<u8>,
+) -> Status {
+    guard(error, || {
+        let nonces = match has_nonces && len > 0 {
+            true => slice::from_raw_parts(nonces, len).to_vec(),
+            false => Vec::new(),
+        };
+        worker(handle).calculate_hash(has_nonces.then_some(&nonces), nonce_mask, nonce_fixed)
+    })
+}
+
+unsafe extern "C" fn worker_sync(handle: Handle, error: Sink<u8>) -> Status {
+    guard(error, || worker(handle).sync().map_err(WorkerError::classify))
+}
+
+unsafe extern "C" fn worker_get_workload(handle: Handle) -> usize {
+    guard_or(0, || worker(handle).get_workload())
+}
+
+unsafe extern "C" fn worker_copy_output_to(handle: Handle, nonces: *mut u64, len: usize, error: Sink<u8>) -> Status {
+    guard(error, || with_nonces(nonces, len, |buffer| worker(handle).copy_output_to(buffer)))
+}
+
+unsafe extern "C" fn worker_pipeline_depth(handle: Handle) -> usize {
+    guard_or(1, || worker(handle).pipeline_depth())
+}
+
+unsafe extern "C" fn worker_finish_batch(handle: Handle, nonces: *mut u64, len: usize, error: Sink<u8>) -> Status {
+    guard(error, || with_nonces(nonces, len, |buffer| worker(handle).finish_batch(buffer)))
+}
+
+unsafe extern "C" fn worker_collect_nonces(handle: Handle, sink: Sink<u64>, error: Sink<u8>) -> Status {
+    guard(error, || {
+        sink.send(&worker(handle).collect_nonces().map_err(WorkerError::classify)?);
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn worker_kernel_time_ms(handle: Handle, kernel_ms: *mut f32) -> bool {
+    match guard_or(None, || worker(handle).kernel_time_ms()) {
+        Some(ms) => {
+            *kernel_ms = ms;
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn worker_set_workload(handle: Handle, workload: usize, error: Sink<u8>) -> Status {
+    guard(error, || worker(handle).set_workload(workload).map_err(WorkerError::classify))
+}
+
+unsafe extern "C" fn worker_backoff(handle: Handle) -> bool {
+    guard_or(false, || worker(handle).backoff())
+}
+
+unsafe extern "C" fn worker_telemetry(handle: Handle, sink: Sink<u8>) {
+    if let Some(telemetry) = guard_or(None, || worker(handle).telemetry()) {
+        sink.send(serde_json::to_string(&telemetry).unwrap_or_default().as_bytes());
+    }
+}
+
+// Workers take a Vec, the miner lends a buffer of a fixed length
+unsafe fn with_nonces(
+    nonces: *mut u64,
+    len: usize,
+    f: impl FnOnce(&mut Vec<u64>) -> Result<(), Error>,
+) -> Result<(), WorkerError> {
+    let mut buffer = match len {
+        0 => Vec::new(),
+        _ => slice::from_raw_parts(nonces, len).to_vec(),
+    };
+    f(&mut buffer).map_err(WorkerError::classify)?;
+    if len > 0 {
+        let copied = buffer.len().min(len);
+        slice::from_raw_parts_mut(nonces, len)[..copied].copy_from_slice(&buffer[..copied]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoadedPlugin;
+    use crate::{DeviceIdentity, DeviceInfo, Error, Plugin, Telemetry, Worker, WorkerError, WorkerSpec};
+    use clap::{App, ArgMatches, FromArgMatches};
+
+    #[derive(clap::Args, Debug)]
+    struct TestOpt {
+        #[clap(long = "test-device", use_delimiter = true)]
+        test_device: Option<Vec<u16>>,
+        #[clap(long = "test-mode", default_value = "fast")]
+        test_mode: String,
+        #[clap(long = "test-disable")]
+        test_disable: bool,
+    }
+
+    struct TestPlugin {
+        specs: Vec<TestSpec>,
+        enabled: bool,
+    }
+
+    impl TestPlugin {
+        fn new() -> Result<Self, Error> {
+            Ok(Self { specs: Vec::new(), enabled: false })
+        }
+    }
+
+    impl Plugin for TestPlugin {
+        fn name(&self) -> &'static str {
+            "Test Worker"
+        }
+
+        fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>> {
+            self.specs.iter().map(|spec| Box::new(*spec) as Box<dyn WorkerSpec>).collect()
+        }
+
+        fn process_option(&mut self, matches: &ArgMatches) -> Result<usize, Error> {
+            let opts = TestOpt::from_arg_matches(matches)?;
+            assert_eq!(opts.test_mode, "fast");
+            self.enabled = !opts.test_disable;
+            self.specs = opts.test_device.unwrap_or_default().into_iter().map(TestSpec).collect();
+            Ok(self.specs.len())
+        }
+
+        fn list_devices(&self) -> Result<Vec<DeviceInfo>, Error> {
+            Ok(vec![DeviceInfo {
+                backend: "test".into(),
+                name: "Test Device".into(),
+                identity: TestSpec(1).identity(),
+                default_workload: Some(1024),
+                ..DeviceInfo::default()
+            }])
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct TestSpec(u16);
+
+    impl WorkerSpec for TestSpec {
+        fn id(&self) -> String {
+            format!("#{}", self.0)
+        }
+
+        fn build(&self) -> Result<Box<dyn Worker>, Error> {
+            Ok(Box::new(TestWorker))
+        }
+
+        fn identity(&self) -> DeviceIdentity {
+            DeviceIdentity { pci_bus_id: Some(format!("0000:{:02x}:00.0", self.0)), uuid: None }
+        }
+    }
+
+    struct TestWorker;
+
+    impl Worker for TestWorker {
+        fn id(&self) -> String {
+            "test".into()
+        }
+
+        fn load_block_constants(&mut self, _: &[u8; 72], _: &[[u16; 64]; 64], _: &[u64; 4]) -> Result<(), WorkerError> {
+            Ok(())
+        }
+
+        fn calculate_hash(&mut self, _: Option<&Vec<u64>>, nonce_mask: u64, _: u64) -> Result<(), WorkerError> {
+            match nonce_mask {
+                0 => Err(WorkerError::DeviceLost("gone".into())),
+                _ => Ok(()),
+            }
+        }
+
+        fn sync(&self) -> Result<(), Error> {
+            panic!("sync is not supported")
+        }
+
+        fn get_workload(&self) -> usize {
+            1024
+        }
+
+        fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+            nonces[0] = 7;
+            Ok(())
+        }
+
+        fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+            self.copy_output_to(nonces)
+        }
+
+        fn telemetry(&self) -> Option<Telemetry> {
+            Some(Telemetry {
+                temperature_c: Some(64.),
+                pci_bus_id: Some("0000:01:00.0".into()),
+                ..Telemetry::default()
+            })
+        }
+    }
+
+    crate::declare_plugin!(TestPlugin, TestPlugin::new, TestOpt, "test");
+
+    #[test]
+    fn test_plugin_round_trip() {
+        let mut plugin = unsafe { LoadedPlugin::from_declaration(&PYRIN_PLUGIN_DECLARATION) }.unwrap();
+        assert_eq!(plugin.name, "Test Worker");
+        assert_eq!(plugin.metadata.name, env!("CARGO_PKG_NAME"));
+        assert_eq!(plugin.metadata.backends, vec!["test"]);
+        // Devices are listed before the options are processed
+        let devices = plugin.list_devices().unwrap();
+        assert_eq!(devices[0].name, "Test Device");
+        assert!(devices[0].identity.matches("01:00.0"));
+        assert_eq!(devices[0].default_workload, Some(1024));
+
+        let app = plugin.augment_args(App::new("miner"));
+        let matches = app.try_get_matches_from(["miner", "--test-device", "1,2"]).unwrap();
+        assert_eq!(plugin.configure(&matches).unwrap(), 2);
+        assert!(plugin.enabled());
+        let specs = plugin.worker_specs();
+        assert_eq!(specs.iter().map(|spec| spec.id()).collect::<Vec<String>>(), vec!["#1", "#2"]);
+        assert!(specs[1].identity().matches("02:00.0"));
+
+        let mut worker = specs[1].build().unwrap();
+        assert_eq!(worker.get_workload(), 1024);
+        worker.load_block_constants(&[0; 72], &[[0; 64]; 64], &[0; 4]).unwrap();
+        worker.calculate_hash(None, u64::MAX, 0).unwrap();
+        assert_eq!(worker.collect_nonces().unwrap(), vec![7]);
+        let telemetry = worker.telemetry().unwrap();
+        assert_eq!(telemetry.temperature_c, Some(64.));
+        assert_eq!(telemetry.pci_bus_id.as_deref(), Some("0000:01:00.0"));
+        assert_eq!(telemetry.power_w, None);
+        assert!(matches!(worker.calculate_hash(None, 0, 0), Err(WorkerError::DeviceLost(_))));
+        // A panic in the plugin comes back as an error instead of unwinding through the interface
+        assert!(matches!(WorkerError::classify(worker.sync().unwrap_err()), WorkerError::Fatal(_)));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::client::{check_address, get_client};
+use crate::control::MinerController;
+use crate::events::{self, MinerEvent};
+use crate::hw_errors::HwErrorConfig;
+use crate::miner::{MinerManager, WorkerSettings};
+use crate::schedule::{self, ScheduleConfig};
+use crate::thermal::ThermalConfig;
+use crate::tuning::BatchController;
+use crate::{hooks, log_event, stats, DeviceIdentity, Error, PluginManager};
+use log::{error, info, Level};
+use rand::{thread_rng, RngCore};
+use std::sync::atomic::AtomicU16;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{broadcast, oneshot};
+
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/**
+ Sets up a miner for an application embedding it, as the command line does for the binary. e.g.
+ `MinerBuilder::new("stratum+tcp://pool:5555", "pyrin:...").cpu_threads(2).build()?.start()?`
+*/
+pub struct MinerBuilder {
+    address: String,
+    mining_address: String,
+    cpu_threads: u16,
+    plugins: PluginManager,
+    mine_when_not_synced: bool,
+    hw_errors: HwErrorConfig,
+    thermal: ThermalConfig,
+    batch_controller: Option<BatchController>,
+    devfund: Option<(String, u16)>,
+    schedule: ScheduleConfig,
+    intensities: Vec<(Option<String>, u8)>,
+}
+
+impl MinerBuilder {
+    /**
+    Mines for `mining_address` through a pool at `stratum+tcp://host:port` or a node at `grpc://host:port`
+    */
+    pub fn new(address: impl Into<String>, mining_address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            mining_address: mining_address.into(),
+            cpu_threads: 0,
+            plugins: PluginManager::new(),
+            mine_when_not_synced: false,
+            hw_errors: HwErrorConfig::default(),
+            thermal: ThermalConfig::default(),
+            batch_controller: None,
+            devfund: None,
+            schedule: ScheduleConfig::default(),
+            intensities: Vec::new(),
+        }
+    }
+
+    pub fn cpu_threads(mut self, threads: u16) -> Self {
+        self.cpu_threads = threads;
+        self
+    }
+
+    /**
+    The plugin workers, from a manager whose plugins are loaded and processed their options
+    */
+    pub fn plugins(mut self, plugins: PluginManager) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn mine_when_not_synced(mut self, mine: bool) -> Self {
+        self.mine_when_not_synced = mine;
+        self
+    }
+
+    pub fn hw_errors(mut self, config: HwErrorConfig) -> Self {
+        self.hw_errors = config;
+        self
+    }
+
+    /**
+    Temperature and power limits of the plugin workers, checked on the sensors they can read
+    */
+    pub fn thermal(mut self, config: ThermalConfig) -> Self {
+        self.thermal = config;
+        self
+    }
+
+    /**
+    Adjusts the workload of the plugin workers to a batch duration, without it they keep their workload
+    */
+    pub fn batch_controller(mut self, controller: Option<BatchController>) -> Self {
+        self.batch_controller = controller;
+        self
+    }
+
+    /**
+    Mines for `address` on `per_10000` out of every 10,000 jobs
+    */
+    pub fn devfund(mut self, address: impl Into<String>, per_10000: u16) -> Self {
+        self.devfund = Some((address.into(), per_10000));
+        self
+    }
+
+    /**
+    Mines only inside the given windows and with fewer CPU threads while the system is busy
+    */
+    pub fn schedule(mut self, schedule: ScheduleConfig) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /**
+    Keeps a GPU, or every GPU when no device is given, busy only `intensity` percent of the time
+    */
+    pub fn intensity(mut self, device: Option<String>, intensity: u8) -> Self {
+        self.intensities.push((device, intensity));
+        self
+    }
+
+    pub fn build(mut self) -> Result<MinerHandle, Error> {
+        check_address(&self.address)?;
+        let specs = self.plugins.build()?;
+        if self.cpu_threads == 0 && specs.is_empty() {
+            return Err("No workers specified".into());
+        }
+        // Settings may name a device by its PCI address or UUID, the workers know it by its id
+        let devices = specs.iter().map(|spec| (spec.id(), spec.identity())).collect::<Vec<_>>();
+        for window in &mut self.schedule.windows {
+            if let Some(device) = &mut window.device {
+                *device = resolve_device(device, &devices);
+            }
+        }
+        self.thermal.devices =
+            self.thermal.devices.drain().map(|(device, limits)| (resolve_device(&device, &devices), limits)).collect();
+        let controller = MinerController::new(self.address.clone());
+        for (device, intensity) in &self.intensities {
+            let device = device.as_ref().map(|device| resolve_device(device, &devices));
+            controller.set_intensity(device.as_deref(), *intensity)?;
+        }
+        let settings = WorkerSettings {
+            hw_errors: self.hw_errors,
+            batch_controller: self.batch_controller,
+            controls: controller.devices(),
+            thermal: self.thermal.clone(),
+        };
+        Ok(MinerHandle { builder: Arc::new(self), settings, controller, running: None })
+    }
+}
+
+/**
+ A miner that was built, stopped until it is started or run
+*/
+pub struct MinerHandle {
+    builder: Arc<MinerBuilder>,
+    settings: WorkerSettings,
+    controller: MinerController,
+    running: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl MinerHandle {
+    /**
+    Mines on a thread of its own with its own runtime, until `stop` is called or the handle is dropped
+    */
+    pub fn start(&mut self) -> Result<(), Error> {
+        if self.running.is_some() {
+            return Err("The miner is already running".into());
+        }
+        let runtime = Runtime::new()?;
+        let (stop, stopped) = oneshot::channel();
+        let (builder, settings, controller) = (self.builder.clone(), self.settings.clone(), self.controller.clone());
+        let thread = thread::Builder::new().name("pyrin-miner".into()).spawn(move || {
+            runtime.block_on(async {
+                tokio::select! {
+                    _ = run(&builder, &settings, &controller) => {}
+                    _ = stopped => {}
+                }
+            })
+        })?;
+        self.running = Some((stop, thread));
+        Ok(())
+    }
+
+    /**
+    Disconnects and closes the workers, waiting for them. The miner can be started again
+    */
+    pub fn stop(&mut self) {
+        if let Some((stop, thread)) = self.running.take() {
+            let _ = stop.send(());
+            if thread.join().is_err() {
+                error!("The miner thread panicked");
+            }
+        }
+    }
+
+    /**
+    Mines on the current runtime, connecting again whenever the connection closes. Never returns, stops when the
+    future is dropped. The future is not `Send`, it runs in `block_on` or on a `LocalSet`
+    */
+    pub async fn run(&self) {
+        run(&self.builder, &self.settings, &self.controller).await
+    }
+
+    /**
+    Lets the workers finish their batches and idle, staying connected and keeping their job
+    */
+    pub fn pause(&self) {
+        self.controller.pause(&[]);
+    }
+
+    pub fn resume(&self) {
+        self.controller.resume(&[]);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.controller.is_paused()
+    }
+
+    /**
+    Pauses single devices, sets workloads and switches pools while the miner runs
+    */
+    pub fn controller(&self) -> MinerController {
+        self.controller.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /**
+    Jobs, share results, hashrate samples and errors from now on, see `events::subscribe`
+    */
+    pub fn events(&self) -> broadcast::Receiver<MinerEvent> {
+        events::subscribe()
+    }
+}
+
+impl Drop for MinerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/**
+ The id of the device `device` names, either by its id or by its identity
+*/
+fn resolve_device(device: &str, devices: &[(String, DeviceIdentity)]) -> String {
+    match devices.iter().find(|(id, identity)| id == device || identity.matches(device)) {
+        Some((id, _)) => id.clone(),
+        None => device.to_string(),
+    }
+}
+
+async fn run(builder: &MinerBuilder, settings: &WorkerSettings, controller: &MinerController) {
+    let scheduler = schedule::run(builder.schedule.clone(), builder.cpu_threads.into(), controller.clone());
+    tokio::join!(scheduler, connect(builder, settings, controller));
+}
+
+async fn connect(builder: &MinerBuilder, settings: &WorkerSettings, controller: &MinerController) {
+    let block_template_ctr = Arc::new(AtomicU16::new((thread_rng().next_u64() % 10_000u64) as u16));
+    let mut pool = controller.pool();
+    let mut reconnect = false;
+    loop {
+        let address = pool.borrow_and_update().clone();
+        tokio::select! {
+            result = client_main(builder, &address, settings, block_template_ctr.clone(), reconnect) => match result {
+                Ok(_) => {
+                    stats::set_connected(&address, false);
+                    info!("Client closed gracefully");
+                    hooks::fire("disconnected", &[("address", address.clone())]);
+                }
+                Err(e) => {
+                    stats::set_connected(&address, false);
+                    log_event!(
+                        Level::Error,
+                        "error",
+                        &[("error", e.to_string().into())],
+                        "Client closed with error {:?}",
+                        e
+                    );
+                    hooks::fire("disconnected", &[("address", address.clone()), ("error", e.to_string())]);
+                    events::emit(MinerEvent::Error { device: None, error: e.to_string() });
+                }
+            },
+            // Switching the pool or reconnecting drops the connection and the workers
+            _ = pool.changed() => {
+                stats::set_connected(&address, false);
+                hooks::fire("disconnected", &[("address", address.clone())]);
+            }
+        }
+        reconnect = true;
+        info!("Client closed, reconnecting");
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn client_main(
+    builder: &MinerBuilder,
+    address: &str,
+    settings: &WorkerSettings,
+    block_template_ctr: Arc<AtomicU16>,
+    reconnect: bool,
+) -> Result<(), Error> {
+    let mut client = get_client(
+        address.to_string(),
+        builder.mining_address.clone(),
+        builder.mine_when_not_synced,
+        block_template_ctr.clone(),
+    )
+    .await?;
+
+    if let Some((address, per_10000)) = &builder.devfund {
+        client.add_devfund(address.clone(), *per_10000);
+    }
+    client.register().await?;
+    stats::set_connected(address, true);
+    if reconnect {
+        hooks::fire("reconnected", &[("address", address.to_string())]);
+    }
+    let mut miner_manager =
+        MinerManager::new(client.get_block_channel(), Some(builder.cpu_threads), &builder.plugins, settings.clone());
+    client.listen(&mut miner_manager).await?;
+    drop(miner_manager);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MinerBuilder;
+
+    #[test]
+    fn test_build() {
+        assert!(MinerBuilder::new("127.0.0.1:13110", "pyrin:x").cpu_threads(1).build().is_err());
+        assert!(MinerBuilder::new("grpc://127.0.0.1:13110", "pyrin:x").build().is_err());
+
+        let miner = MinerBuilder::new("stratum+tcp://127.0.0.1:1", "pyrin:x").cpu_threads(1).build().unwrap();
+        miner.pause();
+        assert!(miner.is_paused());
+        miner.resume();
+        assert!(!miner.is_paused());
+        // Starting would connect to the pool
+        assert!(!miner.is_running());
+    }
+}
@@ -1,10 +1,24 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use log::LevelFilter;
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::Error;
+use pyrin_miner::hooks;
+use pyrin_miner::hw_errors::HwErrorConfig;
+use pyrin_miner::logging::LogOpt;
+use pyrin_miner::schedule::{MiningWindow, ScheduleConfig};
+use pyrin_miner::thermal::{ThermalAction, ThermalConfig};
+use pyrin_miner::tuning::BatchController;
 
 #[derive(Parser, Debug)]
-#[clap(name = "pyrin-miner", version, about = "A Pyrin high performance CPU miner", term_width = 0)]
+#[clap(
+    name = "pyrin-miner",
+    version,
+    about = "A Pyrin high performance CPU miner",
+    term_width = 0,
+    subcommand_negates_reqs = true
+)]
 pub struct Opt {
     #[clap(short, long, help = "Enable debug logging level")]
     pub debug: bool,
@@ -26,11 +40,122 @@ pub struct Opt {
         long_help = "Mine even when pyrin says it is not synced, only useful when passing `--allow-submit-block-when-not-synced` to pyrin  [default: false]"
     )]
     pub mine_when_not_synced: bool,
+    #[clap(
+        long = "hook-cmd",
+        help = "Command to run on miner events. Event details are passed in PYRIN_MINER_* environment variables"
+    )]
+    pub hook_cmd: Option<String>,
+    #[clap(long = "hook-url", help = "Local http:// URL to POST a JSON object to on miner events")]
+    pub hook_url: Option<String>,
+    #[clap(
+        long = "hw-error-threshold",
+        default_value = "5",
+        help = "Percent of wrong GPU results above which the GPU clocks are lowered, or the GPU is paused"
+    )]
+    pub hw_error_threshold: f64,
+    #[clap(
+        long = "hw-check-interval",
+        default_value = "300",
+        help = "Seconds between hardware error spot checks on each GPU"
+    )]
+    pub hw_check_interval: u64,
+    #[clap(
+        long = "max-temp",
+        help = "Temperature in °C a GPU is kept under, as [DEVICE=]TEMPERATURE, may be repeated"
+    )]
+    pub max_temp: Vec<String>,
+    #[clap(long = "max-power", help = "Power draw in W a GPU is throttled under, as [DEVICE=]WATTS, may be repeated")]
+    pub max_power: Vec<String>,
+    #[clap(
+        long = "thermal-action",
+        default_value = "throttle",
+        possible_values = ["throttle", "pause"],
+        help = "What a GPU over --max-temp does: lower its intensity step by step, or pause until it cools down"
+    )]
+    pub thermal_action: ThermalAction,
+    #[clap(
+        long = "thermal-hysteresis",
+        default_value = "5",
+        help = "Degrees under --max-temp a GPU has to cool to before it resumes or is throttled less"
+    )]
+    pub thermal_hysteresis: f32,
+    #[clap(long = "thermal-check-interval", default_value = "5", help = "Seconds between sensor readings of each GPU")]
+    pub thermal_check_interval: u64,
+    #[clap(
+        long = "batch-duration",
+        help = "Resize the workload of every GPU so one batch takes about this many ms, e.g. 250 for a quarter of a block. Overrides the workload options and tuned profiles [default: off]"
+    )]
+    pub batch_duration: Option<f32>,
+    #[clap(
+        long = "control-listen",
+        help = "Address to serve the control commands on over HTTP, e.g. 127.0.0.1:4556. Needs --control-token"
+    )]
+    pub control_listen: Option<String>,
+    #[clap(long = "control-token", help = "Bearer token every control command has to carry")]
+    pub control_token: Option<String>,
+    #[clap(
+        long = "intensity",
+        help = "Percent of the time a GPU is kept busy, as [DEVICE=]PERCENT, may be repeated [default: 100]",
+        long_help = "Percent of the time a GPU is kept busy, resting between batches to keep the desktop responsive, as [DEVICE=]PERCENT, may be repeated. Without a device it applies to every GPU [default: 100]"
+    )]
+    pub intensity: Vec<String>,
+    #[clap(
+        long = "mine-window",
+        help = "Only mine inside this window of local time, may be repeated, e.g. \"mon-fri 18:00-08:00\"",
+        long_help = "Only mine inside this window of local time, may be repeated: [DEVICE=][DAYS ]HH:MM-HH:MM, e.g. \"mon-fri 18:00-08:00\" or \"CPU=sat,sun 00:00-24:00\". A device with windows of its own also keeps to the windows of every device"
+    )]
+    pub mine_window: Vec<MiningWindow>,
+    #[clap(
+        long = "max-load",
+        help = "Drop CPU threads while the 1 minute system load is above this, and bring them back when it falls (Linux only)"
+    )]
+    pub max_load: Option<f32>,
+    #[clap(flatten)]
+    pub log: LogOpt,
+    #[clap(flatten)]
+    pub plugins: PluginOpt,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
     #[clap(skip)]
     pub devfund_address: String,
 }
 
+// Where plugins are loaded from. Read before the rest of the command line, see `plugins::prescan`
+#[derive(Args, Debug, Default, PartialEq, Eq)]
+pub struct PluginOpt {
+    #[clap(
+        long = "plugin-dir",
+        help = "Also load the plugins of this directory, may be repeated. Only the built-in plugins are loaded from a directory unless a manifest allows others"
+    )]
+    pub plugin_dir: Vec<String>,
+    #[clap(long = "plugin", help = "Load this plugin library, may be repeated")]
+    pub plugin: Vec<String>,
+    #[clap(
+        long = "plugin-manifest",
+        help = "JSON file listing the only plugin files allowed to load, optionally pinned to a blake3 hash",
+        long_help = "JSON file listing the only plugin files allowed to load, optionally pinned to a blake3 hash: {\"plugins\": [{\"file\": \"libpyrincuda.so\", \"blake3\": \"<hex>\"}]}"
+    )]
+    pub plugin_manifest: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[clap(subcommand, about = "Inspect the plugins of the miner")]
+    Plugins(PluginsCommand),
+    #[clap(about = "Show every device the plugins see, with what they would mine on it")]
+    ListDevices {
+        #[clap(long, help = "Print the devices as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PluginsCommand {
+    #[clap(about = "Show the plugins that were found, which ones loaded and why the others did not")]
+    List,
+}
+
 impl Opt {
     pub fn process(&mut self) -> Result<(), Error> {
         //self.gpus = None;
@@ -51,6 +176,12 @@ impl Opt {
         if self.num_threads.is_none() {
             self.num_threads = Some(0);
         }
+        if matches!(self.max_load, Some(load) if load <= 0.) {
+            return Err("The maximum load has to be above 0".into());
+        }
+        if let Some(url) = &self.hook_url {
+            hooks::check_url(url)?;
+        }
 
         let miner_network = self.mining_address.split(':').next();
         self.devfund_address = String::from("pyrin:qzj9kz0kmc3rxl9mw86mlda2cqmvp3xhavx9h2jud5ehdchvruql6ey64r8kz");
@@ -69,6 +200,62 @@ impl Opt {
         *self.port.get_or_insert(if self.testnet { 16211 } else { 13110 })
     }
 
+    /**
+    Where to serve the control commands and the token they need, if they are enabled
+    */
+    pub fn control(&self) -> Result<Option<(String, String)>, Error> {
+        match (&self.control_listen, &self.control_token) {
+            (Some(_), Some(token)) if token.is_empty() => Err("The control token cannot be empty".into()),
+            (Some(listen), Some(token)) => Ok(Some((listen.clone(), token.clone()))),
+            (Some(_), None) => Err("--control-listen needs --control-token".into()),
+            (None, _) => Ok(None),
+        }
+    }
+
+    pub fn schedule(&self) -> ScheduleConfig {
+        ScheduleConfig { windows: self.mine_window.clone(), max_load: self.max_load }
+    }
+
+    /**
+    The intensity of every GPU or of the device before a `=`, in the order given
+    */
+    pub fn intensities(&self) -> Result<Vec<(Option<String>, u8)>, Error> {
+        per_device(&self.intensity)
+    }
+
+    pub fn thermal_config(&self) -> Result<ThermalConfig, Error> {
+        let mut config = ThermalConfig {
+            action: self.thermal_action,
+            hysteresis: self.thermal_hysteresis,
+            check_interval: Duration::from_secs(self.thermal_check_interval),
+            ..ThermalConfig::default()
+        };
+        for (device, max_temp) in per_device(&self.max_temp)? {
+            match device {
+                Some(device) => config.devices.entry(device).or_default().max_temp = Some(max_temp),
+                None => config.limits.max_temp = Some(max_temp),
+            }
+        }
+        for (device, max_power) in per_device(&self.max_power)? {
+            match device {
+                Some(device) => config.devices.entry(device).or_default().max_power = Some(max_power),
+                None => config.limits.max_power = Some(max_power),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn hw_error_config(&self) -> HwErrorConfig {
+        HwErrorConfig {
+            threshold: self.hw_error_threshold / 100.,
+            check_interval: Duration::from_secs(self.hw_check_interval),
+        }
+    }
+
+    pub fn batch_controller(&self) -> Option<BatchController> {
+        self.batch_duration.filter(|&ms| ms > 0.).map(BatchController::new)
+    }
+
     pub fn log_level(&self) -> LevelFilter {
         if self.debug {
             LevelFilter::Debug
@@ -77,3 +264,24 @@ impl Opt {
         }
     }
 }
+
+// Values given as `[DEVICE=]VALUE`, without a device they apply to every device
+fn per_device<T>(values: &[String]) -> Result<Vec<(Option<String>, T)>, Error>
+where
+    T: FromStr,
+    T::Err: Into<Error>,
+{
+    values
+        .iter()
+        .map(|value| -> Result<(Option<String>, T), Error> {
+            let (device, value) = match value.rsplit_once('=') {
+                Some((device, value)) => (Some(device.to_string()), value),
+                None => (None, value.as_str()),
+            };
+            match value.trim().parse() {
+                Ok(value) => Ok((device, value)),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .collect()
+}
@@ -1,11 +1,16 @@
 use async_trait::async_trait;
+use std::sync::atomic::AtomicU16;
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 
 pub mod grpc;
 pub mod stratum;
 
+use crate::client::grpc::PyipadHandler;
+use crate::client::stratum::StratumHandler;
+use crate::miner::MinerManager;
 use crate::pow::BlockSeed;
-use crate::{Error, MinerManager};
+use crate::Error;
 
 #[async_trait(?Send)]
 pub trait Client {
@@ -14,3 +19,41 @@ pub trait Client {
     async fn listen(&mut self, miner: &mut MinerManager) -> Result<(), Error>;
     fn get_block_channel(&self) -> Sender<BlockSeed>;
 }
+
+/**
+ Checks that an address names a pool (`stratum+tcp://`) or a node (`grpc://`)
+*/
+pub fn check_address(address: &str) -> Result<(), Error> {
+    match ["stratum+tcp://", "grpc://"].iter().any(|schema| address.starts_with(schema)) {
+        true => Ok(()),
+        false => Err("Did not recognize pool/grpc address schema".into()),
+    }
+}
+
+pub async fn get_client(
+    pyrin_address: String,
+    mining_address: String,
+    mine_when_not_synced: bool,
+    block_template_ctr: Arc<AtomicU16>,
+) -> Result<Box<dyn Client + 'static>, Error> {
+    if pyrin_address.starts_with("stratum+tcp://") {
+        let (_schema, address) = pyrin_address.split_once("://").unwrap();
+        Ok(StratumHandler::connect(
+            address.to_string().clone(),
+            mining_address.clone(),
+            mine_when_not_synced,
+            Some(block_template_ctr.clone()),
+        )
+        .await?)
+    } else if pyrin_address.starts_with("grpc://") {
+        Ok(PyipadHandler::connect(
+            pyrin_address.clone(),
+            mining_address.clone(),
+            mine_when_not_synced,
+            Some(block_template_ctr.clone()),
+        )
+        .await?)
+    } else {
+        Err("Did not recognize pool/grpc address schema".into())
+    }
+}
@@ -1,16 +1,18 @@
 use crate::client::Client;
+use crate::events::{self, MinerEvent};
+use crate::log_event;
 use crate::pow::BlockSeed;
 use crate::pow::BlockSeed::{FullBlock, PartialBlock};
 use crate::proto::pyipad_message::Payload;
 use crate::proto::rpc_client::RpcClient;
 use crate::proto::{
-    GetBlockTemplateRequestMessage, GetInfoRequestMessage, PyipadMessage, NotifyBlockAddedRequestMessage,
-    NotifyNewBlockTemplateRequestMessage,
+    GetBlockTemplateRequestMessage, GetInfoRequestMessage, NotifyBlockAddedRequestMessage,
+    NotifyNewBlockTemplateRequestMessage, PyipadMessage,
 };
-use crate::{miner::MinerManager, Error};
+use crate::{miner::MinerManager, stats, Error};
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use log::{error, info, warn};
+use log::{error, info, warn, Level};
 use rand::{thread_rng, RngCore};
 use semver::Version;
 use std::sync::atomic::{AtomicU16, Ordering};
@@ -123,9 +125,13 @@ impl PyipadHandler {
     async fn client_get_block_template(&mut self) -> Result<(), SendError<PyipadMessage>> {
         let pay_address = match &self.devfund_address {
             Some(devfund_address) if self.block_template_ctr.load(Ordering::SeqCst) <= self.devfund_percent => {
+                stats::set_mining_devfund(true);
                 devfund_address.clone()
             }
-            _ => self.miner_address.clone(),
+            _ => {
+                stats::set_mining_devfund(false);
+                self.miner_address.clone()
+            }
         };
         self.block_template_ctr.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some((v + 1) % 10_000)).unwrap();
         self.client_send(GetBlockTemplateRequestMessage { pay_address, extra_data: EXTRA_DATA.into() }).await
@@ -146,10 +152,28 @@ impl PyipadHandler {
                 }
                 (None, true, None) => error!("No block and No Error!"),
             },
-            Payload::SubmitBlockResponse(res) => match res.error {
-                None => info!("block submitted successfully!"),
-                Some(e) => warn!("Failed submitting block: {:?}", e),
-            },
+            Payload::SubmitBlockResponse(res) => {
+                events::emit(MinerEvent::ShareResult {
+                    job_id: None,
+                    accepted: res.error.is_none(),
+                    reason: res.error.as_ref().map(|e| e.message.clone()),
+                });
+                match res.error {
+                    None => log_event!(
+                        Level::Info,
+                        "block",
+                        &[("result", "accepted".into())],
+                        "block submitted successfully!"
+                    ),
+                    Some(e) => log_event!(
+                        Level::Warn,
+                        "block",
+                        &[("result", "rejected".into()), ("error", e.message.clone().into())],
+                        "Failed submitting block: {:?}",
+                        e
+                    ),
+                }
+            }
             Payload::GetBlockResponse(msg) => {
                 if let Some(e) = msg.error {
                     return Err(e.message.into());
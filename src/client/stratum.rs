@@ -15,12 +15,15 @@ use crate::client::stratum::statum_codec::{
     MiningSubscribe, SetExtranonce, StratumCommand, StratumError, StratumLinePayload, StratumResult,
 };
 use crate::client::Client;
+use crate::events::{self, MinerEvent};
+use crate::log_event;
 use crate::pow::BlockSeed;
 use crate::pow::BlockSeed::PartialBlock;
-use crate::{miner::MinerManager, Error, Uint256};
+use crate::target::Uint256;
+use crate::{hooks, miner::MinerManager, stats, Error};
 use async_trait::async_trait;
 use futures_util::TryStreamExt;
-use log::{error, info, warn};
+use log::{info, warn, Level};
 use num::Float;
 use rand::{thread_rng, RngCore};
 use statum_codec::NewLineJsonCodec;
@@ -128,11 +131,13 @@ impl Client for StratumHandler {
         let pay_address = match &self.devfund_address {
             Some(devfund_address) if self.block_template_ctr.load(Ordering::SeqCst) <= self.devfund_percent => {
                 self.mining_dev = Some(true);
+                stats::set_mining_devfund(true);
                 info!("Mining to devfund");
                 devfund_address.clone()
             }
             _ => {
                 self.mining_dev = Some(false);
+                stats::set_mining_devfund(false);
                 self.miner_address.clone()
             }
         };
@@ -241,6 +246,7 @@ impl StratumHandler {
                         BlockSeed::FullBlock(_) => unreachable!(),
                     };
                     let msg_id = last_stratum_id.fetch_add(1, Ordering::SeqCst);
+                    stats::share_submitted(msg_id, *nonce);
                     {
                         share_stats.shares_pending.try_lock().unwrap().insert(
                             msg_id,
@@ -275,15 +281,21 @@ impl StratumHandler {
                     StratumLinePayload::StratumResult { result } if id.is_some() => {
                         match result {
                             StratumResult::Plain(Some(true)) | StratumResult::Eth((true, _)) => {
-                                if let Some(_jobid) = self
-                                    .shares_stats
-                                    .shares_pending
-                                    .try_lock()
-                                    .unwrap()
-                                    .remove(&id.expect("We checked id is not none"))
-                                {
+                                let id = id.expect("We checked id is not none");
+                                if let Some(jobid) = self.shares_stats.shares_pending.try_lock().unwrap().remove(&id) {
                                     self.shares_stats.accepted.fetch_add(1, Ordering::SeqCst);
-                                    info!("Share accepted");
+                                    stats::share_result(id, true);
+                                    log_event!(
+                                        Level::Info,
+                                        "share",
+                                        &[("result", "accepted".into()), ("job_id", jobid.clone().into())],
+                                        "Share accepted"
+                                    );
+                                    events::emit(MinerEvent::ShareResult {
+                                        job_id: Some(jobid),
+                                        accepted: true,
+                                        reason: None,
+                                    });
                                 } else {
                                     info!("{:?} (Last: {})", msg.clone(), self.last_stratum_id.load(Ordering::SeqCst));
                                     warn!("Ignoring result for now");
@@ -338,32 +350,59 @@ impl StratumHandler {
                 ..
             } => {
                 let jobid = { self.shares_stats.shares_pending.try_lock().unwrap().remove(&id) }.unwrap();
+                stats::share_result(id, false);
+                hooks::fire(
+                    "share_rejected",
+                    &[
+                        ("reason", code.to_string()),
+                        ("code", (code.clone() as u8).to_string()),
+                        ("message", error.clone()),
+                        ("job_id", jobid.clone()),
+                    ],
+                );
+                events::emit(MinerEvent::ShareResult {
+                    job_id: Some(jobid.clone()),
+                    accepted: false,
+                    reason: Some(error.clone()),
+                });
+                let result = match code {
+                    ErrorCode::JobNotFound => "stale",
+                    ErrorCode::DuplicateShare => "duplicate",
+                    ErrorCode::LowDifficultyShare => "low_difficulty",
+                    _ => "rejected",
+                };
+                let fields = [
+                    ("result", result.into()),
+                    ("job_id", jobid.clone().into()),
+                    ("code", (code.clone() as u8).into()),
+                    ("error", error.clone().into()),
+                ];
                 match code {
                     ErrorCode::Unknown => {
-                        error!("Got error code {}: {}", code, error);
+                        log_event!(Level::Error, "share", &fields, "Got error code {}: {}", code, error);
                         Err(error.into())
                     }
                     ErrorCode::JobNotFound => {
                         self.shares_stats.stale.fetch_add(1, Ordering::SeqCst);
-                        warn!("Stale share (Job id: {:?})", jobid);
+                        log_event!(Level::Warn, "share", &fields, "Stale share (Job id: {:?})", jobid);
                         Ok(())
                     }
                     ErrorCode::DuplicateShare => {
                         self.shares_stats.duplicate.fetch_add(1, Ordering::SeqCst);
-                        warn!("Duplicate share (Job id: {:?})", jobid);
+                        log_event!(Level::Warn, "share", &fields, "Duplicate share (Job id: {:?})", jobid);
                         Ok(())
                     }
                     ErrorCode::LowDifficultyShare => {
                         self.shares_stats.low_diff.fetch_add(1, Ordering::SeqCst);
-                        warn!("Low difficulty share (Job id: {:?})", jobid);
+                        log_event!(Level::Warn, "share", &fields, "Low difficulty share (Job id: {:?})", jobid);
                         Ok(())
                     }
                     ErrorCode::Unauthorized => {
-                        error!("Got error code {}: {}", code, error);
+                        log_event!(Level::Error, "share", &fields, "Got error code {}: {}", code, error);
                         Err(error.into())
                     }
                     ErrorCode::NotSubscribed => {
-                        error!("Got error code {}: {}", code, error);
+                        log_event!(Level::Error, "share", &fields, "Got error code {}: {}", code, error);
                         Err(error.into())
                     }
                 }
@@ -388,7 +427,16 @@ impl StratumHandler {
         }
 
         self.target_pool = Uint256::new(buf);
-        info!("Difficulty: {:?}, Target: 0x{}", difficulty, hex::encode(self.target_pool.to_be_bytes()));
+        stats::set_difficulty(*difficulty as f64);
+        let target = hex::encode(self.target_pool.to_be_bytes());
+        log_event!(
+            Level::Info,
+            "difficulty",
+            &[("difficulty", (*difficulty).into()), ("target", target.clone().into())],
+            "Difficulty: {:?}, Target: 0x{}",
+            difficulty,
+            target
+        );
         Ok(())
     }
 
@@ -407,7 +455,18 @@ impl StratumHandler {
         let mut _last_instant = ticker.tick().await;
         loop {
             let _now = ticker.tick().await;
-            info!("{}", shares_info)
+            log_event!(
+                Level::Info,
+                "shares",
+                &[
+                    ("accepted", shares_info.accepted.load(Ordering::SeqCst).into()),
+                    ("stale", shares_info.stale.load(Ordering::SeqCst).into()),
+                    ("low_difficulty", shares_info.low_diff.load(Ordering::SeqCst).into()),
+                    ("duplicate", shares_info.duplicate.load(Ordering::SeqCst).into()),
+                ],
+                "{}",
+                shares_info
+            )
         }
     }
 }
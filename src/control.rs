@@ -0,0 +1,401 @@
+use crate::client::check_address;
+use crate::Error;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Requests larger than this are refused, commands are a few bytes of JSON
+const MAX_REQUEST: usize = 16 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ What the devices were asked to do, read by each worker whenever it gets a command
+*/
+#[derive(Clone, Debug, Default)]
+pub struct ControlState {
+    pub paused_all: bool,
+    pub paused: HashSet<String>,
+    pub workloads: HashMap<String, usize>,
+    /// Percent of the time a GPU is kept busy, for every device and by device
+    pub intensity: Option<u8>,
+    pub intensities: HashMap<String, u8>,
+    /// Set by the schedule, apart from the pauses asked for so resuming does not override it
+    pub scheduled_all: bool,
+    pub scheduled: HashSet<String>,
+    /// CPU threads allowed to mine, the first ones by index. All of them when `None`
+    pub cpu_threads: Option<usize>,
+}
+
+/**
+ The part of the controls that applies to one device
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceControl {
+    pub paused: bool,
+    pub workload: Option<usize>,
+    /// 1 to 100, below 100 the device rests between batches
+    pub intensity: u8,
+}
+
+impl ControlState {
+    pub fn device(&self, id: &str) -> DeviceControl {
+        let paused = self.paused_all || self.scheduled_all || self.paused.contains(id) || self.scheduled.contains(id);
+        let intensity = self.intensities.get(id).copied().or(self.intensity).unwrap_or(100);
+        DeviceControl { paused, workload: self.workloads.get(id).copied(), intensity }
+    }
+
+    /**
+    The controls of the CPU thread launched `index`th, which also pauses when fewer threads are allowed
+    */
+    pub fn cpu_thread(&self, index: usize) -> DeviceControl {
+        let control = self.device("CPU");
+        DeviceControl { paused: control.paused || matches!(self.cpu_threads, Some(n) if index >= n), ..control }
+    }
+}
+
+/**
+ Changes a running miner: pauses and resumes devices, sets workloads and moves to another pool.
+ Devices are named by their id as in the logs, the CPU threads are `CPU`
+*/
+#[derive(Clone)]
+pub struct MinerController {
+    devices: Arc<watch::Sender<ControlState>>,
+    pool: Arc<watch::Sender<String>>,
+}
+
+impl MinerController {
+    pub(crate) fn new(address: String) -> Self {
+        Self { devices: Arc::new(watch::channel(ControlState::default()).0), pool: Arc::new(watch::channel(address).0) }
+    }
+
+    /**
+    Pauses the given devices, or every device when none is given. Workers finish their batches and keep their job
+    */
+    pub fn pause(&self, devices: &[String]) {
+        self.devices.send_modify(|state| match devices.is_empty() {
+            true => state.paused_all = true,
+            false => state.paused.extend(devices.iter().cloned()),
+        });
+        match devices.is_empty() {
+            true => info!("Mining paused"),
+            false => info!("Paused {}", devices.join(", ")),
+        }
+    }
+
+    /**
+    Resumes the given devices, or every device when none is given. A device stays paused while all are
+    */
+    pub fn resume(&self, devices: &[String]) {
+        self.devices.send_modify(|state| match devices.is_empty() {
+            true => {
+                state.paused_all = false;
+                state.paused.clear();
+            }
+            false => devices.iter().for_each(|device| {
+                state.paused.remove(device);
+            }),
+        });
+        match devices.is_empty() {
+            true => info!("Mining resumed"),
+            false => info!("Resumed {}", devices.join(", ")),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.devices.borrow().paused_all
+    }
+
+    /**
+    Sets the nonces of each batch of a device, which then keeps it instead of tuning it to the batch duration
+    */
+    pub fn set_workload(&self, device: &str, workload: usize) -> Result<(), Error> {
+        if workload == 0 {
+            return Err("The workload has to be at least 1".into());
+        }
+        self.devices.send_modify(|state| {
+            state.workloads.insert(device.to_string(), workload);
+        });
+        info!("Workload of {} set to {}", device, workload);
+        Ok(())
+    }
+
+    /**
+    Keeps a GPU busy only `intensity` percent of the time by resting between batches, every GPU when no device is given.
+    A device set on its own keeps its intensity when every device is set
+    */
+    pub fn set_intensity(&self, device: Option<&str>, intensity: u8) -> Result<(), Error> {
+        if !(1..=100).contains(&intensity) {
+            return Err("The intensity is a percentage from 1 to 100".into());
+        }
+        self.devices.send_modify(|state| match device {
+            Some(device) => {
+                state.intensities.insert(device.to_string(), intensity);
+            }
+            None => state.intensity = Some(intensity),
+        });
+        info!("Intensity of {} set to {}%", device.unwrap_or("every GPU"), intensity);
+        Ok(())
+    }
+
+    /**
+    Disconnects and mines through another pool or node from then on
+    */
+    pub fn switch_pool(&self, address: &str) -> Result<(), Error> {
+        check_address(address)?;
+        info!("Switching to {}", address);
+        self.pool.send_replace(address.to_string());
+        Ok(())
+    }
+
+    /**
+    Disconnects and connects again to the same pool or node
+    */
+    pub fn reconnect(&self) {
+        info!("Reconnecting on request");
+        self.pool.send_modify(|_| {});
+    }
+
+    /**
+    Pauses the devices outside their mining windows, every device when `all` is set, and resumes the others
+    */
+    pub(crate) fn set_scheduled(&self, all: bool, devices: HashSet<String>) {
+        self.devices.send_if_modified(|state| {
+            if state.scheduled_all == all && state.scheduled == devices {
+                return false;
+            }
+            match (state.scheduled_all, all) {
+                (false, true) => info!("Outside the mining windows, pausing"),
+                (true, false) => info!("Inside the mining windows, mining"),
+                _ => {}
+            }
+            devices
+                .difference(&state.scheduled)
+                .for_each(|device| info!("Outside its mining windows, pausing {}", device));
+            state
+                .scheduled
+                .difference(&devices)
+                .for_each(|device| info!("Inside its mining windows, resuming {}", device));
+            state.scheduled_all = all;
+            state.scheduled = devices;
+            true
+        });
+    }
+
+    /**
+    Lets only the first `threads` CPU threads mine, or all of them with `None`
+    */
+    pub(crate) fn set_cpu_threads(&self, threads: Option<usize>) {
+        self.devices.send_if_modified(|state| std::mem::replace(&mut state.cpu_threads, threads) != threads);
+    }
+
+    pub fn address(&self) -> String {
+        self.pool.borrow().clone()
+    }
+
+    pub(crate) fn devices(&self) -> watch::Receiver<ControlState> {
+        self.devices.subscribe()
+    }
+
+    pub(crate) fn pool(&self) -> watch::Receiver<String> {
+        self.pool.subscribe()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct CommandBody {
+    devices: Vec<String>,
+    device: Option<String>,
+    workload: Option<usize>,
+    intensity: Option<u8>,
+    address: Option<String>,
+}
+
+/**
+ Serves the control commands over HTTP, each request has to carry `Authorization: Bearer <token>`:
+ `POST /pause` and `POST /resume` with `{"devices": [...]}` or no body for every device,
+ `POST /workload` with `{"device": "...", "workload": n}`,
+ `POST /intensity` with `{"device": "...", "intensity": n}` or no device for every GPU,
+ `POST /pool` with `{"address": "..."}` and `POST /reconnect`
+*/
+pub async fn serve(listener: TcpListener, token: String, controller: MinerController) {
+    let token = Arc::new(token);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Control interface failed accepting a connection: {}", e);
+                continue;
+            }
+        };
+        let (token, controller) = (token.clone(), controller.clone());
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle_connection(stream, &token, &controller)).await {
+                Ok(Err(e)) => warn!("Control request from {} failed: {}", peer, e),
+                Err(_) => warn!("Control request from {} timed out", peer),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, token: &str, controller: &MinerController) -> Result<(), Error> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut authorized = false;
+    let mut length = 0;
+    let mut head = request_line.len();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        head += line.len();
+        if head > MAX_REQUEST {
+            return respond(stream.get_mut(), "413 Payload Too Large", "request too large").await;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "authorization" => authorized = check_token(value.trim(), token),
+                "content-length" => length = value.trim().parse()?,
+                _ => {}
+            }
+        }
+    }
+    if length > MAX_REQUEST {
+        return respond(stream.get_mut(), "413 Payload Too Large", "request too large").await;
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await?;
+
+    let (status, message) = match authorized {
+        true => match request_line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["POST", path, ..] => match execute(controller, path, &body) {
+                Ok(()) => ("200 OK", "ok".to_string()),
+                Err(e) => ("400 Bad Request", e.to_string()),
+            },
+            _ => ("405 Method Not Allowed", "commands are sent with POST".to_string()),
+        },
+        false => ("401 Unauthorized", "missing or wrong token".to_string()),
+    };
+    respond(stream.get_mut(), status, &message).await
+}
+
+// Compares every byte, so the time taken does not tell how much of the token matched
+fn check_token(header: &str, token: &str) -> bool {
+    let given = header.strip_prefix("Bearer ").unwrap_or_default().as_bytes();
+    given.len() == token.len() && given.iter().zip(token.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn execute(controller: &MinerController, path: &str, body: &[u8]) -> Result<(), Error> {
+    let command: CommandBody = match body.is_empty() {
+        true => CommandBody::default(),
+        false => serde_json::from_slice(body)?,
+    };
+    match path {
+        "/pause" => controller.pause(&command.devices),
+        "/resume" => controller.resume(&command.devices),
+        "/workload" => match (command.device, command.workload) {
+            (Some(device), Some(workload)) => controller.set_workload(&device, workload)?,
+            _ => return Err("A workload needs a device and a workload".into()),
+        },
+        "/intensity" => match command.intensity {
+            Some(intensity) => controller.set_intensity(command.device.as_deref(), intensity)?,
+            None => return Err("Expected an intensity from 1 to 100".into()),
+        },
+        "/pool" => controller.switch_pool(&command.address.ok_or("A pool needs an address")?)?,
+        "/reconnect" => controller.reconnect(),
+        _ => return Err(format!("Unknown command {}", path).into()),
+    }
+    Ok(())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) -> Result<(), Error> {
+    let body = match status.starts_with("200") {
+        true => serde_json::json!({ "result": message }),
+        false => serde_json::json!({ "error": message }),
+    }
+    .to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_token, execute, MinerController};
+
+    #[test]
+    fn test_check_token() {
+        assert!(check_token("Bearer secret", "secret"));
+        assert!(!check_token("Bearer secreT", "secret"));
+        assert!(!check_token("Bearer secret2", "secret"));
+        assert!(!check_token("secret", "secret"));
+    }
+
+    #[test]
+    fn test_execute() {
+        let controller = MinerController::new("stratum+tcp://127.0.0.1:5555".into());
+        let mut pool = controller.pool();
+        execute(&controller, "/pause", br##"{"devices": ["#0 (software)"]}"##).unwrap();
+        execute(&controller, "/workload", br##"{"device": "#1 (software)", "workload": 512}"##).unwrap();
+        {
+            let state = controller.devices().borrow().clone();
+            assert!(state.device("#0 (software)").paused);
+            assert!(!state.device("#1 (software)").paused);
+            assert_eq!(state.device("#1 (software)").workload, Some(512));
+        }
+        execute(&controller, "/pause", b"").unwrap();
+        execute(&controller, "/resume", br##"{"devices": ["#0 (software)"]}"##).unwrap();
+        assert!(controller.devices().borrow().device("#0 (software)").paused);
+        execute(&controller, "/resume", b"").unwrap();
+        assert!(!controller.devices().borrow().device("#1 (software)").paused);
+
+        assert!(execute(&controller, "/pool", br##"{"address": "127.0.0.1:5555"}"##).is_err());
+        assert!(!pool.has_changed().unwrap());
+        execute(&controller, "/pool", br##"{"address": "grpc://127.0.0.1:13110"}"##).unwrap();
+        assert!(pool.has_changed().unwrap());
+        assert_eq!(*pool.borrow_and_update(), "grpc://127.0.0.1:13110");
+        assert!(execute(&controller, "/workload", br##"{"device": "CPU", "workload": 0}"##).is_err());
+        assert!(execute(&controller, "/restart", b"").is_err());
+
+        execute(&controller, "/intensity", br##"{"device": "#1 (software)", "intensity": 30}"##).unwrap();
+        execute(&controller, "/intensity", br##"{"intensity": 60}"##).unwrap();
+        {
+            let state = controller.devices().borrow().clone();
+            assert_eq!(state.device("#0 (software)").intensity, 60);
+            assert_eq!(state.device("#1 (software)").intensity, 30);
+        }
+        assert!(execute(&controller, "/intensity", br##"{"intensity": 0}"##).is_err());
+        assert!(execute(&controller, "/intensity", br##"{"intensity": 101}"##).is_err());
+        assert!(execute(&controller, "/intensity", b"").is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let controller = MinerController::new("stratum+tcp://127.0.0.1:5555".into());
+        controller.set_scheduled(false, ["CPU".to_string()].into());
+        controller.set_cpu_threads(Some(2));
+        controller.resume(&[]);
+        {
+            let state = controller.devices().borrow().clone();
+            assert!(state.device("CPU").paused);
+            assert!(!state.device("#0 (software)").paused);
+        }
+        controller.set_scheduled(false, [].into());
+        let state = controller.devices().borrow().clone();
+        assert!(!state.cpu_thread(1).paused);
+        assert!(state.cpu_thread(2).paused);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+/**
+ What names a device across runs, whatever order the driver enumerates devices in: its PCI address, and the UUID
+ of NVIDIA GPUs. Either is `None` when the backend cannot tell
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceIdentity {
+    /// `DOMAIN:BUS:DEVICE.FUNCTION`, e.g. `0000:01:00.0`
+    pub pci_bus_id: Option<String>,
+    /// As NVML writes it, e.g. `GPU-5b1e1f7c-8a4d-2b6e-93f1-0c2d5e6f7a8b`
+    pub uuid: Option<String>,
+}
+
+impl DeviceIdentity {
+    /**
+    Whether `selector` names this device: its UUID, or its PCI address with or without the domain and the
+    function, e.g. `0000:01:00.0`, `01:00.0` or `01:00`. Case is ignored
+    */
+    pub fn matches(&self, selector: &str) -> bool {
+        let selector = selector.trim().to_ascii_lowercase();
+        if matches!(&self.uuid, Some(uuid) if uuid.to_ascii_lowercase() == selector) {
+            return true;
+        }
+        let pci_bus_id = match &self.pci_bus_id {
+            Some(pci_bus_id) => pci_bus_id.to_ascii_lowercase(),
+            None => return false,
+        };
+        let without_domain = pci_bus_id.split_once(':').map_or(pci_bus_id.as_str(), |(_, address)| address);
+        let without_function = without_domain.split_once('.').map_or(without_domain, |(address, _)| address);
+        [pci_bus_id.as_str(), without_domain, without_function].contains(&selector.as_str())
+    }
+}
+
+/**
+ A device as a plugin sees it before mining on it, see `pyrin-miner list-devices`
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceInfo {
+    /// The backend of the plugin, e.g. `cuda` or `opencl`
+    pub backend: String,
+    /// Index of the OpenCL platform the device belongs to, `None` for backends without platforms
+    pub platform: Option<usize>,
+    pub platform_name: Option<String>,
+    /// Index of the device as `--cuda-device` or `--opencl-device` take it
+    pub index: usize,
+    pub name: String,
+    /// Compute capability of NVIDIA GPUs, e.g. `8.6`, or gfx target of AMD GPUs, e.g. `gfx1030`
+    pub arch: Option<String>,
+    pub memory_bytes: Option<u64>,
+    pub identity: DeviceIdentity,
+    /// Whether the plugin ships a kernel built for the device, instead of compiling one or not supporting it
+    pub precompiled: bool,
+    /// The kernel the device would mine with, e.g. `PTX sm_86` or `compiled from source`
+    pub kernel: String,
+    /// Nonces per batch without a workload option, `None` if the device cannot mine
+    pub default_workload: Option<usize>,
+}
+
+/**
+ A device given on the command line, by its index in the order of the driver or by its identity
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Index(usize),
+    /// A PCI address or a UUID, see `DeviceIdentity::matches`
+    Identity(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, index: usize, identity: &DeviceIdentity) -> bool {
+        match self {
+            DeviceSelector::Index(selected) => *selected == index,
+            DeviceSelector::Identity(selector) => identity.matches(selector),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Index(index) => write!(f, "#{}", index),
+            DeviceSelector::Identity(selector) => write!(f, "{}", selector),
+        }
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("Expected a device index, PCI address or UUID".into()),
+            s => Ok(s.parse().map(DeviceSelector::Index).unwrap_or_else(|_| DeviceSelector::Identity(s.into()))),
+        }
+    }
+}
+
+/**
+ Indexes of the devices the selectors name, in the order they were given, or of every device without selectors
+*/
+pub fn select_devices(
+    selectors: Option<&[DeviceSelector]>,
+    identities: &[DeviceIdentity],
+) -> Result<Vec<usize>, Error> {
+    let selectors = match selectors {
+        Some(selectors) => selectors,
+        None => return Ok((0..identities.len()).collect()),
+    };
+    selectors
+        .iter()
+        .map(|selector| {
+            identities
+                .iter()
+                .enumerate()
+                .position(|(index, identity)| selector.matches(index, identity))
+                .ok_or_else(|| format!("No device matches {}, {} devices found", selector, identities.len()).into())
+        })
+        .collect()
+}
+
+/**
+ Settings given per device as `[SELECTOR=]VALUE`, e.g. `--cuda-workload 256,0000:03:00.0=512`. Values without a
+ selector go to the selected devices in order, and the last of them to the devices after it. Backends that name
+ devices otherwise, e.g. by platform too, key the values by a selector of their own
+*/
+#[derive(Clone, Debug)]
+pub struct PerDevice<T, S = DeviceSelector> {
+    ordered: Vec<T>,
+    keyed: Vec<(S, T)>,
+}
+
+impl<T, S> PerDevice<T, S>
+where
+    T: FromStr + Clone,
+    T::Err: StdError + Send + Sync + 'static,
+    S: FromStr<Err = Error>,
+{
+    pub fn parse(values: &[String]) -> Result<Self, Error> {
+        let mut settings = Self { ordered: Vec::new(), keyed: Vec::new() };
+        for value in values {
+            match value.split_once('=') {
+                Some((selector, value)) => settings.keyed.push((selector.parse()?, value.trim().parse()?)),
+                None => settings.ordered.push(value.trim().parse()?),
+            }
+        }
+        Ok(settings)
+    }
+
+    /**
+    The value of the `position`th of the selected devices, the last one given for a selector `matches` accepts
+    or else the one given in order
+    */
+    pub fn get_matching<F: Fn(&S) -> bool>(&self, position: usize, matches: F) -> Option<T> {
+        match self.keyed.iter().rev().find(|(selector, _)| matches(selector)) {
+            Some((_, value)) => Some(value.clone()),
+            None => self.ordered.get(position).or_else(|| self.ordered.last()).cloned(),
+        }
+    }
+
+    /**
+    The selectors values were given for, e.g. to reject those naming none of the selected devices
+    */
+    pub fn selectors(&self) -> impl Iterator<Item = &S> {
+        self.keyed.iter().map(|(selector, _)| selector)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ordered.is_empty() && self.keyed.is_empty()
+    }
+}
+
+impl<T> PerDevice<T>
+where
+    T: FromStr + Clone,
+    T::Err: StdError + Send + Sync + 'static,
+{
+    /**
+    The value of the device with driver index `index`, the `position`th of the selected devices. A value given
+    for its selector wins over one given in order
+    */
+    pub fn get(&self, position: usize, index: usize, identity: &DeviceIdentity) -> Option<T> {
+        self.get_matching(position, |selector| selector.matches(index, identity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select_devices, DeviceIdentity, DeviceSelector, PerDevice};
+
+    fn identity(pci_bus_id: &str, uuid: Option<&str>) -> DeviceIdentity {
+        DeviceIdentity { pci_bus_id: Some(pci_bus_id.into()), uuid: uuid.map(String::from) }
+    }
+
+    #[test]
+    fn test_matches() {
+        let gpu = identity("0000:0a:00.0", Some("GPU-5b1e1f7c-8a4d-2b6e-93f1-0c2d5e6f7a8b"));
+        assert!(gpu.matches("0000:0a:00.0"));
+        assert!(gpu.matches("0A:00.0"));
+        assert!(gpu.matches("0a:00"));
+        assert!(gpu.matches("gpu-5b1e1f7c-8a4d-2b6e-93f1-0c2d5e6f7a8b"));
+        assert!(!gpu.matches("0b:00.0"));
+        assert!(!gpu.matches("0000"));
+        assert!(!DeviceIdentity::default().matches("0a:00.0"));
+    }
+
+    #[test]
+    fn test_select_devices() {
+        let identities = [identity("0000:01:00.0", None), identity("0000:03:00.0", Some("GPU-1234"))];
+        assert_eq!(select_devices(None, &identities).unwrap(), vec![0, 1]);
+        let selectors = ["GPU-1234", "0"].map(|s| s.parse::<DeviceSelector>().unwrap());
+        assert_eq!(select_devices(Some(&selectors), &identities).unwrap(), vec![1, 0]);
+        assert!(select_devices(Some(&[DeviceSelector::Index(2)]), &identities).is_err());
+        assert!(select_devices(Some(&[DeviceSelector::Identity("05:00.0".into())]), &identities).is_err());
+        assert!("".parse::<DeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn test_per_device() {
+        let values = ["256", "512", "03:00.0=1024"].map(String::from);
+        let workloads = PerDevice::<f32>::parse(&values).unwrap();
+        let (first, second, third) =
+            (identity("0000:01:00.0", None), identity("0000:02:00.0", None), identity("0000:03:00.0", None));
+        assert_eq!(workloads.get(0, 0, &first), Some(256.));
+        assert_eq!(workloads.get(1, 1, &second), Some(512.));
+        assert_eq!(workloads.get(2, 2, &third), Some(1024.));
+        assert_eq!(workloads.get(3, 3, &DeviceIdentity::default()), Some(512.));
+
+        let keyed = PerDevice::<u32>::parse(&["1=1200".into()]).unwrap();
+        assert_eq!(keyed.get(0, 1, &first), Some(1200));
+        assert_eq!(keyed.get(1, 0, &second), None);
+        assert!(PerDevice::<u32>::parse(&[]).unwrap().is_empty());
+        assert!(PerDevice::<u32>::parse(&["01:00.0=fast".into()]).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+// Events kept for a slow receiver before it starts missing them
+const CAPACITY: usize = 256;
+
+static EVENTS: Lazy<Channel> = Lazy::new(Channel::new);
+
+/**
+ What the miner is doing, for applications embedding it. Sent next to the log lines and hooks of the same events
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum MinerEvent {
+    /// A new job from the pool or the node, only jobs from a pool have an id
+    Job { job_id: Option<String> },
+    /// The answer of the pool to a share, or of the node to a block
+    ShareResult { job_id: Option<String>, accepted: bool, reason: Option<String> },
+    /// Hashes per second over the last interval, in total and by device
+    Hashrate { total: f64, devices: Vec<(String, f64)> },
+    /// A device or the connection failed, the connection has no device
+    Error { device: Option<String>, error: String },
+}
+
+struct Channel(broadcast::Sender<MinerEvent>);
+
+impl Channel {
+    fn new() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<MinerEvent> {
+        self.0.subscribe()
+    }
+
+    fn emit(&self, event: MinerEvent) {
+        // Fails only when nobody listens
+        let _ = self.0.send(event);
+    }
+}
+
+/**
+ Receives the events sent from now on. Events of every miner of the process go through the same channel
+*/
+pub fn subscribe() -> broadcast::Receiver<MinerEvent> {
+    EVENTS.subscribe()
+}
+
+pub(crate) fn emit(event: MinerEvent) {
+    EVENTS.emit(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, MinerEvent};
+
+    #[test]
+    fn test_subscribe() {
+        // Miners started by other tests send to the process channel
+        let channel = Channel::new();
+        channel.emit(MinerEvent::Job { job_id: None });
+        let mut events = channel.subscribe();
+        let event = MinerEvent::Error { device: Some("#0".into()), error: "lost".into() };
+        channel.emit(event.clone());
+        assert_eq!(events.try_recv().unwrap(), event);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+// Applies to connecting, sending the request and reading the response each
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Events waiting for a busy hook, later ones are dropped until it catches up
+const QUEUE_LENGTH: usize = 64;
+
+static HOOKS: OnceCell<Hooks> = OnceCell::new();
+
+#[derive(Clone, Debug)]
+struct Event {
+    name: &'static str,
+    timestamp: u64,
+    fields: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Hooks {
+    command: Option<SyncSender<Event>>,
+    url: Option<SyncSender<Event>>,
+}
+
+/**
+ Sets the hooks fired on miner events. Events fired before this are dropped
+*/
+pub fn init(command: Option<String>, url: Option<String>) -> Result<(), Error> {
+    if let Some(url) = &url {
+        check_url(url)?;
+    }
+    let hooks = Hooks {
+        command: command.map(|command| {
+            spawn_hook("command", move |event| run_command(&command, event.name, event.timestamp, &event.fields))
+        }),
+        url: url.map(|url| {
+            spawn_hook("POST", move |event| post_json(&url, &to_json(event.name, event.timestamp, &event.fields)))
+        }),
+    };
+    HOOKS.set(hooks).map_err(|_| "Hooks are already initialized")?;
+    Ok(())
+}
+
+/**
+ Fails unless the URL is one the hooks can POST to, a plain `http://host[:port][/path]`
+*/
+pub fn check_url(url: &str) -> Result<(), Error> {
+    parse_http_url(url).map(|_| ())
+}
+
+/**
+ Fires an event, e.g. `fire("share_rejected", &[("reason", "stale".into())])`.
+ Each hook runs the events one after the other on a thread of its own, so this never blocks the caller
+*/
+pub fn fire(event: &'static str, fields: &[(&'static str, String)]) {
+    let hooks = match HOOKS.get() {
+        Some(hooks) if hooks.command.is_some() || hooks.url.is_some() => hooks,
+        _ => return,
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let event = Event { name: event, timestamp, fields: fields.to_vec() };
+
+    for (kind, queue) in [("command", &hooks.command), ("POST", &hooks.url)] {
+        if let Some(queue) = queue {
+            if let Err(TrySendError::Full(event)) = queue.try_send(event.clone()) {
+                warn!("Hook {} is still busy, dropping {}", kind, event.name);
+            }
+        }
+    }
+}
+
+fn spawn_hook<F>(kind: &'static str, run: F) -> SyncSender<Event>
+where
+    F: Fn(&Event) -> Result<(), Error> + Send + 'static,
+{
+    let (queue, events) = mpsc::sync_channel::<Event>(QUEUE_LENGTH);
+    std::thread::spawn(move || {
+        for event in events {
+            if let Err(e) = run(&event) {
+                warn!("Hook {} for {} failed: {}", kind, event.name, e);
+            }
+        }
+    });
+    queue
+}
+
+fn run_command(command: &str, event: &str, timestamp: u64, fields: &[(&str, String)]) -> Result<(), Error> {
+    #[cfg(target_os = "windows")]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.env("PYRIN_MINER_EVENT", event).env("PYRIN_MINER_TIMESTAMP", timestamp.to_string());
+    for (key, value) in fields {
+        cmd.env(format!("PYRIN_MINER_{}", key.to_uppercase()), value);
+    }
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+    Ok(())
+}
+
+fn to_json(event: &str, timestamp: u64, fields: &[(&str, String)]) -> String {
+    let mut body = serde_json::Map::new();
+    body.insert("event".into(), event.into());
+    body.insert("timestamp".into(), timestamp.into());
+    for (key, value) in fields {
+        body.insert(key.to_string(), value.clone().into());
+    }
+    serde_json::Value::Object(body).to_string()
+}
+
+// Splits `http://host[:port][/path]` into the address to connect to, the host header and the path
+fn parse_http_url(url: &str) -> Result<(String, String, String), Error> {
+    let rest = url.strip_prefix("http://").ok_or("Only http:// hook URLs are supported")?;
+    let (host, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("Hook URL {} has no host", url).into());
+    }
+    let address = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    Ok((address, host.to_string(), path.to_string()))
+}
+
+// Tries every address the host resolves to, each for at most HTTP_TIMEOUT
+fn connect(address: &str) -> Result<TcpStream, Error> {
+    let mut last_error = None;
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, HTTP_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => format!("{} does not resolve to any address", address).into(),
+    })
+}
+
+fn post_json(url: &str, body: &str) -> Result<(), Error> {
+    let (address, host, path) = parse_http_url(url)?;
+    let mut stream = connect(&address)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => {
+            debug!("Hook POST answered {}", status);
+            Ok(())
+        }
+        _ => Err(format!("unexpected response: {}", status).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_http_url, spawn_hook, to_json, Event, QUEUE_LENGTH};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_parse_http_url() {
+        assert_eq!(
+            parse_http_url("http://127.0.0.1:8080/events").unwrap(),
+            ("127.0.0.1:8080".into(), "127.0.0.1:8080".into(), "/events".into())
+        );
+        assert_eq!(
+            parse_http_url("http://localhost").unwrap(),
+            ("localhost:80".into(), "localhost".into(), "/".into())
+        );
+        assert!(parse_http_url("https://localhost").is_err());
+        assert!(parse_http_url("http:///path").is_err());
+    }
+
+    #[test]
+    fn test_busy_hook_drops_events() {
+        let (release, blocked) = mpsc::channel::<()>();
+        let queue = spawn_hook("test", move |_| {
+            let _ = blocked.recv();
+            Ok(())
+        });
+        let event = Event { name: "test", timestamp: 0, fields: Vec::new() };
+        // The hook thread may have taken the first event already, the queue holds QUEUE_LENGTH more
+        let sent = (0..QUEUE_LENGTH + 2).filter(|_| queue.try_send(event.clone()).is_ok()).count();
+        assert!((QUEUE_LENGTH..=QUEUE_LENGTH + 1).contains(&sent));
+        drop(release);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json("share_rejected", 42, &[("reason", "stale".into())])).unwrap();
+        assert_eq!(json["event"], "share_rejected");
+        assert_eq!(json["timestamp"], 42);
+        assert_eq!(json["reason"], "stale");
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Results kept for the error rate of a device
+const WINDOW: usize = 50;
+// Do not act on the first few results, a single error would look like a 100% rate
+const MIN_SAMPLES: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HwErrorConfig {
+    /// Error rate (0..1) above which the device is backed off
+    pub threshold: f64,
+    pub check_interval: Duration,
+}
+
+impl Default for HwErrorConfig {
+    fn default() -> Self {
+        Self { threshold: 0.05, check_interval: Duration::from_secs(300) }
+    }
+}
+
+/**
+ Rolling hardware error rate of a single device, fed by the nonces it finds and by spot checks
+*/
+pub struct HwErrorTracker {
+    results: VecDeque<bool>,
+    errors: usize,
+    threshold: f64,
+}
+
+impl HwErrorTracker {
+    pub fn new(threshold: f64) -> Self {
+        Self { results: VecDeque::with_capacity(WINDOW), errors: 0, threshold }
+    }
+
+    pub fn record(&mut self, valid: bool) {
+        if self.results.len() == WINDOW && !self.results.pop_front().expect("The window is full") {
+            self.errors -= 1;
+        }
+        if !valid {
+            self.errors += 1;
+        }
+        self.results.push_back(valid);
+    }
+
+    pub fn rate(&self) -> f64 {
+        match self.results.len() {
+            0 => 0.,
+            n => self.errors as f64 / n as f64,
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.results.len() >= MIN_SAMPLES && self.rate() > self.threshold
+    }
+
+    pub fn reset(&mut self) {
+        self.results.clear();
+        self.errors = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HwErrorTracker, MIN_SAMPLES, WINDOW};
+
+    #[test]
+    fn test_needs_min_samples() {
+        let mut tracker = HwErrorTracker::new(0.05);
+        tracker.record(false);
+        assert_eq!(tracker.rate(), 1.);
+        assert!(!tracker.exceeded());
+        (1..MIN_SAMPLES).for_each(|_| tracker.record(true));
+        assert!(tracker.exceeded());
+        tracker.reset();
+        assert!(!tracker.exceeded());
+    }
+
+    #[test]
+    fn test_old_errors_expire() {
+        let mut tracker = HwErrorTracker::new(0.05);
+        (0..5).for_each(|_| tracker.record(false));
+        (0..WINDOW).for_each(|_| tracker.record(true));
+        assert_eq!(tracker.rate(), 0.);
+        assert!(!tracker.exceeded());
+    }
+}
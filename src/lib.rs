@@ -1,55 +1,182 @@
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
 use clap::ArgMatches;
 use std::any::Any;
 use std::error::Error as StdError;
+use std::fmt;
 
+pub mod abi;
+#[cfg(feature = "miner")]
+pub mod builder;
+#[cfg(feature = "miner")]
+pub mod client;
+#[cfg(feature = "miner")]
+pub mod control;
+pub mod device;
+#[cfg(feature = "miner")]
+pub mod events;
+#[cfg(feature = "miner")]
+pub mod hooks;
+#[cfg(feature = "miner")]
+pub mod hw_errors;
+pub mod logging;
+#[cfg(feature = "miner")]
+pub mod miner;
+pub mod pow;
+#[cfg(feature = "miner")]
+mod pyipad_messages;
+#[cfg(feature = "miner")]
+pub mod schedule;
+#[cfg(feature = "miner")]
+pub mod stats;
+pub mod target;
+#[cfg(feature = "miner")]
+pub mod thermal;
+pub mod tuning;
+#[cfg(feature = "miner")]
+mod watch;
 pub mod xoshiro256starstar;
-use libloading::{Library, Symbol};
+use crate::abi::{LoadedPlugin, PluginMetadata};
+#[cfg(feature = "miner")]
+pub use crate::builder::{MinerBuilder, MinerHandle};
+#[cfg(feature = "miner")]
+pub use crate::control::MinerController;
+pub use crate::device::{DeviceIdentity, DeviceInfo};
+#[cfg(feature = "miner")]
+pub use crate::events::MinerEvent;
+use crate::target::Uint256;
+use libloading::Library;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+pub mod proto {
+    #![allow(clippy::derive_partial_eq_without_eq)]
+    // What tonic::include_proto! expands to, so the messages build without tonic
+    include!(concat!(env!("OUT_DIR"), "/protowire.rs"));
+    // include!("protowire.rs"); // FIXME: https://github.com/intellij-rust/intellij-rust/issues/6579
+}
 
 pub type Error = Box<dyn StdError + Send + Sync + 'static>;
 
+pub type Hash = Uint256;
+
+/**
+ Failure of a worker, by how the miner can recover from it
+*/
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The call failed but the device is fine, retrying may work
+    Transient(Error),
+    /// The device or its context is gone, the worker has to be built again
+    DeviceLost(Error),
+    /// The device cannot mine anymore
+    Fatal(Error),
+}
+
+impl WorkerError {
+    /**
+    Reads the kind of an error returned as `Error`, anything but a `WorkerError` counts as transient
+    */
+    pub fn classify(error: Error) -> Self {
+        match error.downcast::<WorkerError>() {
+            Ok(error) => *error,
+            Err(error) => WorkerError::Transient(error),
+        }
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::Transient(e) => write!(f, "{}", e),
+            WorkerError::DeviceLost(e) => write!(f, "device lost: {}", e),
+            WorkerError::Fatal(e) => write!(f, "fatal: {}", e),
+        }
+    }
+}
+
+impl StdError for WorkerError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            WorkerError::Transient(e) | WorkerError::DeviceLost(e) | WorkerError::Fatal(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/**
+ Sensor readings of a device, each `None` when the backend cannot read it
+*/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Telemetry {
+    pub temperature_c: Option<f32>,
+    pub power_w: Option<f32>,
+    pub fan_percent: Option<f32>,
+    pub core_clock_mhz: Option<u32>,
+    pub memory_clock_mhz: Option<u32>,
+    /// PCI address as `DOMAIN:BUS:DEVICE.FUNCTION`, e.g. `0000:01:00.0`
+    pub pci_bus_id: Option<String>,
+}
+
+impl fmt::Display for Telemetry {
+    // The readings there are, e.g. `64°C, 182W, fan 55%, core 1800 MHz, memory 7000 MHz, bus 0000:01:00.0`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let readings = [
+            self.temperature_c.map(|temperature| format!("{:.0}°C", temperature)),
+            self.power_w.map(|power| format!("{:.0}W", power)),
+            self.fan_percent.map(|fan| format!("fan {:.0}%", fan)),
+            self.core_clock_mhz.map(|clock| format!("core {} MHz", clock)),
+            self.memory_clock_mhz.map(|clock| format!("memory {} MHz", clock)),
+            self.pci_bus_id.as_ref().map(|pci_bus_id| format!("bus {}", pci_bus_id)),
+        ];
+        write!(f, "{}", readings.into_iter().flatten().collect::<Vec<_>>().join(", "))
+    }
+}
+
 #[derive(Default)]
 pub struct PluginManager {
-    plugins: Vec<Box<dyn Plugin>>,
+    plugins: Vec<LoadedPlugin>,
     loaded_libraries: Vec<Library>,
+    loads: Vec<PluginLoad>,
+}
+
+/**
+ Outcome of loading one plugin library, kept so it can be reported once logging is set up
+*/
+#[derive(Clone, Debug)]
+pub struct PluginLoad {
+    pub path: String,
+    pub result: Result<PluginMetadata, String>,
 }
 
 /**
  Plugin Manager class - allows inserting your own hashers
  Inspired by https://michael-f-bryan.github.io/rust-ffi-guide/dynamic_loading.html
+ Plugins are only reached through the C interface of `abi`, so they may be built with another compiler
 */
 impl PluginManager {
     pub fn new() -> Self {
-        Self { plugins: Vec::new(), loaded_libraries: Vec::new() }
+        Self { plugins: Vec::new(), loaded_libraries: Vec::new(), loads: Vec::new() }
     }
 
     pub(crate) unsafe fn load_single_plugin<'help>(
         &mut self,
         app: clap::App<'help>,
         path: &str,
-    ) -> Result<clap::App<'help>, (clap::App<'help>, Error)> {
-        type PluginCreate<'help> =
-            unsafe fn(*const clap::App<'help>) -> (*mut clap::App<'help>, *mut dyn Plugin, *mut Error);
-
+    ) -> Result<clap::App<'help>, Box<(clap::App<'help>, Error)>> {
         let lib = match Library::new(path) {
             Ok(l) => l,
-            Err(e) => return Err((app, e.to_string().into())),
+            Err(e) => return Err(Box::new((app, e.to_string().into()))),
         };
 
         self.loaded_libraries.push(lib); // Save library so it persists in memory
         let lib = self.loaded_libraries.last().unwrap();
 
-        let constructor: Symbol<PluginCreate> = match lib.get(b"_plugin_create") {
-            Ok(cons) => cons,
-            Err(e) => return Err((app, e.to_string().into())),
+        let plugin = match LoadedPlugin::load(lib) {
+            Ok(plugin) => plugin,
+            Err(e) => return Err(Box::new((app, e))),
         };
-
-        let (app, boxed_raw, error) = constructor(Box::into_raw(Box::new(app)));
-        let app = *Box::from_raw(app);
-
-        if boxed_raw.is_null() {
-            return Err((app, *Box::from_raw(error)));
-        }
-        let plugin = Box::from_raw(boxed_raw);
+        let app = plugin.augment_args(app);
         self.plugins.push(plugin);
 
         Ok(app)
@@ -59,7 +186,7 @@ impl PluginManager {
         let mut specs = Vec::<Box<dyn WorkerSpec + 'static>>::new();
         for plugin in &self.plugins {
             if plugin.enabled() {
-                specs.extend(plugin.get_worker_specs());
+                specs.extend(plugin.worker_specs());
             }
         }
         Ok(specs)
@@ -71,14 +198,10 @@ impl PluginManager {
     pub fn process_options(&mut self, matchs: &ArgMatches) -> Result<usize, Error> {
         let mut count = 0usize;
         self.plugins.iter_mut().for_each(|plugin| {
-            count += match plugin.process_option(matchs) {
+            count += match plugin.configure(matchs) {
                 Ok(n) => n,
                 Err(e) => {
-                    eprintln!(
-                        "WARNING: Failed processing options for {} (ignore if you do not intend to use): {}",
-                        plugin.name(),
-                        e
-                    );
+                    warn!("Failed processing options for {} (ignore if you do not intend to use): {}", plugin.name, e);
                     0
                 }
             }
@@ -89,6 +212,20 @@ impl PluginManager {
     pub fn has_specs(&self) -> bool {
         !self.plugins.is_empty()
     }
+
+    /**
+    The devices each loaded plugin sees, by plugin name, whether or not its options enable it
+    */
+    pub fn list_devices(&self) -> Vec<(String, Result<Vec<DeviceInfo>, Error>)> {
+        self.plugins.iter().map(|plugin| (plugin.name.clone(), plugin.list_devices())).collect()
+    }
+
+    /**
+    Every library `load_plugins` tried, in order, with the plugin it loaded or why it failed
+    */
+    pub fn loads(&self) -> &[PluginLoad] {
+        &self.loads
+    }
 }
 
 pub trait Plugin: Any + Send + Sync {
@@ -96,6 +233,13 @@ pub trait Plugin: Any + Send + Sync {
     fn enabled(&self) -> bool;
     fn get_worker_specs(&self) -> Vec<Box<dyn WorkerSpec>>;
     fn process_option(&mut self, matchs: &ArgMatches) -> Result<usize, Error>;
+
+    /**
+    Every device the backend sees, including the ones the options leave out. Called without `process_option`
+    */
+    fn list_devices(&self) -> Result<Vec<DeviceInfo>, Error> {
+        Ok(Vec::new())
+    }
 }
 
 pub trait WorkerSpec: Any + Send + Sync {
@@ -105,19 +249,90 @@ pub trait WorkerSpec: Any + Send + Sync {
     workload: f32,
     is_absolute: bool*/
     fn id(&self) -> String;
-    fn build(&self) -> Box<dyn Worker>;
+    fn build(&self) -> Result<Box<dyn Worker>, Error>;
+
+    /**
+    What names the device across runs, so settings keyed by it reach the same card when device indices change
+    */
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity::default()
+    }
 }
 
 pub trait Worker {
     //fn new(device_id: u32, workload: f32, is_absolute: bool) -> Result<Self, Error>;
     fn id(&self) -> String;
-    fn load_block_constants(&mut self, hash_header: &[u8; 72], matrix: &[[u16; 64]; 64], target: &[u64; 4]);
+    fn load_block_constants(
+        &mut self,
+        hash_header: &[u8; 72],
+        matrix: &[[u16; 64]; 64],
+        target: &[u64; 4],
+    ) -> Result<(), WorkerError>;
 
-    fn calculate_hash(&mut self, nonces: Option<&Vec<u64>>, nonce_mask: u64, nonce_fixed: u64);
+    fn calculate_hash(
+        &mut self,
+        nonces: Option<&Vec<u64>>,
+        nonce_mask: u64,
+        nonce_fixed: u64,
+    ) -> Result<(), WorkerError>;
     fn sync(&self) -> Result<(), Error>;
 
     fn get_workload(&self) -> usize;
     fn copy_output_to(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error>;
+
+    /**
+    How many batches `calculate_hash` may queue before the oldest one is collected with `finish_batch`.
+    Constants loaded while batches are queued only apply to the batches launched after them
+    */
+    fn pipeline_depth(&self) -> usize {
+        1
+    }
+
+    /**
+    Waits for the oldest batch in flight and copies its result
+    */
+    fn finish_batch(&mut self, nonces: &mut Vec<u64>) -> Result<(), Error> {
+        self.sync()?;
+        self.copy_output_to(nonces)
+    }
+
+    /**
+    Waits for the oldest batch in flight and returns every nonce it found. Workers that keep a single
+    result per batch return at most one
+    */
+    fn collect_nonces(&mut self) -> Result<Vec<u64>, Error> {
+        let mut nonces = vec![0u64; 1];
+        self.finish_batch(&mut nonces)?;
+        Ok(nonces.into_iter().filter(|&nonce| nonce != 0).collect())
+    }
+
+    /**
+    Device time of the last batch collected in milliseconds, if the backend can measure it
+    */
+    fn kernel_time_ms(&self) -> Option<f32> {
+        None
+    }
+
+    /**
+    Changes the number of nonces per batch. Workers may clamp it, `get_workload` reports the applied value
+    */
+    fn set_workload(&mut self, _workload: usize) -> Result<(), Error> {
+        Err(format!("{} does not support changing the workload", self.id()).into())
+    }
+
+    /**
+    Lowers the clocks of the device after too many hardware errors. Returns false if it could not
+    */
+    fn backoff(&mut self) -> bool {
+        false
+    }
+
+    /**
+    Reads the sensors of the device, None if the backend has no way to
+    */
+    fn telemetry(&self) -> Option<Telemetry> {
+        None
+    }
 }
 
 pub fn load_plugins<'help>(
@@ -127,42 +342,49 @@ pub fn load_plugins<'help>(
     let mut factory = PluginManager::new();
     let mut app = app;
     for path in paths {
-        app = unsafe {
-            factory.load_single_plugin(app, path.as_str()).unwrap_or_else(|(app, e)| {
-                eprintln!("WARNING: Failed loading plugin {} (ignore if you do not intend to use): {}", path, e);
-                app
-            })
+        let result;
+        (app, result) = match unsafe { factory.load_single_plugin(app, path.as_str()) } {
+            Ok(app) => (app, Ok(factory.plugins.last().expect("The plugin was just loaded").metadata.clone())),
+            Err(failed) => {
+                let (app, e) = *failed;
+                (app, Err(e.to_string()))
+            }
         };
+        factory.loads.push(PluginLoad { path: path.clone(), result });
     }
     Ok((app, factory))
 }
 
+/**
+ Exports a plugin through the C interface of `abi`, e.g. `declare_plugin!(CudaPlugin, CudaPlugin::new, CudaOpt, "cuda")`
+ with the plugin type, its constructor, its clap arguments and the backends it mines with
+*/
 #[macro_export]
 macro_rules! declare_plugin {
-    ($plugin_type:ty, $constructor:path, $args:ty) => {
-        use clap::Args;
-        #[no_mangle]
-        pub unsafe extern "C" fn _plugin_create(
-            app: *mut clap::App,
-        ) -> (*mut clap::App, *mut dyn $crate::Plugin, *const $crate::Error) {
-            // make sure the constructor is the correct type.
-            let constructor: fn() -> Result<$plugin_type, $crate::Error> = $constructor;
-
-            let object = match constructor() {
-                Ok(obj) => obj,
-                Err(e) => {
-                    return (
-                        app,
-                        unsafe { std::mem::MaybeUninit::zeroed().assume_init() }, // Translates to null pointer
-                        Box::into_raw(Box::new(e)),
-                    );
-                }
-            };
+    ($plugin_type:ty, $constructor:path, $args:ty, $($backend:literal),+) => {
+        struct PluginEntry;
 
-            let boxed: Box<dyn $crate::Plugin> = Box::new(object);
+        impl $crate::abi::PluginEntry for PluginEntry {
+            type Args = $args;
 
-            let boxed_app = Box::new(<$args>::augment_args(unsafe { *Box::from_raw(app) }));
-            (Box::into_raw(boxed_app), Box::into_raw(boxed), std::ptr::null::<Error>())
+            fn create() -> Result<Box<dyn $crate::Plugin>, $crate::Error> {
+                // make sure the constructor is the correct type.
+                let constructor: fn() -> Result<$plugin_type, $crate::Error> = $constructor;
+                Ok(Box::new(constructor()?))
+            }
         }
+
+        #[no_mangle]
+        pub static PYRIN_PLUGIN_ABI_VERSION: u32 = $crate::abi::ABI_VERSION;
+
+        #[no_mangle]
+        pub static PYRIN_PLUGIN_DECLARATION: $crate::abi::PluginDeclaration = $crate::abi::PluginDeclaration {
+            name: concat!(env!("CARGO_PKG_NAME"), "\0").as_ptr() as *const std::os::raw::c_char,
+            version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const std::os::raw::c_char,
+            backends: concat!($($backend, ","),+, "\0").as_ptr() as *const std::os::raw::c_char,
+            plugin: $crate::abi::PluginVTable::of::<PluginEntry>(),
+            spec: $crate::abi::SpecVTable::new(),
+            worker: $crate::abi::WorkerVTable::new(),
+        };
     };
 }
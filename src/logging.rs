@@ -0,0 +1,219 @@
+use crate::Error;
+use clap::{ArgMatches, Args, FromArgMatches};
+use env_logger::fmt::{Color, Formatter};
+use log::{Level, LevelFilter, Record};
+use once_cell::sync::OnceCell;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+pub use serde_json::Value;
+
+// The miner and every plugin have their own logger, each one switches this after parsing the options
+static JSON: AtomicBool = AtomicBool::new(false);
+static CAPTURE: AtomicBool = AtomicBool::new(false);
+// Lines kept while captured
+const RECENT_LINES: usize = 100;
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+// A plugin has its own copy of the statics above, it sends its captured lines to the miner through this
+static FORWARD: OnceCell<Forward> = OnceCell::new();
+
+type Forward = Box<dyn Fn(&str) + Send + Sync>;
+type Fields = Vec<(&'static str, Value)>;
+
+thread_local! {
+    // Fields of the event being logged on this thread, read back by the formatter
+    static EVENT: RefCell<Option<(&'static str, Fields)>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected text or json", s).into()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct LogOpt {
+    #[clap(
+        long = "log-format",
+        default_value = "text",
+        possible_values = ["text", "json"],
+        help = "Log as human readable text, or as one JSON object per line"
+    )]
+    pub log_format: LogFormat,
+    #[clap(long = "tui", help = "Show a live dashboard of the devices instead of the log lines")]
+    pub tui: bool,
+}
+
+/**
+ Installs the logger. The miner and each plugin call this once, so they all share the same output format
+*/
+pub fn init(level: LevelFilter) {
+    env_logger::builder().filter_level(level).parse_default_env().format(write_record).init();
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/**
+ Keeps the log lines in memory instead of writing them to the terminal, see `recent`
+*/
+pub fn set_capture(capture: bool) {
+    CAPTURE.store(capture, Ordering::Relaxed);
+}
+
+/**
+ Sends the captured lines of this logger to `forward` instead of keeping them, set by the miner in each plugin
+*/
+pub fn forward_captured<F: Fn(&str) + Send + Sync + 'static>(forward: F) {
+    let _ = FORWARD.set(Box::new(forward));
+}
+
+/**
+ Keeps a captured line, whichever logger it comes from
+*/
+pub fn capture(line: String) {
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_LINES {
+        recent.pop_front();
+    }
+    recent.push_back(line);
+}
+
+/**
+ The latest captured log lines of the miner and its plugins, oldest first
+*/
+pub fn recent(count: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().skip(recent.len().saturating_sub(count)).cloned().collect()
+}
+
+/**
+ Applies `--log-format` and `--tui` from the matches of the miner, for plugins
+*/
+pub fn configure(matches: &ArgMatches) -> Result<(), Error> {
+    let opts = LogOpt::from_arg_matches(matches)?;
+    set_format(opts.log_format);
+    set_capture(opts.tui);
+    Ok(())
+}
+
+/**
+ Logs a message with an event name and stable fields, use through `log_event!`.
+ Text logs only show the message, JSON logs also carry the event and its fields
+*/
+pub fn event(
+    level: Level,
+    module_path: &'static str,
+    event: &'static str,
+    fields: &[(&'static str, Value)],
+    args: fmt::Arguments,
+) {
+    if level > log::max_level() {
+        return;
+    }
+    EVENT.with(|current| *current.borrow_mut() = Some((event, fields.to_vec())));
+    log::logger().log(
+        &Record::builder().args(args).level(level).target(module_path).module_path_static(Some(module_path)).build(),
+    );
+    EVENT.with(|current| current.borrow_mut().take());
+}
+
+/**
+ `log_event!(Level::Info, "share", &[("result", "accepted".into())], "Share accepted")`
+*/
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, $event:expr, $fields:expr, $($arg:tt)+) => {
+        $crate::logging::event($level, module_path!(), $event, $fields, format_args!($($arg)+))
+    };
+}
+
+fn write_record(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    if CAPTURE.load(Ordering::Relaxed) {
+        let line = match JSON.load(Ordering::Relaxed) {
+            true => to_json(&buf.timestamp_millis().to_string(), record),
+            false => format!("[{} {:<5} {}] {}", buf.timestamp(), record.level(), record.target(), record.args()),
+        };
+        match FORWARD.get() {
+            Some(forward) => forward(&line),
+            None => capture(line),
+        }
+        Ok(())
+    } else if JSON.load(Ordering::Relaxed) {
+        writeln!(buf, "{}", to_json(&buf.timestamp_millis().to_string(), record))
+    } else {
+        // Same layout as the default env_logger format, integrations parse it by field position
+        let mut subtle = buf.style();
+        subtle.set_color(Color::Black).set_intense(true);
+        write!(buf, "{}{} {:<5}", subtle.value("["), buf.timestamp(), buf.default_styled_level(record.level()))?;
+        if let Some(module_path) = record.module_path() {
+            write!(buf, " {}", module_path)?;
+        }
+        writeln!(buf, "{} {}", subtle.value("]"), record.args())
+    }
+}
+
+fn to_json(timestamp: &str, record: &Record) -> String {
+    let mut object = serde_json::Map::new();
+    object.insert("timestamp".into(), timestamp.into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    EVENT.with(|current| match &*current.borrow() {
+        Some((event, fields)) => {
+            object.insert("event".into(), (*event).into());
+            for (key, value) in fields {
+                object.insert(key.to_string(), value.clone());
+            }
+        }
+        None => {
+            object.insert("event".into(), "message".into());
+        }
+    });
+    object.insert("message".into(), record.args().to_string().into());
+    Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_json, Value, EVENT};
+    use log::{Level, Record};
+
+    #[test]
+    fn test_to_json() {
+        let log = || {
+            let json = to_json(
+                "now",
+                &Record::builder().args(format_args!("Share accepted")).level(Level::Info).target("stratum").build(),
+            );
+            serde_json::from_str::<Value>(&json).unwrap()
+        };
+        let json = log();
+        assert_eq!(json["event"], "message");
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["message"], "Share accepted");
+
+        EVENT.with(|current| *current.borrow_mut() = Some(("share", vec![("result", "accepted".into())])));
+        let json = log();
+        assert_eq!(json["event"], "share");
+        assert_eq!(json["result"], "accepted");
+        assert_eq!(json["timestamp"], "now");
+    }
+}
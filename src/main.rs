@@ -1,47 +1,19 @@
-#![cfg_attr(all(test, feature = "bench"), feature(test))]
-
-use std::env::consts::DLL_EXTENSION;
 use std::env::current_exe;
 use std::error::Error as StdError;
-use std::ffi::OsStr;
 
 use clap::{App, FromArgMatches, IntoApp};
-use pyrin_miner::PluginManager;
-use log::{error, info};
-use rand::{thread_rng, RngCore};
-use std::fs;
-use std::sync::atomic::AtomicU16;
-use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Duration;
+use log::{info, Level};
+use pyrin_miner::{control, hooks, log_event, logging, MinerBuilder, PluginManager};
+use tokio::net::TcpListener;
 
-use crate::cli::Opt;
-use crate::client::grpc::PyipadHandler;
-use crate::client::stratum::StratumHandler;
-use crate::client::Client;
-use crate::miner::MinerManager;
-use crate::target::Uint256;
+use crate::cli::{Command, Opt, PluginsCommand};
 
 mod cli;
-mod client;
-mod pyipad_messages;
-mod miner;
-mod pow;
-mod target;
-mod watch;
-
-const WHITELIST: [&str; 4] = ["libpyrincuda", "libpyrinopencl", "pyrincuda", "pyrinopencl"];
-
-pub mod proto {
-    #![allow(clippy::derive_partial_eq_without_eq)]
-    tonic::include_proto!("protowire");
-    // include!("protowire.rs"); // FIXME: https://github.com/intellij-rust/intellij-rust/issues/6579
-}
+mod plugins;
+mod tui;
 
 pub type Error = Box<dyn StdError + Send + Sync + 'static>;
 
-type Hash = Uint256;
-
 #[cfg(target_os = "windows")]
 fn adjust_console() -> Result<(), Error> {
     let console = win32console::console::WinConsole::input();
@@ -52,71 +24,6 @@ fn adjust_console() -> Result<(), Error> {
     Ok(())
 }
 
-fn filter_plugins(dirname: &str) -> Vec<String> {
-    match fs::read_dir(dirname) {
-        Ok(readdir) => readdir
-            .map(|entry| entry.unwrap().path())
-            .filter(|fname| {
-                fname.is_file()
-                    && fname.extension().is_some()
-                    && fname.extension().and_then(OsStr::to_str).unwrap_or_default().starts_with(DLL_EXTENSION)
-            })
-            .filter(|fname| WHITELIST.iter().any(|lib| *lib == fname.file_stem().and_then(OsStr::to_str).unwrap()))
-            .map(|path| path.to_str().unwrap().to_string())
-            .collect::<Vec<String>>(),
-        _ => Vec::<String>::new(),
-    }
-}
-
-async fn get_client(
-    pyrin_address: String,
-    mining_address: String,
-    mine_when_not_synced: bool,
-    block_template_ctr: Arc<AtomicU16>,
-) -> Result<Box<dyn Client + 'static>, Error> {
-    if pyrin_address.starts_with("stratum+tcp://") {
-        let (_schema, address) = pyrin_address.split_once("://").unwrap();
-        Ok(StratumHandler::connect(
-            address.to_string().clone(),
-            mining_address.clone(),
-            mine_when_not_synced,
-            Some(block_template_ctr.clone()),
-        )
-        .await?)
-    } else if pyrin_address.starts_with("grpc://") {
-        Ok(PyipadHandler::connect(
-            pyrin_address.clone(),
-            mining_address.clone(),
-            mine_when_not_synced,
-            Some(block_template_ctr.clone()),
-        )
-        .await?)
-    } else {
-        Err("Did not recognize pool/grpc address schema".into())
-    }
-}
-
-async fn client_main(
-    opt: &Opt,
-    block_template_ctr: Arc<AtomicU16>,
-    plugin_manager: &PluginManager,
-) -> Result<(), Error> {
-    let mut client = get_client(
-        opt.pyrin_address.clone(),
-        opt.mining_address.clone(),
-        opt.mine_when_not_synced,
-        block_template_ctr.clone(),
-    )
-    .await?;
-
-    client.add_devfund(String::from("pyrin:qzj9kz0kmc3rxl9mw86mlda2cqmvp3xhavx9h2jud5ehdchvruql6ey64r8kz"), 2);
-    client.register().await?;
-    let mut miner_manager = MinerManager::new(client.get_block_channel(), opt.num_threads, plugin_manager);
-    client.listen(&mut miner_manager).await?;
-    drop(miner_manager);
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     #[cfg(target_os = "windows")]
@@ -125,27 +32,41 @@ async fn main() -> Result<(), Error> {
     });
     let mut path = current_exe().unwrap_or_default();
     path.pop(); // Getting the parent directory
-    let plugins = filter_plugins(path.to_str().unwrap_or("."));
-    let (app, mut plugin_manager): (App, PluginManager) = pyrin_miner::load_plugins(Opt::into_app(), &plugins)?;
-
-    let matches = app.get_matches();
+    let args = std::env::args().collect::<Vec<String>>();
+    let discovery = plugins::discover(&plugins::prescan(&args), &path)?;
+    let (app, mut plugin_manager): (App, PluginManager) =
+        pyrin_miner::load_plugins(Opt::into_app(), &discovery.paths)?;
+
+    let matches = app.get_matches_from(&args);
+    let plugin_report = plugins::report(&discovery, &plugin_manager);
+    match Command::from_arg_matches(&matches) {
+        Ok(Command::Plugins(PluginsCommand::List)) => {
+            plugins::print_report(&plugin_report);
+            return Ok(());
+        }
+        Ok(Command::ListDevices { json }) => return plugins::print_devices(&plugin_manager, json),
+        Err(_) => {}
+    }
 
-    let worker_count = plugin_manager.process_options(&matches)?;
     let mut opt: Opt = Opt::from_arg_matches(&matches)?;
     opt.process()?;
-    env_logger::builder().filter_level(opt.log_level()).parse_default_env().init();
+    let control = opt.control()?;
+    logging::init(opt.log_level());
+    logging::set_format(opt.log.log_format);
+    logging::set_capture(opt.log.tui);
+    hooks::init(opt.hook_cmd.clone(), opt.hook_url.clone())?;
     info!("=================================================================================");
     info!("                 Pyrin-Miner GPU {}", env!("CARGO_PKG_VERSION"));
     info!(" Mining for: {}", opt.mining_address);
     info!("=================================================================================");
-    info!("Found plugins: {:?}", plugins);
+    plugins::log_report(&plugin_report);
+    let worker_count = plugin_manager.process_options(&matches)?;
     info!("Plugins found {} workers", worker_count);
     if worker_count == 0 && opt.num_threads.unwrap_or(0) == 0 {
-        error!("No workers specified");
+        log_event!(Level::Error, "error", &[("error", "No workers specified".into())], "No workers specified");
         return Err("No workers specified".into());
     }
 
-    let block_template_ctr = Arc::new(AtomicU16::new((thread_rng().next_u64() % 10_000u64) as u16));
     let devfund_percent = 200;
 
     if devfund_percent > 0 {
@@ -156,12 +77,27 @@ async fn main() -> Result<(), Error> {
             opt.devfund_address
         );
     }
-    loop {
-        match client_main(&opt, block_template_ctr.clone(), &plugin_manager).await {
-            Ok(_) => info!("Client closed gracefully"),
-            Err(e) => error!("Client closed with error {:?}", e),
-        }
-        info!("Client closed, reconnecting");
-        sleep(Duration::from_millis(100));
+    if opt.log.tui {
+        tokio::spawn(tui::run());
     }
+    let mut builder = MinerBuilder::new(opt.pyrin_address.clone(), opt.mining_address.clone())
+        .cpu_threads(opt.num_threads.unwrap_or(0))
+        .plugins(plugin_manager)
+        .mine_when_not_synced(opt.mine_when_not_synced)
+        .hw_errors(opt.hw_error_config())
+        .thermal(opt.thermal_config()?)
+        .batch_controller(opt.batch_controller())
+        .devfund(opt.devfund_address.clone(), 2)
+        .schedule(opt.schedule());
+    for (device, intensity) in opt.intensities()? {
+        builder = builder.intensity(device, intensity);
+    }
+    let miner = builder.build()?;
+    if let Some((listen, token)) = control {
+        let listener = TcpListener::bind(&listen).await?;
+        info!("Serving control commands on {}", listen);
+        tokio::spawn(control::serve(listener, token, miner.controller()));
+    }
+    miner.run().await;
+    Ok(())
 }
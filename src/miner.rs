@@ -1,25 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::num::Wrapping;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::{pow, watch, Error};
-use log::{error, info, warn};
+use crate::abi::panic_message;
+use crate::control::ControlState;
+use crate::events::{self, MinerEvent};
+use crate::hw_errors::{HwErrorConfig, HwErrorTracker};
+use crate::pow::{self, BlockSeed};
+use crate::stats::{self, Averages, DeviceSnapshot};
+use crate::thermal::{ThermalConfig, ThermalGuard};
+use crate::tuning::{self, BatchController};
+use crate::{hooks, log_event, watch, Error, PluginManager, Worker, WorkerError, WorkerSpec};
+use log::{error, info, warn, Level};
 use rand::{thread_rng, RngCore};
+use time::{macros::format_description, OffsetDateTime};
 use tokio::sync::mpsc::Sender;
 use tokio::task::{self, JoinHandle};
 use tokio::time::MissedTickBehavior;
 
-use crate::pow::BlockSeed;
-use pyrin_miner::{PluginManager, WorkerSpec};
-
 type MinerHandler = std::thread::JoinHandle<Result<(), Error>>;
 
+// Panic payload of a thread stopped by the freeze handler, which is not a crash
+const FORCED_SHUTDOWN: &str = "Forced shutdown";
+
 #[cfg(any(target_os = "linux", target_os = "mac_os"))]
 extern "C" fn signal_panic(_signal: nix::libc::c_int) {
-    panic!("Forced shutdown");
+    std::panic::panic_any(FORCED_SHUTDOWN);
 }
 
 #[cfg(any(target_os = "linux", target_os = "mac_os"))]
@@ -85,15 +95,30 @@ fn register_freeze_handler() {
 #[derive(Clone)]
 enum WorkerCommand {
     Job(Box<pow::State>),
+    /// The controls changed. Carries the current job, since a worker only sees the last command sent
+    Control(Option<Box<pow::State>>),
     Close,
 }
 
+// The job a worker hashes after a command, `None` when it has to close
+fn next_job(command: Option<WorkerCommand>) -> Option<Option<Box<pow::State>>> {
+    match command {
+        Some(WorkerCommand::Job(s)) => Some(Some(s)),
+        Some(WorkerCommand::Control(s)) => Some(s),
+        Some(WorkerCommand::Close) => None,
+        None => Some(None),
+    }
+}
+
 #[allow(dead_code)]
 pub struct MinerManager {
     handles: Vec<MinerHandler>,
-    block_channel: watch::Sender<Option<WorkerCommand>>,
+    block_channel: Arc<watch::Sender<Option<WorkerCommand>>>,
+    // The job last sent to the workers, sent again with each control command
+    job: Arc<Mutex<Option<Box<pow::State>>>>,
     send_channel: Sender<BlockSeed>,
     logger_handle: JoinHandle<()>,
+    control_handle: JoinHandle<()>,
     is_synced: bool,
     hashes_tried: Arc<AtomicU64>,
     hashes_by_worker: Arc<Mutex<HashMap<String, Arc<AtomicU64>>>>,
@@ -104,6 +129,7 @@ impl Drop for MinerManager {
     fn drop(&mut self) {
         info!("Closing miner");
         self.logger_handle.abort();
+        self.control_handle.abort();
         match self.block_channel.send(Some(WorkerCommand::Close)) {
             Ok(_) => {}
             Err(_) => warn!("All workers are already dead"),
@@ -124,23 +150,132 @@ impl Drop for MinerManager {
     }
 }
 
+/**
+ How the worker threads run, shared by all of them and kept across connections
+*/
+#[derive(Clone)]
+pub struct WorkerSettings {
+    pub hw_errors: HwErrorConfig,
+    pub batch_controller: Option<BatchController>,
+    /// Read by the workers on each command, see `control::MinerController`
+    pub controls: tokio::sync::watch::Receiver<ControlState>,
+    pub thermal: ThermalConfig,
+}
+
+/**
+ Runs a worker thread, reporting a panic in it like an error it returned
+*/
+fn spawn_worker<F>(device: String, f: F) -> MinerHandler
+where
+    F: FnOnce() -> Result<(), Error> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+            if panic.downcast_ref::<&str>() == Some(&FORCED_SHUTDOWN) {
+                return Err(FORCED_SHUTDOWN.into());
+            }
+            let e = panic_message(panic);
+            report_crash(device, &e);
+            Err(e.into())
+        })
+    })
+}
+
+fn report_crash(device: String, error: &str) {
+    log_event!(
+        Level::Error,
+        "error",
+        &[("device", device.clone().into()), ("error", error.into())],
+        "{}: Worker thread crashed: {}",
+        device,
+        error
+    );
+    hooks::fire("worker_crashed", &[("device", device.clone()), ("error", error.to_string())]);
+    events::emit(MinerEvent::Error { device: Some(device), error: error.to_string() });
+}
+
 pub fn get_num_cpus(n_cpus: Option<u16>) -> u16 {
     n_cpus.unwrap_or_else(|| {
         num_cpus::get_physical().try_into().expect("Doesn't make sense to have more than 65,536 CPU cores")
     })
 }
 
+/**
+ Logs a share or block that was sent to the pool or node, and fires its hook
+*/
+fn report_block(block_seed: &BlockSeed) {
+    match block_seed {
+        BlockSeed::FullBlock(block) => {
+            let block_hash = block.block_hash().expect("We just got it from the state, we should be able to hash it");
+            let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+            let block_time = OffsetDateTime::from(
+                UNIX_EPOCH + Duration::from_millis(block.header.as_ref().unwrap().timestamp as u64),
+            );
+            log_event!(
+                Level::Info,
+                "block_found",
+                &[("hash", format!("{:x}", block_hash).into())],
+                "Found a block: {:x} (Timestamp: {})",
+                block_hash,
+                block_time.format(format).unwrap_or_else(|_| "unknown".to_string())
+            );
+            hooks::fire("block_found", &[("hash", format!("{:x}", block_hash))]);
+        }
+        BlockSeed::PartialBlock { id, nonce, hash, .. } => {
+            log_event!(
+                Level::Info,
+                "share_found",
+                &[("job_id", id.clone().into()), ("nonce", format!("{:016x}", nonce).into())],
+                "Found a share!"
+            );
+            hooks::fire(
+                "share_found",
+                &[
+                    ("job_id", id.clone()),
+                    ("nonce", format!("{:016x}", nonce)),
+                    ("hash", hash.clone().unwrap_or_default()),
+                ],
+            );
+        }
+    }
+}
+
 const LOG_RATE: Duration = Duration::from_secs(10);
+// Batches a worker may run on the self-test job before it is considered broken
+const SELF_TEST_BATCHES: usize = 16;
+// How long a device rests when its error rate is too high and its clocks cannot be lowered
+const HW_ERROR_PAUSE: Duration = Duration::from_secs(300);
+// Transient errors in a row before the device is treated as lost
+const TRANSIENT_ERROR_LIMIT: usize = 5;
+// Longest a batch may take on a fixed workload, a block interval. The batch controller keeps its own target
+const MAX_BATCH_MS: f32 = 1000.;
+// Longest sleep while a device rests between batches, so new jobs and controls are still picked up
+const IDLE_SLICE: Duration = Duration::from_millis(50);
+// Attempts to rebuild a lost device before it is disabled, and the wait before each
+const REBUILD_ATTEMPTS: usize = 3;
+const REBUILD_DELAY: Duration = Duration::from_secs(10);
 
 impl MinerManager {
-    pub fn new(send_channel: Sender<BlockSeed>, n_cpus: Option<u16>, manager: &PluginManager) -> Self {
+    pub fn new(
+        send_channel: Sender<BlockSeed>,
+        n_cpus: Option<u16>,
+        manager: &PluginManager,
+        settings: WorkerSettings,
+    ) -> Self {
         register_freeze_handler();
         let hashes_tried = Arc::new(AtomicU64::new(0));
         let hashes_by_worker = Arc::new(Mutex::new(HashMap::<String, Arc<AtomicU64>>::new()));
         let (send, recv) = watch::channel(None);
-        let mut handles =
-            Self::launch_cpu_threads(send_channel.clone(), Arc::clone(&hashes_tried), recv.clone(), n_cpus)
-                .collect::<Vec<MinerHandler>>();
+        let mut handles = Self::launch_cpu_threads(
+            send_channel.clone(),
+            Arc::clone(&hashes_tried),
+            recv.clone(),
+            n_cpus,
+            settings.controls.clone(),
+        )
+        .collect::<Vec<MinerHandler>>();
+        let mut controls = settings.controls.clone();
+        controls.borrow_and_update();
         if manager.has_specs() {
             handles.append(&mut Self::launch_gpu_threads(
                 send_channel.clone(),
@@ -148,13 +283,18 @@ impl MinerManager {
                 recv,
                 manager,
                 hashes_by_worker.clone(),
+                settings,
             ));
         }
+        let block_channel = Arc::new(send);
+        let job = Arc::new(Mutex::new(None));
         Self {
             handles,
-            block_channel: send,
+            block_channel: block_channel.clone(),
+            job: job.clone(),
             send_channel,
             logger_handle: task::spawn(Self::log_hashrate(Arc::clone(&hashes_tried), hashes_by_worker.clone())),
+            control_handle: task::spawn(Self::forward_controls(controls, block_channel, job)),
             is_synced: true,
             hashes_tried,
             current_state_id: AtomicUsize::new(0),
@@ -167,11 +307,19 @@ impl MinerManager {
         hashes_tried: Arc<AtomicU64>,
         work_channel: watch::Receiver<Option<WorkerCommand>>,
         n_cpus: Option<u16>,
+        controls: tokio::sync::watch::Receiver<ControlState>,
     ) -> impl Iterator<Item = MinerHandler> {
         let n_cpus = get_num_cpus(n_cpus);
         info!("launching: {} cpu miners", n_cpus);
-        (0..n_cpus)
-            .map(move |_| Self::launch_cpu_miner(send_channel.clone(), work_channel.clone(), Arc::clone(&hashes_tried)))
+        (0..n_cpus).map(move |index| {
+            Self::launch_cpu_miner(
+                send_channel.clone(),
+                work_channel.clone(),
+                Arc::clone(&hashes_tried),
+                controls.clone(),
+                index.into(),
+            )
+        })
     }
 
     fn launch_gpu_threads(
@@ -180,6 +328,7 @@ impl MinerManager {
         work_channel: watch::Receiver<Option<WorkerCommand>>,
         manager: &PluginManager,
         hashes_by_worker: Arc<Mutex<HashMap<String, Arc<AtomicU64>>>>,
+        settings: WorkerSettings,
     ) -> Vec<MinerHandler> {
         let mut vec = Vec::<MinerHandler>::new();
         let specs = manager.build().unwrap();
@@ -192,6 +341,7 @@ impl MinerManager {
                 Arc::clone(&hashes_tried),
                 spec,
                 worker_hashes_tried,
+                settings.clone(),
             ));
         }
         vec
@@ -201,23 +351,55 @@ impl MinerManager {
         let state = match block {
             Some(b) => {
                 self.is_synced = true;
+                stats::record_job(true);
                 let id = self.current_state_id.fetch_add(1, Ordering::SeqCst);
-                Some(WorkerCommand::Job(Box::new(pow::State::new(id, b)?)))
+                let job_id = match &b {
+                    BlockSeed::PartialBlock { id, .. } => Some(id.clone()),
+                    BlockSeed::FullBlock(_) => None,
+                };
+                log_event!(
+                    Level::Debug,
+                    "job",
+                    &[("job_id", job_id.clone().into()), ("state_id", id.into())],
+                    "New job {}",
+                    id
+                );
+                events::emit(MinerEvent::Job { job_id });
+                Some(Box::new(pow::State::new(id, b)?))
             }
             None => {
                 if !self.is_synced {
                     return Ok(());
                 }
                 self.is_synced = false;
+                stats::record_job(false);
                 warn!("Pyipad is not synced, skipping current template");
                 None
             }
         };
 
-        self.block_channel.send(state).map_err(|_e| "Failed sending block to threads")?;
+        let mut job = self.job.lock().unwrap();
+        *job = state.clone();
+        self.block_channel.send(state.map(WorkerCommand::Job)).map_err(|_e| "Failed sending block to threads")?;
         Ok(())
     }
 
+    /**
+    Sends the current job as a control command whenever the controls change, so every worker reads them
+    */
+    async fn forward_controls(
+        mut controls: tokio::sync::watch::Receiver<ControlState>,
+        block_channel: Arc<watch::Sender<Option<WorkerCommand>>>,
+        job: Arc<Mutex<Option<Box<pow::State>>>>,
+    ) {
+        while controls.changed().await.is_ok() {
+            let job = job.lock().unwrap();
+            if block_channel.send(Some(WorkerCommand::Control(job.clone()))).is_err() {
+                break;
+            }
+        }
+    }
+
     #[allow(unreachable_code)]
     fn launch_gpu_miner(
         send_channel: Sender<BlockSeed>,
@@ -225,62 +407,279 @@ impl MinerManager {
         hashes_tried: Arc<AtomicU64>,
         spec: Box<dyn WorkerSpec>,
         worker_hashes_tried: Arc<AtomicU64>,
+        settings: WorkerSettings,
     ) -> MinerHandler {
-        std::thread::spawn(move || {
-            let mut box_ = spec.build();
-            let gpu_work = box_.as_mut();
+        let WorkerSettings { hw_errors: hw_config, mut batch_controller, controls, thermal: thermal_config } = settings;
+        spawn_worker(spec.id(), move || {
+            let mut gpu_work = match spec.build() {
+                Ok(worker) => worker,
+                Err(e) => {
+                    log_event!(
+                        Level::Error,
+                        "error",
+                        &[("device", spec.id().into()), ("error", e.to_string().into())],
+                        "{}: Could not start the worker, disabling it: {}",
+                        spec.id(),
+                        e
+                    );
+                    events::emit(MinerEvent::Error { device: Some(spec.id()), error: e.to_string() });
+                    return Ok(());
+                }
+            };
+            match Self::self_test(gpu_work.as_mut()) {
+                Ok(()) => info!("{}: Self-test passed", gpu_work.id()),
+                Err(e) => {
+                    log_event!(
+                        Level::Error,
+                        "error",
+                        &[("device", gpu_work.id().into()), ("error", e.to_string().into())],
+                        "{}: Self-test failed, disabling worker: {}",
+                        gpu_work.id(),
+                        e
+                    );
+                    events::emit(MinerEvent::Error { device: Some(gpu_work.id()), error: e.to_string() });
+                    return Ok(());
+                }
+            }
+            if let Some(guard) = ThermalGuard::new(&gpu_work.id(), &thermal_config) {
+                if let Err(e) = guard.check(gpu_work.telemetry().as_ref()) {
+                    log_event!(
+                        Level::Error,
+                        "error",
+                        &[("device", gpu_work.id().into()), ("error", e.to_string().into())],
+                        "{}: {}, disabling worker",
+                        gpu_work.id(),
+                        e
+                    );
+                    events::emit(MinerEvent::Error { device: Some(gpu_work.id()), error: e.to_string() });
+                    return Ok(());
+                }
+            }
             (|| {
                 info!("Spawned Thread for GPU {}", gpu_work.id());
-                let mut nonces = vec![0u64; 1];
 
                 let mut state = None;
+                // Batches on the device with the state they hash, oldest first
+                let mut in_flight = VecDeque::<(Box<pow::State>, usize)>::new();
+                let depth = gpu_work.pipeline_depth().max(1);
+                // Id of the state whose constants are on the device
+                let mut loaded = None;
+                let mut hw_errors = HwErrorTracker::new(hw_config.threshold);
+                let mut last_check = Instant::now();
+                let mut paused_until: Option<Instant> = None;
+                // End of the rest after a batch when the intensity is below 100%, and when the last batch was launched
+                let mut idle_until: Option<Instant> = None;
+                let mut launched = Instant::now();
+                let mut transient_errors = 0;
+                let device = gpu_work.id();
+                let mut control = controls.borrow().device(&device);
+                let mut thermal = ThermalGuard::new(&device, &thermal_config);
+                // Sensors are read for the stats as often as the hashrate is logged, and whenever the guard asks
+                let mut telemetry_read: Option<Instant> = None;
+                // Workload last set from the controls, set again on a rebuilt worker
+                let mut applied_workload = None;
 
                 loop {
-                    nonces[0] = 0;
-                    if state.is_none() {
+                    let thermal_due = matches!(&thermal, Some(guard) if guard.due());
+                    if thermal_due || !matches!(telemetry_read, Some(read) if read.elapsed() < LOG_RATE) {
+                        telemetry_read = Some(Instant::now());
+                        let telemetry = gpu_work.telemetry();
+                        if let Some(telemetry) = &telemetry {
+                            stats::set_telemetry(&device, telemetry.clone());
+                        }
+                        if let Some(guard) = thermal.as_mut().filter(|guard| guard.due()) {
+                            guard.update(telemetry);
+                        }
+                    }
+                    let thermal_paused = matches!(&thermal, Some(guard) if guard.paused());
+                    let intensity = control.intensity.min(thermal.as_ref().map_or(100, ThermalGuard::intensity));
+                    if control.paused && in_flight.is_empty() {
+                        state = match next_job(block_channel.wait_for_change()?) {
+                            Some(job) => job,
+                            None => return Ok(()),
+                        };
+                        control = controls.borrow().device(&device);
+                        continue;
+                    }
+                    if let Some(workload) = control.workload.filter(|&workload| applied_workload != Some(workload)) {
+                        applied_workload = Some(workload);
+                        match gpu_work.set_workload(workload) {
+                            Ok(()) => batch_controller = None,
+                            Err(e) => warn!("{}: Could not set the workload to {}: {}", device, workload, e),
+                        }
+                    }
+                    if let (Some(until), true) = (paused_until, in_flight.is_empty()) {
+                        if Instant::now() < until {
+                            sleep(Duration::from_millis(100));
+                            if let Some(new_cmd) = block_channel.get_changed()? {
+                                state = match next_job(new_cmd) {
+                                    Some(job) => job,
+                                    None => return Ok(()),
+                                };
+                                control = controls.borrow().device(&device);
+                            }
+                            continue;
+                        }
+                        info!("{}: Resuming after hardware errors", gpu_work.id());
+                        paused_until = None;
+                        last_check = Instant::now();
+                    }
+                    // Sensors are read again on the next pass, until the device cools down
+                    if thermal_paused && in_flight.is_empty() {
+                        sleep(IDLE_SLICE);
+                        if let Some(new_cmd) = block_channel.get_changed()? {
+                            state = match next_job(new_cmd) {
+                                Some(job) => job,
+                                None => return Ok(()),
+                            };
+                            control = controls.borrow().device(&device);
+                        }
+                        continue;
+                    }
+                    if let (Some(until), true) = (idle_until, in_flight.is_empty() && intensity < 100) {
+                        let now = Instant::now();
+                        if now < until {
+                            sleep((until - now).min(IDLE_SLICE));
+                            if let Some(new_cmd) = block_channel.get_changed()? {
+                                state = match next_job(new_cmd) {
+                                    Some(job) => job,
+                                    None => return Ok(()),
+                                };
+                                control = controls.borrow().device(&device);
+                            }
+                            continue;
+                        }
+                        idle_until = None;
+                    }
+                    if state.is_none() && in_flight.is_empty() {
                         state = match block_channel.wait_for_change() {
-                            Ok(cmd) => match cmd {
-                                Some(WorkerCommand::Job(s)) => Some(s),
-                                Some(WorkerCommand::Close) => {return Ok(());}
-                                None => None,
+                            Ok(cmd) => match next_job(cmd) {
+                                Some(job) => job,
+                                None => return Ok(()),
                             },
                             Err(e) => {
-                                info!("{}: GPU thread crashed: {}", gpu_work.id(), e.to_string());
+                                info!("{}: GPU thread crashed: {}", gpu_work.id(), e);
                                 return Ok(());
                             }
                         };
+                        control = controls.borrow().device(&device);
+                        continue;
+                    }
+                    // Checked once the batches in flight are collected, so the test job does not mix with them
+                    if in_flight.is_empty() && last_check.elapsed() >= hw_config.check_interval {
+                        last_check = Instant::now();
+                        let valid = Self::self_test(gpu_work.as_mut()).is_ok();
+                        hw_errors.record(valid);
+                        if !valid {
+                            stats::record_error(&gpu_work.id());
+                        }
+                        loaded = None;
+                    }
+                    // New constants are queued behind the batches of the previous job
+                    let mut failure = None;
+                    if let (Some(s), None, false) = (&state, paused_until, control.paused || thermal_paused) {
+                        let check_due = last_check.elapsed() >= hw_config.check_interval;
+                        // A queued batch would start right after the current one, leaving no room to rest
+                        let depth = if intensity < 100 { 1 } else { depth };
+                        while in_flight.is_empty() || (in_flight.len() < depth && !check_due) {
+                            if loaded != Some(s.id) {
+                                if let Err(e) = s.load_to_gpu(gpu_work.as_mut()) {
+                                    failure = Some(e);
+                                    break;
+                                }
+                                loaded = Some(s.id);
+                            }
+                            if let Err(e) = s.pow_gpu(gpu_work.as_mut()) {
+                                failure = Some(e);
+                                break;
+                            }
+                            if in_flight.is_empty() {
+                                launched = Instant::now();
+                            }
+                            in_flight.push_back((s.clone(), gpu_work.get_workload()));
+                        }
+                    }
+                    if let Some(e) = failure {
+                        if Self::recover(spec.as_ref(), &mut gpu_work, e, &mut transient_errors)? {
+                            in_flight.clear();
+                            loaded = None;
+                            applied_workload = None;
+                        }
+                        continue;
                     }
-                    let state_ref = match &state {
-                        Some(s) => {
-                            s.load_to_gpu(gpu_work);
-                            s
-                        },
+                    let (state_ref, batch_workload) = match in_flight.pop_front() {
+                        Some(batch) => batch,
                         None => continue,
                     };
-                    state_ref.pow_gpu(gpu_work);
-                    if let Err(e) = gpu_work.sync() {
-                        warn!("CUDA run ignored: {}", e);
-                        continue
+                    let found = match gpu_work.collect_nonces() {
+                        Ok(found) => found,
+                        Err(e) => {
+                            if Self::recover(spec.as_ref(), &mut gpu_work, WorkerError::classify(e), &mut transient_errors)? {
+                                in_flight.clear();
+                                loaded = None;
+                                applied_workload = None;
+                            }
+                            continue;
+                        }
+                    };
+                    transient_errors = 0;
+                    match (&batch_controller, gpu_work.kernel_time_ms()) {
+                        (Some(controller), Some(kernel_ms)) => {
+                            if let Some(workload) = controller.adjust(batch_workload, kernel_ms) {
+                                if let Err(e) = gpu_work.set_workload(workload) {
+                                    warn!("{}: Keeping a fixed workload: {}", gpu_work.id(), e);
+                                    batch_controller = None;
+                                }
+                            }
+                        }
+                        (None, Some(kernel_ms)) if kernel_ms > MAX_BATCH_MS => {
+                            return Err(format!(
+                                "A batch took {:.0}ms, longer than a block. Please reduce the workload or set --batch-duration",
+                                kernel_ms
+                            )
+                            .into());
+                        }
+                        _ => {}
+                    }
+                    if intensity < 100 && in_flight.is_empty() {
+                        // Without a device timer the time since launch also counts the readback
+                        let busy_ms =
+                            gpu_work.kernel_time_ms().unwrap_or_else(|| launched.elapsed().as_secs_f32() * 1000.);
+                        idle_until = Some(Instant::now() + tuning::idle_gap(busy_ms, intensity));
                     }
 
-                    gpu_work.copy_output_to(&mut nonces)?;
-                    if nonces[0] != 0 {
-                        if let Some(block_seed) = state_ref.generate_block_if_pow(nonces[0]) {
+                    for nonce in found {
+                        if let Some(block_seed) = state_ref.generate_block_if_pow(nonce) {
+                            if let BlockSeed::PartialBlock { nonce, .. } = block_seed {
+                                stats::share_found(&gpu_work.id(), nonce);
+                            }
                             match send_channel.blocking_send(block_seed.clone()) {
-                                Ok(()) => block_seed.report_block(),
-                                Err(e) => error!("Failed submitting block: ({})", e.to_string()),
+                                Ok(()) => report_block(&block_seed),
+                                Err(e) => error!("Failed submitting block: ({})", e),
                             };
+                            hw_errors.record(true);
+                            // Other nonces solve the same template, as do the batches after it unless a new job arrived
                             if let BlockSeed::FullBlock(_) = block_seed {
-                                state = None;
+                                if state.as_ref().map(|s| s.id) == Some(state_ref.id) {
+                                    state = None;
+                                    for _ in in_flight.drain(..) {
+                                        if let Err(e) = gpu_work.collect_nonces() {
+                                            let error = WorkerError::classify(e);
+                                            if Self::recover(spec.as_ref(), &mut gpu_work, error, &mut transient_errors)? {
+                                                loaded = None;
+                                                applied_workload = None;
+                                                break;
+                                            }
+                                        }
+                                    }
+                                }
+                                break;
                             }
-                            nonces[0] = 0;
-                            hashes_tried.fetch_add(gpu_work.get_workload().try_into().unwrap(), Ordering::AcqRel);
-                            worker_hashes_tried.fetch_add(gpu_work.get_workload().try_into().unwrap(), Ordering::AcqRel);
-                            continue;
                         } else {
-                            let hash = state_ref.calculate_pow(nonces[0]);
-                            warn!("Something is wrong in GPU results! Got nonce {}, with hash real {:?}  (target: {}*2^196)", nonces[0], hash.0, state_ref.target.0[3]);
-                            break;
+                            let hash = state_ref.calculate_pow(nonce);
+                            warn!("Something is wrong in GPU results! Got nonce {}, with hash real {:?}  (target: {}*2^196)", nonce, hash.0, state_ref.target.0[3]);
+                            hw_errors.record(false);
+                            stats::record_error(&gpu_work.id());
                         }
                     }
 
@@ -314,56 +713,168 @@ impl MinerManager {
                             assert!(false);
                         }*/
 
-                    hashes_tried.fetch_add(gpu_work.get_workload().try_into().unwrap(), Ordering::AcqRel);
-                    worker_hashes_tried.fetch_add(gpu_work.get_workload().try_into().unwrap(), Ordering::AcqRel);
+                    hashes_tried.fetch_add(batch_workload.try_into().unwrap(), Ordering::AcqRel);
+                    worker_hashes_tried.fetch_add(batch_workload.try_into().unwrap(), Ordering::AcqRel);
+
+                    if hw_errors.exceeded() {
+                        let rate = hw_errors.rate() * 100.;
+                        if gpu_work.backoff() {
+                            log_event!(
+                                Level::Warn,
+                                "hw_errors",
+                                &[("device", gpu_work.id().into()), ("rate", rate.into()), ("action", "backoff".into())],
+                                "{}: {:.1}% hardware errors, lowered the clocks",
+                                gpu_work.id(),
+                                rate
+                            );
+                        } else {
+                            log_event!(
+                                Level::Warn,
+                                "hw_errors",
+                                &[("device", gpu_work.id().into()), ("rate", rate.into()), ("action", "pause".into())],
+                                "{}: {:.1}% hardware errors, pausing for {} seconds",
+                                gpu_work.id(),
+                                rate,
+                                HW_ERROR_PAUSE.as_secs()
+                            );
+                            paused_until = Some(Instant::now() + HW_ERROR_PAUSE);
+                        }
+                        hw_errors.reset();
+                    }
 
                     {
                         if let Some(new_cmd) = block_channel.get_changed()? {
-                            state = match new_cmd {
-                                Some(WorkerCommand::Job(s)) => Some(s),
-                                Some(WorkerCommand::Close) => {return Ok(());}
-                                None => None,
+                            state = match next_job(new_cmd) {
+                                Some(job) => job,
+                                None => return Ok(()),
                             };
+                            control = controls.borrow().device(&device);
                         }
                     }
                 }
                 Ok(())
             })()
             .map_err(|e: Error| {
-                error!("{}: GPU thread crashed: {}", gpu_work.id(), e.to_string());
+                report_crash(gpu_work.id(), &e.to_string());
                 e
             })
         })
     }
 
+    /**
+    Handles a failed batch. Transient errors are retried until too many come in a row, a lost device is rebuilt from
+    its spec and a fatal error ends the thread. Returns whether the worker was replaced
+    */
+    fn recover(
+        spec: &dyn WorkerSpec,
+        gpu_work: &mut Box<dyn Worker>,
+        error: WorkerError,
+        transient_errors: &mut usize,
+    ) -> Result<bool, Error> {
+        let error = match error {
+            WorkerError::Transient(e) if *transient_errors < TRANSIENT_ERROR_LIMIT => {
+                *transient_errors += 1;
+                warn!("{}: Batch failed, retrying: {}", gpu_work.id(), e);
+                return Ok(false);
+            }
+            WorkerError::Transient(e) => WorkerError::DeviceLost(e),
+            error => error,
+        };
+        if let WorkerError::Fatal(_) = error {
+            return Err(error.into());
+        }
+        *transient_errors = 0;
+
+        let id = gpu_work.id();
+        log_event!(
+            Level::Warn,
+            "device_lost",
+            &[("device", id.clone().into()), ("error", error.to_string().into())],
+            "{}: Rebuilding the worker after an error: {}",
+            id,
+            error
+        );
+        for attempt in 1..=REBUILD_ATTEMPTS {
+            sleep(REBUILD_DELAY);
+            match spec.build().and_then(|mut worker| Self::self_test(worker.as_mut()).map(|()| worker)) {
+                Ok(worker) => {
+                    *gpu_work = worker;
+                    info!("{}: Worker rebuilt", id);
+                    return Ok(true);
+                }
+                Err(e) => warn!("{}: Rebuild attempt {} of {} failed: {}", id, attempt, REBUILD_ATTEMPTS, e),
+            }
+        }
+        Err(format!("Could not rebuild the worker after {} attempts, disabling it: {}", REBUILD_ATTEMPTS, error).into())
+    }
+
+    /**
+    Runs a known job on the worker and checks the nonce it returns against the CPU implementation
+    */
+    fn self_test(gpu_work: &mut dyn Worker) -> Result<(), Error> {
+        let state = pow::State::known_answer()?;
+        let mut nonces = vec![0u64; 1];
+        for _ in 0..SELF_TEST_BATCHES {
+            nonces[0] = 0;
+            state.load_to_gpu(gpu_work)?;
+            state.pow_gpu(gpu_work)?;
+            gpu_work.finish_batch(&mut nonces)?;
+            if nonces[0] != 0 {
+                let hash = state.calculate_pow(nonces[0]);
+                if hash > state.target {
+                    return Err(format!(
+                        "Got nonce {}, but its hash is {:x}. The kernel does not match the CPU implementation",
+                        nonces[0], hash
+                    )
+                    .into());
+                }
+                return Ok(());
+            }
+        }
+        Err(format!("No nonce was found in {} batches, even though the target is very easy", SELF_TEST_BATCHES).into())
+    }
+
     #[allow(unreachable_code)]
     fn launch_cpu_miner(
         send_channel: Sender<BlockSeed>,
         mut block_channel: watch::Receiver<Option<WorkerCommand>>,
         hashes_tried: Arc<AtomicU64>,
+        controls: tokio::sync::watch::Receiver<ControlState>,
+        index: usize,
     ) -> MinerHandler {
         let mut nonce = Wrapping(thread_rng().next_u64());
         let mut mask = Wrapping(0);
         let mut fixed = Wrapping(0);
-        std::thread::spawn(move || {
+        spawn_worker("CPU".into(), move || {
             (|| {
                 let mut state = None;
+                let mut paused = controls.borrow().cpu_thread(index).paused;
 
                 loop {
+                    if paused {
+                        state = match next_job(block_channel.wait_for_change()?) {
+                            Some(job) => job,
+                            None => return Ok(()),
+                        };
+                        paused = controls.borrow().cpu_thread(index).paused;
+                        if let Some(s) = &state {
+                            mask = Wrapping(s.nonce_mask);
+                            fixed = Wrapping(s.nonce_fixed);
+                        }
+                        continue;
+                    }
                     if state.is_none() {
                         state = match block_channel.wait_for_change() {
-                            Ok(cmd) => match cmd {
-                                Some(WorkerCommand::Job(s)) => Some(s),
-                                Some(WorkerCommand::Close) => {
-                                    return Ok(());
-                                }
-                                None => None,
+                            Ok(cmd) => match next_job(cmd) {
+                                Some(job) => job,
+                                None => return Ok(()),
                             },
                             Err(e) => {
-                                info!("CPU thread crashed: {}", e.to_string());
+                                info!("CPU thread crashed: {}", e);
                                 return Ok(());
                             }
                         };
+                        paused = controls.borrow().cpu_thread(index).paused;
                         if let Some(s) = &state {
                             mask = Wrapping(s.nonce_mask);
                             fixed = Wrapping(s.nonce_fixed);
@@ -376,9 +887,12 @@ impl MinerManager {
                     nonce = (nonce & mask) | fixed;
 
                     if let Some(block_seed) = state_ref.generate_block_if_pow(nonce.0) {
+                        if let BlockSeed::PartialBlock { nonce: share_nonce, .. } = block_seed {
+                            stats::share_found("CPU", share_nonce);
+                        }
                         match send_channel.blocking_send(block_seed.clone()) {
-                            Ok(()) => block_seed.report_block(),
-                            Err(e) => error!("Failed submitting block: ({})", e.to_string()),
+                            Ok(()) => report_block(&block_seed),
+                            Err(e) => error!("Failed submitting block: ({})", e),
                         };
                         if let BlockSeed::FullBlock(_) = block_seed {
                             state = None;
@@ -390,20 +904,18 @@ impl MinerManager {
 
                     if nonce.0 % 128 == 0 {
                         if let Some(new_cmd) = block_channel.get_changed()? {
-                            state = match new_cmd {
-                                Some(WorkerCommand::Job(s)) => Some(s),
-                                Some(WorkerCommand::Close) => {
-                                    return Ok(());
-                                }
-                                None => None,
+                            state = match next_job(new_cmd) {
+                                Some(job) => job,
+                                None => return Ok(()),
                             };
+                            paused = controls.borrow().cpu_thread(index).paused;
                         }
                     }
                 }
                 Ok(())
             })()
             .map_err(|e: Error| {
-                error!("CPU thread crashed: {}", e.to_string());
+                report_crash("CPU".into(), &e.to_string());
                 e
             })
         })
@@ -416,42 +928,130 @@ impl MinerManager {
         loop {
             let now = ticker.tick().await;
             let duration = (now - last_instant).as_secs_f64();
+            let hashes = hashes_tried.swap(0, Ordering::AcqRel);
+            let devices = hashes_by_worker
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device, counter)| (device.clone(), counter.swap(0, Ordering::AcqRel)))
+                .collect::<Vec<_>>();
+            stats::record_interval(duration, hashes, &devices);
+            events::emit(MinerEvent::Hashrate {
+                total: hashes as f64 / duration,
+                devices: devices.iter().map(|(device, hashes)| (device.clone(), *hashes as f64 / duration)).collect(),
+            });
+            let snapshot = stats::snapshot();
+
+            // Logged before the current hashrate, integrations take the last line with the word hashrate
+            Self::log_average_hashrate(&snapshot);
             Self::log_single_hashrate(
-                &hashes_tried,
+                hashes,
+                &snapshot.total,
+                None,
                 "Current hashrate is".into(),
                 "Workers stalled or crashed. Considered reducing workload and check that your node is synced",
                 duration,
                 false,
             );
-            for (device, rate) in &*hashes_by_worker.lock().unwrap() {
-                Self::log_single_hashrate(rate, format!("Device {}:", device), "0 hash/s", duration, true);
+            for (device, hashes) in &devices {
+                let device_stats = snapshot
+                    .devices
+                    .iter()
+                    .find(|d| &d.id == device)
+                    .cloned()
+                    .unwrap_or_else(|| DeviceSnapshot { id: device.clone(), ..Default::default() });
+                Self::log_single_hashrate(
+                    *hashes,
+                    &device_stats.averages,
+                    Some(&device_stats),
+                    format!("Device {}:", device),
+                    "0 hash/s",
+                    duration,
+                    true,
+                );
             }
             last_instant = now;
         }
     }
 
+    fn log_average_hashrate(snapshot: &stats::Snapshot) {
+        let effective = match snapshot.effective {
+            Some(effective) => format!(", effective: {}", Self::format_averages(&effective)),
+            None => String::new(),
+        };
+        log_event!(
+            Level::Info,
+            "hashrate_average",
+            &[
+                ("avg_10s", snapshot.total[0].into()),
+                ("avg_60s", snapshot.total[1].into()),
+                ("avg_15m", snapshot.total[2].into()),
+                ("effective_10s", snapshot.effective.map(|e| e[0]).into()),
+                ("effective_60s", snapshot.effective.map(|e| e[1]).into()),
+                ("effective_15m", snapshot.effective.map(|e| e[2]).into()),
+                ("difficulty", snapshot.difficulty.into()),
+            ],
+            "Average hashrate 10s/60s/15m: {}{}",
+            Self::format_averages(&snapshot.total),
+            effective
+        );
+    }
+
     fn log_single_hashrate(
-        counter: &Arc<AtomicU64>,
+        hashes: u64,
+        averages: &Averages,
+        device: Option<&DeviceSnapshot>,
         prefix: String,
         warn_message: &str,
         duration: f64,
         keep_prefix: bool,
     ) {
-        let hashes = counter.swap(0, Ordering::AcqRel);
         let rate = (hashes as f64) / duration;
+        let telemetry = device.and_then(|d| d.telemetry.clone()).unwrap_or_default();
+        // The total has no device nor sensors, the same fields otherwise
+        let fields = [
+            ("device", device.map(|d| d.id.clone()).into()),
+            ("hashrate", rate.into()),
+            ("hashes", hashes.into()),
+            ("seconds", duration.into()),
+            ("avg_10s", averages[0].into()),
+            ("avg_60s", averages[1].into()),
+            ("avg_15m", averages[2].into()),
+            ("temperature", telemetry.temperature_c.into()),
+            ("power", telemetry.power_w.into()),
+            ("fan", telemetry.fan_percent.into()),
+            ("core_clock", telemetry.core_clock_mhz.into()),
+            ("memory_clock", telemetry.memory_clock_mhz.into()),
+            ("pci_bus_id", telemetry.pci_bus_id.clone().into()),
+        ];
+        // Readings follow the hashrate, e.g. `Device #0: 1.02 Ghash/s (64°C, 182W, bus 0000:01:00.0)`
+        let readings = match telemetry.to_string() {
+            readings if readings.is_empty() => readings,
+            readings => format!(" ({})", readings),
+        };
         if hashes == 0 {
             match keep_prefix {
-                true => warn!("{}{}", prefix, warn_message),
-                false => warn!("{}", warn_message),
+                true => log_event!(Level::Warn, "hashrate", &fields, "{}{}{}", prefix, warn_message, readings),
+                false => log_event!(Level::Warn, "hashrate", &fields, "{}", warn_message),
             };
         } else if hashes != 0 {
             let (rate, suffix) = Self::hash_suffix(rate);
-            info!("{} {:.2} {}", prefix, rate, suffix);
+            log_event!(Level::Info, "hashrate", &fields, "{} {:.2} {}{}", prefix, rate, suffix, readings);
         }
     }
 
+    // All averages in the unit of the 60s one, e.g. `1.02 / 0.99 / 0.97 Mhash/s`
+    pub fn format_averages(averages: &Averages) -> String {
+        let (scaled, suffix) = Self::hash_suffix(averages[1]);
+        let scale = match averages[1] {
+            n if n > 0. => scaled / n,
+            _ => 1.,
+        };
+        format!("{:.2} / {:.2} / {:.2} {}", averages[0] * scale, averages[1] * scale, averages[2] * scale, suffix)
+    }
+
     #[inline]
-    fn hash_suffix(n: f64) -> (f64, &'static str) {
+    pub fn hash_suffix(n: f64) -> (f64, &'static str) {
         match n {
             n if n < 1_000.0 => (n, "hash/s"),
             n if n < 1_000_000.0 => (n / 1_000.0, "Khash/s"),
@@ -0,0 +1,370 @@
+use crate::cli::PluginOpt;
+use crate::Error;
+use log::{debug, Level};
+use pyrin_miner::abi::PluginMetadata;
+use pyrin_miner::{log_event, DeviceInfo, PluginManager};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env::consts::DLL_EXTENSION;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+// Plugins loaded from a directory when no manifest is given
+const WHITELIST: [&str; 6] =
+    ["libpyrincuda", "libpyrinopencl", "libpyrinsoftware", "pyrincuda", "pyrinopencl", "pyrinsoftware"];
+// Directories and plugin libraries to load, separated like PATH
+pub const PLUGINS_ENV: &str = "PYRIN_MINER_PLUGINS";
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    plugins: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestEntry {
+    // File name of the library, as in libpyrincuda.so
+    file: String,
+    // Hex blake3 hash of the library, if it is pinned
+    blake3: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PluginStatus {
+    Loaded(PluginMetadata),
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginEntry {
+    pub path: String,
+    pub status: PluginStatus,
+}
+
+/**
+ The plugin libraries allowed to load, and the ones found but held back
+*/
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub paths: Vec<String>,
+    pub rejected: Vec<PluginEntry>,
+}
+
+/**
+ Reads the plugin options before the command line can be parsed, since the options of the plugins are part of it
+*/
+pub fn prescan(args: &[String]) -> PluginOpt {
+    let mut opts = PluginOpt::default();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        if !["--plugin-dir", "--plugin", "--plugin-manifest"].contains(&name) {
+            continue;
+        }
+        let value = match value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => break,
+        };
+        match name {
+            "--plugin-dir" => opts.plugin_dir.push(value),
+            "--plugin" => opts.plugin.push(value),
+            _ => opts.plugin_manifest = Some(value),
+        }
+    }
+    opts
+}
+
+/**
+ Finds the plugins next to the miner, in `PYRIN_MINER_PLUGINS` and in the options. Libraries named explicitly are
+ loaded as they are, libraries found in a directory only if they are built-in. A manifest replaces both rules with
+ its own list
+*/
+pub fn discover(opts: &PluginOpt, exe_dir: &Path) -> Result<Discovery, Error> {
+    let manifest = match &opts.plugin_manifest {
+        Some(path) => {
+            Some(read_manifest(path).map_err(|e| format!("Cannot read the plugin manifest {}: {}", path, e))?)
+        }
+        None => None,
+    };
+
+    let mut dirs = vec![exe_dir.to_path_buf()];
+    let mut files = Vec::new();
+    if let Some(value) = env::var_os(PLUGINS_ENV) {
+        for path in env::split_paths(&value).filter(|path| !path.as_os_str().is_empty()) {
+            match path.is_dir() {
+                true => dirs.push(path),
+                false => files.push(path),
+            }
+        }
+    }
+    dirs.extend(opts.plugin_dir.iter().map(PathBuf::from));
+    files.extend(opts.plugin.iter().map(PathBuf::from));
+
+    let mut discovery = Discovery::default();
+    let mut seen = HashSet::new();
+    let scanned = dirs.iter().flat_map(|dir| libraries(dir)).map(|path| (path, true));
+    let named = files.into_iter().map(|path| (path, false));
+    for (path, scanned) in scanned.chain(named) {
+        if !seen.insert(fs::canonicalize(&path).unwrap_or_else(|_| path.clone())) {
+            continue;
+        }
+        let display = path.to_string_lossy().into_owned();
+        match allow(&path, manifest.as_ref(), scanned) {
+            Ok(()) => discovery.paths.push(display),
+            Err(status) => discovery.rejected.push(PluginEntry { path: display, status }),
+        }
+    }
+    Ok(discovery)
+}
+
+fn read_manifest(path: &str) -> Result<Manifest, Error> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn libraries(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = match fs::read_dir(dir) {
+        Ok(readdir) => readdir
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|fname| {
+                fname.is_file()
+                    && fname.extension().and_then(OsStr::to_str).unwrap_or_default().starts_with(DLL_EXTENSION)
+            })
+            .collect::<Vec<PathBuf>>(),
+        _ => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+fn allow(path: &Path, manifest: Option<&Manifest>, scanned: bool) -> Result<(), PluginStatus> {
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None if !scanned => return Ok(()),
+        None => {
+            let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+            return match WHITELIST.contains(&stem) {
+                true => Ok(()),
+                false => {
+                    Err(PluginStatus::Skipped("not a built-in plugin, load it with --plugin or a manifest".into()))
+                }
+            };
+        }
+    };
+    let file = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    let entry = manifest
+        .plugins
+        .iter()
+        .find(|entry| entry.file == file)
+        .ok_or_else(|| PluginStatus::Skipped("not in the plugin manifest".into()))?;
+    if let Some(expected) = &entry.blake3 {
+        // Checked right before loading, the directories of the plugins should not be writable by others
+        let bytes = fs::read(path).map_err(|e| PluginStatus::Failed(format!("cannot read the library: {}", e)))?;
+        let actual = blake3::hash(&bytes).to_hex();
+        if !actual.as_str().eq_ignore_ascii_case(expected) {
+            return Err(PluginStatus::Failed(format!("blake3 hash {} does not match the manifest", actual)));
+        }
+    }
+    Ok(())
+}
+
+/**
+ Every plugin library found, with the plugin it loaded or why it did not
+*/
+pub fn report(discovery: &Discovery, manager: &PluginManager) -> Vec<PluginEntry> {
+    let loads = manager.loads().iter().map(|load| PluginEntry {
+        path: load.path.clone(),
+        status: match &load.result {
+            Ok(metadata) => PluginStatus::Loaded(metadata.clone()),
+            Err(e) => PluginStatus::Failed(e.clone()),
+        },
+    });
+    loads.chain(discovery.rejected.iter().cloned()).collect()
+}
+
+pub fn log_report(entries: &[PluginEntry]) {
+    for entry in entries {
+        match &entry.status {
+            PluginStatus::Loaded(metadata) => log_event!(
+                Level::Info,
+                "plugin",
+                &[
+                    ("path", entry.path.clone().into()),
+                    ("plugin", metadata.name.clone().into()),
+                    ("status", "loaded".into())
+                ],
+                "Loaded plugin {} {} ({}) from {}",
+                metadata.name,
+                metadata.version,
+                metadata.backends.join(", "),
+                entry.path
+            ),
+            PluginStatus::Failed(e) => log_event!(
+                Level::Warn,
+                "plugin",
+                &[("path", entry.path.clone().into()), ("status", "failed".into()), ("error", e.clone().into())],
+                "Failed loading plugin {} (ignore if you do not intend to use): {}",
+                entry.path,
+                e
+            ),
+            PluginStatus::Skipped(reason) => debug!("Skipped plugin {}: {}", entry.path, reason),
+        }
+    }
+}
+
+/**
+ Output of `plugins list`
+*/
+pub fn print_report(entries: &[PluginEntry]) {
+    if entries.is_empty() {
+        println!("No plugins found");
+    }
+    for entry in entries {
+        match &entry.status {
+            PluginStatus::Loaded(metadata) => println!(
+                "loaded   {}\n         {} {}, backends: {}",
+                entry.path,
+                metadata.name,
+                metadata.version,
+                metadata.backends.join(", ")
+            ),
+            PluginStatus::Failed(e) => println!("failed   {}\n         {}", entry.path, e),
+            PluginStatus::Skipped(reason) => println!("skipped  {}\n         {}", entry.path, reason),
+        }
+    }
+}
+
+/**
+ Output of `list-devices`, one line per device under its backend and platform, or a JSON list with `--json`.
+ Plugins that cannot list their devices say why on stderr
+*/
+pub fn print_devices(plugin_manager: &PluginManager, json: bool) -> Result<(), Error> {
+    let mut devices = Vec::new();
+    for (plugin, result) in plugin_manager.list_devices() {
+        match result {
+            Ok(found) => devices.extend(found),
+            Err(e) => eprintln!("{} cannot list its devices: {}", plugin, e),
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+    if devices.is_empty() {
+        println!("No devices found");
+    }
+    let mut group = None;
+    for device in &devices {
+        if group != Some((&device.backend, device.platform)) {
+            group = Some((&device.backend, device.platform));
+            match (device.platform, &device.platform_name) {
+                (Some(platform), Some(name)) => println!("{} platform #{}: {}", device.backend, platform, name),
+                (Some(platform), None) => println!("{} platform #{}", device.backend, platform),
+                (None, _) => println!("{}", device.backend),
+            }
+        }
+        println!("  #{} {}\n     {}", device.index, device.name, describe_device(device));
+    }
+    Ok(())
+}
+
+// What is known of a device, e.g. `compute 8.6, 10.0 GiB, bus 0000:01:00.0, kernel PTX sm_86, workload 69632`
+fn describe_device(device: &DeviceInfo) -> String {
+    let details = [
+        // Compute capabilities are bare numbers, gfx targets name themselves
+        device.arch.as_ref().map(|arch| match arch.starts_with(|c: char| c.is_ascii_digit()) {
+            true => format!("compute {}", arch),
+            false => arch.clone(),
+        }),
+        device.memory_bytes.map(|bytes| format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)),
+        device.identity.pci_bus_id.as_ref().map(|pci_bus_id| format!("bus {}", pci_bus_id)),
+        device.identity.uuid.clone(),
+        Some(format!("kernel {}", device.kernel)),
+        Some(match device.default_workload {
+            Some(workload) => format!("workload {}", workload),
+            None => "cannot mine".into(),
+        }),
+    ];
+    details.into_iter().flatten().collect::<Vec<String>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allow, describe_device, prescan, Manifest, ManifestEntry, PluginStatus};
+    use crate::cli::PluginOpt;
+    use pyrin_miner::{DeviceIdentity, DeviceInfo};
+    use std::path::Path;
+
+    #[test]
+    fn test_prescan() {
+        let args = [
+            "pyrin-miner",
+            "-a",
+            "pyrin:x",
+            "--plugin-dir",
+            "/opt/a",
+            "--plugin=/opt/b.so",
+            "--plugin-manifest",
+            "m.json",
+        ];
+        let opts = prescan(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>());
+        assert_eq!(
+            opts,
+            PluginOpt {
+                plugin_dir: vec!["/opt/a".into()],
+                plugin: vec!["/opt/b.so".into()],
+                plugin_manifest: Some("m.json".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_allow() {
+        assert_eq!(allow(Path::new("/opt/libpyrincuda.so"), None, true), Ok(()));
+        assert!(matches!(allow(Path::new("/opt/libother.so"), None, true), Err(PluginStatus::Skipped(_))));
+        assert_eq!(allow(Path::new("/opt/libother.so"), None, false), Ok(()));
+
+        let path = std::env::temp_dir().join(format!("pyrin-miner-plugin-{}.so", std::process::id()));
+        std::fs::write(&path, b"not really a library").unwrap();
+        let file = path.file_name().unwrap().to_string_lossy().into_owned();
+        let pinned = |blake3: &str| Manifest {
+            plugins: vec![ManifestEntry { file: file.clone(), blake3: Some(blake3.into()) }],
+        };
+        let hash = blake3::hash(b"not really a library").to_hex().to_string();
+        assert_eq!(allow(&path, Some(&pinned(&hash.to_uppercase())), true), Ok(()));
+        assert!(matches!(allow(&path, Some(&pinned(&"0".repeat(64))), false), Err(PluginStatus::Failed(_))));
+        assert!(matches!(
+            allow(Path::new("/opt/libpyrincuda.so"), Some(&pinned(&hash)), true),
+            Err(PluginStatus::Skipped(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_describe_device() {
+        let device = DeviceInfo {
+            backend: "cuda".into(),
+            name: "NVIDIA GeForce RTX 3080".into(),
+            arch: Some("8.6".into()),
+            memory_bytes: Some(10 << 30),
+            identity: DeviceIdentity { pci_bus_id: Some("0000:01:00.0".into()), uuid: None },
+            precompiled: true,
+            kernel: "PTX sm_86".into(),
+            default_workload: Some(69632),
+            ..DeviceInfo::default()
+        };
+        assert_eq!(
+            describe_device(&device),
+            "compute 8.6, 10.0 GiB, bus 0000:01:00.0, kernel PTX sm_86, workload 69632"
+        );
+        let device =
+            DeviceInfo { backend: "opencl".into(), kernel: "compiled from source".into(), ..DeviceInfo::default() };
+        assert_eq!(describe_device(&device), "kernel compiled from source, cannot mine");
+    }
+}
@@ -1,25 +1,20 @@
-use log::info;
 use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
-use time::{macros::format_description, OffsetDateTime};
 
-pub use crate::pow::hasher::HeaderHasher;
+pub use crate::pow::hasher::{Hasher, HeaderHasher, HeavyHasher, PowHasher};
+pub use crate::pow::heavy_hash::Matrix;
 use crate::{
-    pow::{
-        hasher::{Hasher, PowHasher},
-        heavy_hash::Matrix,
-    },
     proto::{RpcBlock, RpcBlockHeader},
     target::{self, Uint256},
-    Error, Hash,
+    Error, Hash, Worker, WorkerError,
 };
-use pyrin_miner::Worker;
 
 mod hasher;
 mod heavy_hash;
-mod keccak;
 mod xoshiro;
 
+/**
+ The work a job came with: a full block template from the node, or the header hash and target of a pool job
+*/
 #[derive(Clone, Debug)]
 pub enum BlockSeed {
     FullBlock(Box<RpcBlock>),
@@ -35,27 +30,10 @@ pub enum BlockSeed {
     },
 }
 
-impl BlockSeed {
-    pub fn report_block(&self) {
-        match self {
-            BlockSeed::FullBlock(block) => {
-                let block_hash =
-                    block.block_hash().expect("We just got it from the state, we should be able to hash it");
-                let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
-                let block_time = OffsetDateTime::from(
-                    UNIX_EPOCH + Duration::from_millis(block.header.as_ref().unwrap().timestamp as u64),
-                );
-                info!(
-                    "Found a block: {:x} (Timestamp: {})",
-                    block_hash,
-                    block_time.format(format).unwrap_or_else(|_| "unknown".to_string())
-                );
-            }
-            BlockSeed::PartialBlock { .. } => info!("Found a share!"),
-        }
-    }
-}
-
+/**
+ A job ready to hash: the matrix and the pre-PoW hash of its header, the target and the nonce bits the pool leaves
+ to the miner. `calculate_pow` is the reference the workers are checked against
+*/
 #[derive(Clone)]
 pub struct State {
     pub id: usize,
@@ -128,6 +106,27 @@ impl State {
         })
     }
 
+    /**
+    A fixed job with a known matrix and a very easy target, used to check workers against the CPU before mining
+    */
+    pub fn known_answer() -> Result<Self, Error> {
+        Self::new(
+            0,
+            BlockSeed::PartialBlock {
+                id: "self-test".into(),
+                // Generates the matrix in `heavy_hash::tests::test_generate_matrix`
+                header_hash: Hash::from_le_bytes([42; 32]).0,
+                timestamp: 5435345234,
+                nonce: 0,
+                // About one in 256 hashes is below it
+                target: Uint256::new([u64::MAX, u64::MAX, u64::MAX, 1 << 56]),
+                nonce_mask: 0xffffffffffffffffu64,
+                nonce_fixed: 0,
+                hash: None,
+            },
+        )
+    }
+
     #[inline(always)]
     // PRE_POW_HASH || TIME || 32 zero byte padding || NONCE
     pub fn calculate_pow(&self, nonce: u64) -> Uint256 {
@@ -140,8 +139,7 @@ impl State {
     pub fn check_pow(&self, nonce: u64) -> bool {
         let pow = self.calculate_pow(nonce);
         // The pow hash must be less or equal than the claimed target.
-        // pow <= self.target
-        true
+        pow <= self.target
     }
 
     #[inline(always)]
@@ -162,19 +160,23 @@ impl State {
         })
     }
 
-    pub fn load_to_gpu(&self, gpu_work: &mut dyn Worker) {
-        gpu_work.load_block_constants(&self.pow_hash_header, &self.matrix.0, &self.target.0);
+    pub fn load_to_gpu(&self, gpu_work: &mut dyn Worker) -> Result<(), WorkerError> {
+        gpu_work.load_block_constants(&self.pow_hash_header, &self.matrix.0, &self.target.0)
     }
 
     #[inline(always)]
-    pub fn pow_gpu(&self, gpu_work: &mut dyn Worker) {
-        gpu_work.calculate_hash(None, self.nonce_mask, self.nonce_fixed);
+    pub fn pow_gpu(&self, gpu_work: &mut dyn Worker) -> Result<(), WorkerError> {
+        gpu_work.calculate_hash(None, self.nonce_mask, self.nonce_fixed)
     }
 }
 
 #[cfg(not(any(target_pointer_width = "64", target_pointer_width = "32")))]
 compile_error!("Supporting only 32/64 bits");
 
+/**
+ Writes a header as the node hashes it. With `for_pre_pow` the nonce and timestamp are zeroed, which gives the
+ pre-PoW hash. Panics if a hash in the header is not hex of the right length
+*/
 #[inline(always)]
 pub fn serialize_header<H: Hasher>(hasher: &mut H, header: &RpcBlockHeader, for_pre_pow: bool) {
     let (nonce, timestamp) = if for_pre_pow { (0, 0) } else { (header.nonce, header.timestamp) };
@@ -261,7 +263,7 @@ fn decode_to_slice<T: AsRef<[u8]>>(data: T, out: &mut [u8]) -> Result<(), FromHe
 #[cfg(test)]
 mod tests {
     use crate::pow::hasher::{Hasher, HeaderHasher};
-    use crate::pow::serialize_header;
+    use crate::pow::{serialize_header, State};
     use crate::proto::{RpcBlockHeader, RpcBlockLevelParents};
     use crate::Hash;
 
@@ -465,12 +467,32 @@ mod tests {
         serialize_header(&mut buf, &header, true);
         assert_eq!(&expected_res[..], &buf.0);
 
+        // blake3 keyed with "BlockHash", computed over `expected_res` with the blake3 C reference implementation
         let expected_hash = Hash::from_le_bytes([
-            85, 146, 211, 217, 138, 239, 47, 85, 152, 59, 58, 16, 4, 149, 129, 179, 172, 226, 174, 233, 160, 96, 202,
-            54, 6, 225, 64, 142, 106, 0, 110, 137,
+            251, 208, 79, 39, 227, 249, 25, 78, 223, 5, 180, 124, 206, 108, 69, 99, 95, 143, 212, 120, 52, 232, 153,
+            209, 182, 60, 12, 12, 145, 186, 204, 125,
         ]);
         let mut hasher = HeaderHasher::new();
         hasher.write(buf.0);
         assert_eq!(hasher.finalize(), expected_hash);
     }
+
+    #[test]
+    fn test_known_answer() {
+        let state = State::known_answer().unwrap();
+        // The CUDA and OpenCL kernels, compiled for the host, give the same values for this state
+        let vectors = [
+            (0, [1007155183841976128, 5256638194944557447, 7369366540844306870, 13328134673378236208]),
+            (1, [9036919612005407282, 6949132997012597096, 3352440167545351728, 5280396108658759098]),
+            (2, [1669500877518031636, 12466798206347167494, 15737483273499570247, 17556750335546319306]),
+            (0xdeadbeef, [2838110571495150779, 13480890032063163555, 6568103241986891239, 3469677798008123733]),
+            (u64::MAX, [5911084376775294992, 8162097548314218629, 13460968963068619660, 15291634120865756375]),
+        ];
+        for (nonce, expected_pow) in vectors {
+            assert_eq!(state.calculate_pow(nonce), Hash::new(expected_pow), "nonce {}", nonce);
+        }
+
+        let nonce = (1..10_000).find(|&nonce| state.check_pow(nonce)).expect("The target is very easy");
+        assert!(state.calculate_pow(nonce) <= state.target);
+    }
 }
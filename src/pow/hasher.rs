@@ -1,78 +1,56 @@
 use crate::Hash;
 use blake3::Hasher as Blake3Hasher;
-use num::traits::real::Real;
 
 const BLOCK_HASH_DOMAIN: &[u8] = b"BlockHash";
 
+/**
+ Hashes a nonce together with the pre-PoW hash and timestamp of a header, the first step of the PoW
+*/
 #[derive(Clone, Copy)]
-pub(super) struct PowHasher([u64; 25]);
+pub struct PowHasher([u8; 80]);
 
+/**
+ Hashes the output of the matrix multiplication, the last step of the PoW
+*/
 #[derive(Clone, Copy)]
-pub(super) struct HeavyHasher;
+pub struct HeavyHasher;
 
+/**
+ Keyed blake3 over a serialized header, gives the block hash and the pre-PoW hash
+*/
 #[derive(Clone)]
 pub struct HeaderHasher(Blake3Hasher);
 
 impl PowHasher {
-    // The initial state of `cSHAKE256("ProofOfWorkHash")`
-    // [10] -> 1123092876221303310 ^ 0x04(padding byte) = 1123092876221303306
-    // [16] -> 10306167911662716186 ^ 0x8000000000000000(final padding) = 1082795874807940378
-    #[rustfmt::skip]
-    const INITIAL_STATE: [u64; 25] = [
-        1242148031264380989, 3008272977830772284, 2188519011337848018, 1992179434288343456, 8876506674959887717,
-        5399642050693751366, 1745875063082670864, 8605242046444978844, 17936695144567157056, 3343109343542796272,
-        1123092876221303306, 4963925045340115282, 17037383077651887893, 16629644495023626889, 12833675776649114147,
-        3784524041015224902, 1082795874807940378, 13952716920571277634, 13411128033953605860, 15060696040649351053,
-        9928834659948351306, 5237849264682708699, 12825353012139217522, 6706187291358897596, 196324915476054915,
-    ];
     #[inline(always)]
-    pub(super) fn new(pre_pow_hash: Hash, timestamp: u64) -> Self {
-        let mut start = Self::INITIAL_STATE;
-        for (&pre_pow_word, state_word) in pre_pow_hash.0.iter().zip(start.iter_mut()) {
-            *state_word ^= pre_pow_word;
-        }
-        start[4] ^= timestamp;
-        Self(start)
+    pub fn new(pre_pow_hash: Hash, timestamp: u64) -> Self {
+        // PRE_POW_HASH || TIME || 32 zero byte padding || NONCE
+        let mut input = [0u8; 80];
+        input[..32].copy_from_slice(&pre_pow_hash.to_le_bytes());
+        input[32..40].copy_from_slice(&timestamp.to_le_bytes());
+        Self(input)
     }
 
     #[inline(always)]
-    pub(super) fn finalize_with_nonce(mut self, nonce: u64) -> Hash {
-        self.0[9] ^= nonce;
-        super::keccak::f1600(&mut self.0);
-        Hash::new(self.0[..4].try_into().unwrap())
+    pub fn finalize_with_nonce(mut self, nonce: u64) -> Hash {
+        self.0[72..].copy_from_slice(&nonce.to_le_bytes());
+        Hash::from_le_bytes(*blake3::hash(&self.0).as_bytes())
     }
 }
 
 impl HeavyHasher {
-    // The initial state of `cSHAKE256("ProofOfWorkHash")`
-    // [4] -> 16654558671554924254 ^ 0x04(padding byte) = 16654558671554924250
-    // [16] -> 9793466274154320918 ^ 0x8000000000000000(final padding) = 570094237299545110
-    #[rustfmt::skip]
-    const INITIAL_STATE: [u64; 25] = [
-        4239941492252378377, 8746723911537738262, 8796936657246353646, 1272090201925444760, 16654558671554924250,
-        8270816933120786537, 13907396207649043898, 6782861118970774626, 9239690602118867528, 11582319943599406348,
-        17596056728278508070, 15212962468105129023, 7812475424661425213, 3370482334374859748, 5690099369266491460,
-        8596393687355028144, 570094237299545110, 9119540418498120711, 16901969272480492857, 13372017233735502424,
-        14372891883993151831, 5171152063242093102, 10573107899694386186, 6096431547456407061, 1592359455985097269,
-    ];
     #[inline(always)]
-    pub(super) fn hash(in_hash: Hash) -> Hash {
-        let mut state = Self::INITIAL_STATE;
-        for (&pre_pow_word, state_word) in in_hash.0.iter().zip(state.iter_mut()) {
-            *state_word ^= pre_pow_word;
-        }
-        super::keccak::f1600(&mut state);
-        Hash::new(state[..4].try_into().unwrap())
+    pub fn hash(in_hash: Hash) -> Hash {
+        Hash::from_le_bytes(*blake3::hash(&in_hash.to_le_bytes()).as_bytes())
     }
 }
 
 impl HeaderHasher {
     #[inline(always)]
     pub fn new() -> Self {
-        let mut key = [42u8; 32];
-        key = [66, 108, 111, 99, 107, 72, 97, 115, 104, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let mut hasher = Blake3Hasher::new_keyed(&key);
-        Self(hasher)
+        let mut key = [0u8; 32];
+        key[..BLOCK_HASH_DOMAIN.len()].copy_from_slice(BLOCK_HASH_DOMAIN);
+        Self(Blake3Hasher::new_keyed(&key))
     }
 
     pub fn write<A: AsRef<[u8]>>(&mut self, data: A) {
@@ -81,10 +59,19 @@ impl HeaderHasher {
 
     #[inline(always)]
     pub fn finalize(self) -> Hash {
-        Hash::from_le_bytes(self.0.finalize().as_bytes().clone().try_into().expect("this is 32 bytes"))
+        Hash::from_le_bytes(*self.0.finalize().as_bytes())
     }
 }
 
+impl Default for HeaderHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ Where `serialize_header` writes a header, e.g. a `HeaderHasher`
+*/
 pub trait Hasher {
     fn update<A: AsRef<[u8]>>(&mut self, data: A) -> &mut Self;
 }
@@ -98,14 +85,8 @@ impl Hasher for HeaderHasher {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
     use crate::pow::hasher::{HeavyHasher, PowHasher};
     use crate::Hash;
-    use sha3::digest::{ExtendableOutput, Update, XofReader};
-    use sha3::CShake256;
-
-    const PROOF_OF_WORK_DOMAIN: &[u8] = b"ProofOfWorkHash";
-    const HEAVY_HASH_DOMAIN: &[u8] = b"HeavyHash";
 
     #[test]
     fn test_pow_hash() {
@@ -116,14 +97,10 @@ mod tests {
         let hash1 = hasher.finalize_with_nonce(nonce);
 
         let mut hasher = blake3::Hasher::new();
-        let a1 = unsafe { std::mem::transmute(pre_pow_hash.to_le_bytes()) };
-        let a2 = unsafe { std::mem::transmute(timestamp.to_le_bytes()) };
-        let a3 = unsafe { std::mem::transmute([0u8; 32]) };
-        let a4 = unsafe { std::mem::transmute(nonce.to_le_bytes()) };
-        hasher.write(a1);
-        hasher.write(a2);
-        hasher.write(a3);
-        hasher.write(a4);
+        hasher.update(&pre_pow_hash.to_le_bytes());
+        hasher.update(&timestamp.to_le_bytes());
+        hasher.update(&[0u8; 32]);
+        hasher.update(&nonce.to_le_bytes());
 
         let mut hash2 = [0u8; 32];
         hasher.finalize_xof().fill(&mut hash2);
@@ -136,8 +113,7 @@ mod tests {
         let hash1 = HeavyHasher::hash(val);
 
         let mut hasher = blake3::Hasher::new();
-        let bytes = unsafe { std::mem::transmute(val.to_le_bytes()) };
-        hasher.write(bytes);
+        hasher.update(&val.to_le_bytes());
 
         let mut hash2 = [0u8; 32];
         hasher.finalize_xof().fill(&mut hash2);
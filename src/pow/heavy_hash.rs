@@ -2,6 +2,9 @@ use crate::pow::{hasher::HeavyHasher, xoshiro::XoShiRo256PlusPlus};
 use crate::Hash;
 use std::mem::MaybeUninit;
 
+/**
+ The 64x64 matrix of 4 bit values generated from the pre-PoW hash of a job, see `Matrix::generate`
+*/
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Matrix(pub [[u16; 64]; 64]);
 
@@ -24,6 +27,9 @@ impl Matrix {
     //     }
     // }
 
+    /**
+    Generates the matrix of a job from its pre-PoW hash, drawing again until it has full rank
+    */
     #[inline(always)]
     pub fn generate(hash: Hash) -> Self {
         let mut generator = XoShiRo256PlusPlus::new(hash);
@@ -98,6 +104,9 @@ impl Matrix {
         rank
     }
 
+    /**
+    Multiplies the hash from `PowHasher` by the matrix, xors the product back into it and hashes it with `HeavyHasher`
+    */
     pub fn heavy_hash(&self, hash: Hash) -> Hash {
         let hash = hash.to_le_bytes();
         // SAFETY: An uninitialized MaybrUninit is always safe.
@@ -164,8 +173,8 @@ mod tests {
     #[test]
     fn test_heavy_hash() {
         let expected_hash = Hash::from_le_bytes([
-            135, 104, 159, 55, 153, 67, 234, 249, 183, 71, 92, 169, 83, 37, 104, 119, 114, 191, 204, 104, 252, 120,
-            153, 202, 235, 68, 9, 236, 69, 144, 195, 37,
+            202, 67, 159, 207, 142, 13, 53, 241, 108, 225, 122, 207, 252, 164, 189, 217, 201, 29, 140, 43, 98, 218,
+            147, 74, 230, 22, 232, 237, 79, 199, 88, 24,
         ]);
         #[rustfmt::skip]
         let test_matrix = Matrix([
@@ -0,0 +1,298 @@
+use crate::control::MinerController;
+use crate::Error;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+// How often the windows and the system load are checked, the load average itself moves over about a minute
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/**
+ When a device may mine: `[DEVICE=][DAYS ]HH:MM-HH:MM` in local time, e.g. `mon-fri 18:00-08:00` or
+ `CPU=sat,sun 00:00-24:00`. Days default to every day. A window ending before it starts runs over midnight and
+ belongs to the day it starts on
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MiningWindow {
+    /// The device it applies to, every device when `None`
+    pub device: Option<String>,
+    // Bit per day, 0 is Sunday
+    days: u8,
+    start: u32,
+    end: u32,
+}
+
+impl MiningWindow {
+    /**
+    Whether the window is open on `weekday` (0 is Sunday) at `minute` past midnight
+    */
+    pub fn contains(&self, weekday: u32, minute: u32) -> bool {
+        let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        match self.start < self.end {
+            true => on(weekday) && (self.start..self.end).contains(&minute),
+            false => (on(weekday) && minute >= self.start) || (on(weekday + 6) && minute < self.end),
+        }
+    }
+}
+
+impl FromStr for MiningWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (device, window) = match s.rsplit_once('=') {
+            Some((device, window)) => (Some(device.trim().to_string()), window.trim()),
+            None => (None, s.trim()),
+        };
+        let (days, hours) = match window.rsplit_once(' ') {
+            Some((days, hours)) => (parse_days(days.trim())?, hours),
+            None => (0x7f, window),
+        };
+        let (start, end) = hours.split_once('-').ok_or_else(|| format!("Expected HH:MM-HH:MM, got {}", hours))?;
+        let (start, end) = (parse_minute(start)?, parse_minute(end)?);
+        if start == end || start == MINUTES_PER_DAY {
+            return Err(format!("The mining window {} is empty", s).into());
+        }
+        Ok(Self { device, days, start, end: end % MINUTES_PER_DAY })
+    }
+}
+
+fn parse_days(days: &str) -> Result<u8, Error> {
+    let day = |name: &str| {
+        DAY_NAMES
+            .iter()
+            .position(|day| name.trim().to_ascii_lowercase().starts_with(day))
+            .ok_or_else(|| format!("Unknown day {}", name))
+    };
+    let mut mask = 0u8;
+    for range in days.split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(range)?, day(range)?),
+        };
+        // Ranges may wrap around the week, e.g. fri-mon
+        let mut current = first;
+        loop {
+            mask |= 1 << current;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_minute(time: &str) -> Result<u32, Error> {
+    let (hour, minute) = time.trim().split_once(':').ok_or_else(|| format!("Expected HH:MM, got {}", time))?;
+    let (hour, minute): (u32, u32) = (hour.parse()?, minute.parse()?);
+    match hour * 60 + minute {
+        total if minute < 60 && total <= MINUTES_PER_DAY => Ok(total),
+        _ => Err(format!("{} is not a time of the day", time).into()),
+    }
+}
+
+/**
+ When the miner runs: the windows each device may mine in, and the system load above which CPU threads stop
+*/
+#[derive(Clone, Debug, Default)]
+pub struct ScheduleConfig {
+    pub windows: Vec<MiningWindow>,
+    /// 1 minute load average above which a CPU thread is dropped, they come back while there is room for one more
+    pub max_load: Option<f32>,
+}
+
+impl ScheduleConfig {
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty() && self.max_load.is_none()
+    }
+}
+
+/**
+ Whether every device is outside its windows, and which single devices are
+*/
+fn closed_devices(windows: &[MiningWindow], weekday: u32, minute: u32) -> (bool, HashSet<String>) {
+    let closed = |device: &Option<String>| {
+        let mut windows = windows.iter().filter(|window| &window.device == device).peekable();
+        windows.peek().is_some() && !windows.any(|window| window.contains(weekday, minute))
+    };
+    let devices = windows.iter().filter_map(|window| window.device.clone()).collect::<HashSet<String>>();
+    (closed(&None), devices.into_iter().filter(|device| closed(&Some(device.clone()))).collect())
+}
+
+/**
+ The CPU threads to mine with after a load check, moving by one thread at a time
+*/
+fn next_cpu_threads(current: usize, threads: usize, load: f32, max_load: f32) -> usize {
+    if load > max_load {
+        current.saturating_sub(1)
+    } else if load + 1. <= max_load {
+        (current + 1).min(threads)
+    } else {
+        current
+    }
+}
+
+/**
+ The CPU threads to mine with after reading the system load with `read`. A failed reading stops the limit and gives
+ back every thread
+*/
+fn limit_cpu_threads<R>(max_load: &mut Option<f32>, allowed: usize, threads: usize, read: R) -> usize
+where
+    R: FnOnce() -> Result<f32, Error>,
+{
+    let limit = match *max_load {
+        Some(limit) => limit,
+        None => return allowed,
+    };
+    match read() {
+        Ok(load) => {
+            let next = next_cpu_threads(allowed, threads, load, limit);
+            if next != allowed {
+                info!("System load is {:.2}, mining with {} of {} CPU threads", load, next, threads);
+            }
+            next
+        }
+        Err(e) => {
+            warn!("Cannot read the system load, not limiting CPU threads: {}", e);
+            *max_load = None;
+            threads
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_load() -> Result<f32, Error> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg")?;
+    Ok(loadavg.split_whitespace().next().ok_or("/proc/loadavg is empty")?.parse()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_load() -> Result<f32, Error> {
+    Err("The system load is only read on Linux".into())
+}
+
+// The weekday (0 is Sunday) and the minute of the day in local time
+#[cfg(unix)]
+fn local_time() -> (u32, u32) {
+    use nix::libc;
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn local_time() -> (u32, u32) {
+    let mut now = unsafe { std::mem::zeroed::<winapi::SYSTEMTIME>() };
+    unsafe { kernel32::GetLocalTime(&mut now) };
+    (now.wDayOfWeek as u32, now.wHour as u32 * 60 + now.wMinute as u32)
+}
+
+/**
+ Pauses the devices outside their windows and drops CPU threads while the system is busy, until the future is dropped
+*/
+pub async fn run(config: ScheduleConfig, cpu_threads: usize, controller: MinerController) {
+    if config.is_empty() {
+        return;
+    }
+    let mut max_load = config.max_load;
+    if max_load.is_some() && cpu_threads == 0 {
+        warn!("The load limit only applies to CPU threads, and none are mining");
+        max_load = None;
+    }
+    let mut allowed = cpu_threads;
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let (weekday, minute) = local_time();
+        let (all, devices) = closed_devices(&config.windows, weekday, minute);
+        controller.set_scheduled(all, devices);
+
+        let next = limit_cpu_threads(&mut max_load, allowed, cpu_threads, read_load);
+        if next != allowed {
+            allowed = next;
+            controller.set_cpu_threads(Some(allowed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closed_devices, limit_cpu_threads, next_cpu_threads, MiningWindow};
+
+    #[test]
+    fn test_parse() {
+        let window: MiningWindow = "mon-fri 18:00-08:00".parse().unwrap();
+        assert_eq!(window, MiningWindow { device: None, days: 0b0111110, start: 18 * 60, end: 8 * 60 });
+        let window: MiningWindow = "CPU=sat,sun 00:00-24:00".parse().unwrap();
+        assert_eq!(window, MiningWindow { device: Some("CPU".into()), days: 0b1000001, start: 0, end: 0 });
+        let window: MiningWindow = "#0 (software)=22:30-06:00".parse().unwrap();
+        assert_eq!(window.device.as_deref(), Some("#0 (software)"));
+        assert_eq!(window.days, 0x7f);
+        assert_eq!("fri-mon 10:00-11:00".parse::<MiningWindow>().unwrap().days, 0b1100011);
+
+        assert!("mon-fri".parse::<MiningWindow>().is_err());
+        assert!("someday 10:00-11:00".parse::<MiningWindow>().is_err());
+        assert!("10:00-10:00".parse::<MiningWindow>().is_err());
+        assert!("10:00-25:00".parse::<MiningWindow>().is_err());
+        assert!("10:60-11:00".parse::<MiningWindow>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let overnight: MiningWindow = "mon-fri 18:00-08:00".parse().unwrap();
+        assert!(overnight.contains(1, 18 * 60));
+        assert!(overnight.contains(2, 7 * 60 + 59));
+        assert!(!overnight.contains(2, 8 * 60));
+        assert!(!overnight.contains(1, 12 * 60));
+        // Friday night runs into Saturday morning, Sunday night is not mined
+        assert!(overnight.contains(6, 60));
+        assert!(!overnight.contains(0, 20 * 60));
+        assert!(!overnight.contains(1, 60));
+
+        let weekend: MiningWindow = "sat,sun 00:00-24:00".parse().unwrap();
+        assert!(weekend.contains(0, 0));
+        assert!(weekend.contains(6, 23 * 60 + 59));
+        assert!(!weekend.contains(1, 0));
+    }
+
+    #[test]
+    fn test_closed_devices() {
+        let windows = ["mon-fri 18:00-08:00", "sat,sun 00:00-24:00", "CPU=mon-fri 20:00-06:00"]
+            .iter()
+            .map(|window| window.parse().unwrap())
+            .collect::<Vec<MiningWindow>>();
+        assert_eq!(closed_devices(&windows, 1, 19 * 60), (false, ["CPU".to_string()].into()));
+        assert_eq!(closed_devices(&windows, 1, 21 * 60), (false, [].into()));
+        assert_eq!(closed_devices(&windows, 1, 12 * 60), (true, ["CPU".to_string()].into()));
+        assert_eq!(closed_devices(&windows[2..], 1, 12 * 60), (false, ["CPU".to_string()].into()));
+        assert_eq!(closed_devices(&[], 1, 12 * 60), (false, [].into()));
+    }
+
+    #[test]
+    fn test_next_cpu_threads() {
+        assert_eq!(next_cpu_threads(4, 4, 6.5, 6.), 3);
+        assert_eq!(next_cpu_threads(0, 4, 6.5, 6.), 0);
+        assert_eq!(next_cpu_threads(3, 4, 5.5, 6.), 3);
+        assert_eq!(next_cpu_threads(3, 4, 4.5, 6.), 4);
+        assert_eq!(next_cpu_threads(4, 4, 1., 6.), 4);
+    }
+
+    #[test]
+    fn test_limit_cpu_threads() {
+        let mut max_load = Some(6.);
+        assert_eq!(limit_cpu_threads(&mut max_load, 4, 4, || Ok(6.5)), 3);
+        assert_eq!(limit_cpu_threads(&mut max_load, 3, 4, || Ok(5.5)), 3);
+        // A failed reading gives back the dropped threads and stops reading
+        assert_eq!(limit_cpu_threads(&mut max_load, 3, 4, || Err("/proc/loadavg is empty".into())), 4);
+        assert_eq!(max_load, None);
+        assert_eq!(limit_cpu_threads(&mut max_load, 4, 4, || panic!("Read the load without a limit")), 4);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::Telemetry;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Windows of the rolling averages, shortest first
+pub const WINDOWS: [Duration; 3] = [Duration::from_secs(10), Duration::from_secs(60), Duration::from_secs(15 * 60)];
+
+// Hashes needed on average for a share of difficulty 1, 2^256 / (0xffff * 2^208)
+const HASHES_PER_DIFFICULTY: f64 = (1u64 << 48) as f64 / 0xffff as f64;
+
+// Shares the pool never answered are dropped past this many
+const MAX_PENDING_SHARES: usize = 1024;
+
+static STATS: Lazy<Mutex<Stats>> = Lazy::new(|| Mutex::new(Stats::default()));
+
+/// Average rate over each of `WINDOWS`, in hash/s
+pub type Averages = [f64; 3];
+
+/**
+ Amounts counted over consecutive intervals, long enough to cover the longest window
+*/
+#[derive(Debug, Default)]
+pub struct RollingRate {
+    // (seconds, amount) of every interval, oldest first
+    samples: VecDeque<(f64, f64)>,
+    seconds: f64,
+}
+
+impl RollingRate {
+    pub fn push(&mut self, seconds: f64, amount: f64) {
+        self.samples.push_back((seconds, amount));
+        self.seconds += seconds;
+        let longest = WINDOWS[WINDOWS.len() - 1].as_secs_f64();
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if self.seconds - oldest < longest {
+                break;
+            }
+            self.samples.pop_front();
+            self.seconds -= oldest;
+        }
+    }
+
+    /**
+    Rate over the latest intervals whose middle falls in the window. Shorter while less time was recorded
+    */
+    pub fn rate(&self, window: Duration) -> f64 {
+        let (mut seconds, mut amount) = (0., 0.);
+        for &(interval, value) in self.samples.iter().rev() {
+            if seconds > 0. && seconds + interval / 2. > window.as_secs_f64() {
+                break;
+            }
+            seconds += interval;
+            amount += value;
+        }
+        match seconds {
+            s if s > 0. => amount / s,
+            _ => 0.,
+        }
+    }
+
+    pub fn averages(&self) -> Averages {
+        WINDOWS.map(|window| self.rate(window))
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceStats {
+    rate: RollingRate,
+    accepted: u64,
+    rejected: u64,
+    errors: u64,
+    telemetry: Option<Telemetry>,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    total: RollingRate,
+    devices: BTreeMap<String, DeviceStats>,
+    effective: RollingRate,
+    // Work of the shares accepted since the last interval
+    accepted_work: f64,
+    difficulty: Option<f64>,
+    // Device of every share found, by nonce until it is submitted, then by stratum id
+    found: HashMap<u64, String>,
+    submitted: HashMap<u32, String>,
+    address: Option<String>,
+    connected: bool,
+    synced: bool,
+    last_job: Option<Instant>,
+    mining_devfund: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSnapshot {
+    pub id: String,
+    pub averages: Averages,
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Results that did not match the CPU implementation
+    pub errors: u64,
+    /// Latest sensor readings, None if the device has none
+    pub telemetry: Option<Telemetry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub total: Averages,
+    pub devices: Vec<DeviceSnapshot>,
+    /// Hashrate the pool credited through accepted shares, None when not mining to a pool
+    pub effective: Option<Averages>,
+    pub difficulty: Option<f64>,
+    pub address: Option<String>,
+    pub connected: bool,
+    pub synced: bool,
+    pub job_age: Option<Duration>,
+    pub mining_devfund: Option<bool>,
+}
+
+/**
+ Adds the hashes tried over the last `seconds`, in total and by device
+*/
+pub fn record_interval(seconds: f64, total: u64, devices: &[(String, u64)]) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    stats.total.push(seconds, total as f64);
+    for (device, hashes) in devices {
+        stats.devices.entry(device.clone()).or_default().rate.push(seconds, *hashes as f64);
+    }
+    let accepted_work = std::mem::take(&mut stats.accepted_work);
+    if stats.difficulty.is_some() {
+        stats.effective.push(seconds, accepted_work);
+    }
+}
+
+pub fn set_difficulty(difficulty: f64) {
+    STATS.lock().unwrap_or_else(|e| e.into_inner()).difficulty = Some(difficulty);
+}
+
+/**
+ Remembers which device found the share with this nonce, call before sending it to the pool
+*/
+pub fn share_found(device: &str, nonce: u64) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    if stats.found.len() >= MAX_PENDING_SHARES {
+        stats.found.clear();
+    }
+    stats.found.insert(nonce, device.to_string());
+}
+
+pub fn share_submitted(stratum_id: u32, nonce: u64) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    if stats.submitted.len() >= MAX_PENDING_SHARES {
+        stats.submitted.clear();
+    }
+    if let Some(device) = stats.found.remove(&nonce) {
+        stats.submitted.insert(stratum_id, device);
+    }
+}
+
+/**
+ Counts the answer of the pool for the device of the share. Accepted shares are credited with the work
+ expected at the current pool difficulty
+*/
+pub fn share_result(stratum_id: u32, accepted: bool) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    if accepted {
+        if let Some(difficulty) = stats.difficulty {
+            stats.accepted_work += difficulty * HASHES_PER_DIFFICULTY;
+        }
+    }
+    if let Some(device) = stats.submitted.remove(&stratum_id) {
+        let device = stats.devices.entry(device).or_default();
+        match accepted {
+            true => device.accepted += 1,
+            false => device.rejected += 1,
+        }
+    }
+}
+
+pub fn record_error(device: &str) {
+    STATS.lock().unwrap_or_else(|e| e.into_inner()).devices.entry(device.to_string()).or_default().errors += 1;
+}
+
+pub fn set_telemetry(device: &str, telemetry: Telemetry) {
+    STATS.lock().unwrap_or_else(|e| e.into_inner()).devices.entry(device.to_string()).or_default().telemetry =
+        Some(telemetry);
+}
+
+pub fn set_connected(address: &str, connected: bool) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    stats.address = Some(address.to_string());
+    stats.connected = connected;
+}
+
+/**
+ A new job arrived, or the node is not synced when `job` is false
+*/
+pub fn record_job(job: bool) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    stats.synced = job;
+    if job {
+        stats.last_job = Some(Instant::now());
+    }
+}
+
+pub fn set_mining_devfund(mining_devfund: bool) {
+    STATS.lock().unwrap_or_else(|e| e.into_inner()).mining_devfund = Some(mining_devfund);
+}
+
+pub fn snapshot() -> Snapshot {
+    let stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    Snapshot {
+        total: stats.total.averages(),
+        devices: stats
+            .devices
+            .iter()
+            .map(|(id, device)| DeviceSnapshot {
+                id: id.clone(),
+                averages: device.rate.averages(),
+                accepted: device.accepted,
+                rejected: device.rejected,
+                errors: device.errors,
+                telemetry: device.telemetry.clone(),
+            })
+            .collect(),
+        effective: stats.difficulty.map(|_| stats.effective.averages()),
+        difficulty: stats.difficulty,
+        address: stats.address.clone(),
+        connected: stats.connected,
+        synced: stats.synced,
+        job_age: stats.last_job.map(|job| job.elapsed()),
+        mining_devfund: stats.mining_devfund,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RollingRate, HASHES_PER_DIFFICULTY, WINDOWS};
+    use std::time::Duration;
+
+    #[test]
+    fn test_windows() {
+        let mut rate = RollingRate::default();
+        assert_eq!(rate.averages(), [0., 0., 0.]);
+        rate.push(10., 1000.);
+        assert_eq!(rate.averages(), [100., 100., 100.]);
+        (0..5).for_each(|_| rate.push(10.01, 0.));
+        rate.push(9.99, 2000.);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(rate.rate(WINDOWS[0]), 2000. / 9.99));
+        assert!(close(rate.rate(WINDOWS[1]), 2000. / 60.04));
+        assert!(close(rate.rate(WINDOWS[2]), 3000. / 70.04));
+    }
+
+    #[test]
+    fn test_old_samples_expire() {
+        let mut rate = RollingRate::default();
+        rate.push(10., 1e6);
+        (0..WINDOWS[2].as_secs() / 10).for_each(|_| rate.push(10., 100.));
+        assert_eq!(rate.rate(Duration::from_secs(3600)), 10.);
+    }
+
+    #[test]
+    fn test_hashes_per_difficulty() {
+        assert_eq!(HASHES_PER_DIFFICULTY, 2f64.powi(256) / (0xffff as f64 * 2f64.powi(208)));
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{log_event, Error, Telemetry};
+use log::{warn, Level};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Intensity taken off a device on every check it spends over a limit, and the lowest it is throttled to
+const THROTTLE_STEP: u8 = 10;
+const MIN_THROTTLE: u8 = 10;
+// Share of its power limit a device has to stay under before a throttle step is undone
+const POWER_HYSTERESIS: f32 = 0.9;
+
+/**
+ What a device does when it runs too hot. Power over its limit always throttles, a paused device would draw
+ idle power right away and resume on the next check
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThermalAction {
+    /// Lowers the intensity of the device a step on each check over the limit, and raises it again once cooled
+    Throttle,
+    /// Pauses the device until it cools below the hysteresis point
+    Pause,
+}
+
+impl FromStr for ThermalAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "throttle" => Ok(ThermalAction::Throttle),
+            "pause" => Ok(ThermalAction::Pause),
+            _ => Err(format!("Unknown thermal action {}, expected throttle or pause", s).into()),
+        }
+    }
+}
+
+/**
+ Limits of a device, `None` for the ones it does not have
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThermalLimits {
+    pub max_temp: Option<f32>,
+    pub max_power: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThermalConfig {
+    /// Limits of every device, a device of `devices` keeps the ones it sets itself
+    pub limits: ThermalLimits,
+    pub devices: HashMap<String, ThermalLimits>,
+    pub action: ThermalAction,
+    /// Degrees under the temperature limit a device has to reach before it is resumed or throttled less
+    pub hysteresis: f32,
+    pub check_interval: Duration,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            limits: ThermalLimits::default(),
+            devices: HashMap::new(),
+            action: ThermalAction::Throttle,
+            hysteresis: 5.,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ThermalConfig {
+    pub fn limits(&self, device: &str) -> ThermalLimits {
+        let own = self.devices.get(device).copied().unwrap_or_default();
+        ThermalLimits {
+            max_temp: own.max_temp.or(self.limits.max_temp),
+            max_power: own.max_power.or(self.limits.max_power),
+        }
+    }
+}
+
+/**
+ Keeps one device within its limits from the readings of its sensors
+*/
+pub struct ThermalGuard {
+    device: String,
+    limits: ThermalLimits,
+    action: ThermalAction,
+    hysteresis: f32,
+    check_interval: Duration,
+    last_check: Instant,
+    paused: bool,
+    intensity: u8,
+    // Warned once that the sensors cannot be read
+    unreadable: bool,
+}
+
+impl ThermalGuard {
+    /**
+    A guard for a device, None if no limit applies to it
+    */
+    pub fn new(device: &str, config: &ThermalConfig) -> Option<Self> {
+        let limits = config.limits(device);
+        if limits.max_temp.is_none() && limits.max_power.is_none() {
+            return None;
+        }
+        Some(Self {
+            device: device.to_string(),
+            limits,
+            action: config.action,
+            hysteresis: config.hysteresis,
+            check_interval: config.check_interval,
+            last_check: Instant::now(),
+            paused: false,
+            intensity: 100,
+            unreadable: false,
+        })
+    }
+
+    pub fn due(&self) -> bool {
+        self.last_check.elapsed() >= self.check_interval
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /**
+    Percent of the time the device may be busy, 100 unless it is throttled
+    */
+    pub fn intensity(&self) -> u8 {
+        self.intensity
+    }
+
+    /**
+    Fails if the readings lack a sensor one of the limits needs, so the device is never mined unprotected
+    */
+    pub fn check(&self, telemetry: Option<&Telemetry>) -> Result<(), Error> {
+        let telemetry = telemetry.cloned().unwrap_or_default();
+        let mut missing = Vec::new();
+        if self.limits.max_temp.is_some() && telemetry.temperature_c.is_none() {
+            missing.push("temperature");
+        }
+        if self.limits.max_power.is_some() && telemetry.power_w.is_none() {
+            missing.push("power draw");
+        }
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Cannot read the {} its limits need", missing.join(" and ")).into()),
+        }
+    }
+
+    pub fn update(&mut self, telemetry: Option<Telemetry>) {
+        self.last_check = Instant::now();
+        let telemetry = telemetry.unwrap_or_default();
+        let over = |value: Option<f32>, limit: Option<f32>| matches!((value, limit), (Some(v), Some(l)) if v > l);
+        let under = |value: Option<f32>, limit: Option<f32>| match (value, limit) {
+            (Some(v), Some(l)) => v <= l,
+            _ => true,
+        };
+        let (temperature, power) = (telemetry.temperature_c, telemetry.power_w);
+        let missing = (self.limits.max_temp.is_some() && temperature.is_none())
+            || (self.limits.max_power.is_some() && power.is_none());
+        if missing && !self.unreadable {
+            warn!("{}: Lost the sensors its limits need, it is not protected", self.device);
+            self.unreadable = true;
+        }
+        let hot = over(temperature, self.limits.max_temp);
+        let cool = under(temperature, self.limits.max_temp.map(|max| max - self.hysteresis));
+        let power_over = over(power, self.limits.max_power);
+        let power_low = under(power, self.limits.max_power.map(|max| max * POWER_HYSTERESIS));
+
+        if self.paused {
+            if cool {
+                self.paused = false;
+                self.log(Level::Info, "resume", temperature, power, "Cooled down, resuming");
+            }
+            return;
+        }
+        if hot && self.action == ThermalAction::Pause {
+            self.paused = true;
+            self.log(Level::Warn, "pause", temperature, power, "Over its limit, pausing until it cools down");
+        } else if (hot || power_over) && self.intensity > MIN_THROTTLE {
+            self.intensity = self.intensity.saturating_sub(THROTTLE_STEP).max(MIN_THROTTLE);
+            let message = format!("Over its limit, throttling to {}%", self.intensity);
+            self.log(Level::Warn, "throttle", temperature, power, &message);
+        } else if cool && power_low && self.intensity < 100 {
+            self.intensity = (self.intensity + THROTTLE_STEP).min(100);
+            let message = format!("Within its limits, raising the intensity to {}%", self.intensity);
+            self.log(Level::Info, "restore", temperature, power, &message);
+        }
+    }
+
+    fn log(&self, level: Level, action: &'static str, temperature: Option<f32>, power: Option<f32>, message: &str) {
+        log_event!(
+            level,
+            "thermal",
+            &[
+                ("device", self.device.clone().into()),
+                ("action", action.into()),
+                ("temperature", temperature.into()),
+                ("power", power.into()),
+                ("intensity", self.intensity.into()),
+            ],
+            "{}: {} ({}, {})",
+            self.device,
+            message,
+            match temperature {
+                Some(temperature) => format!("{:.0}°C", temperature),
+                None => "unknown temperature".into(),
+            },
+            match power {
+                Some(power) => format!("{:.0}W", power),
+                None => "unknown power".into(),
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ThermalAction, ThermalConfig, ThermalGuard, ThermalLimits};
+    use crate::Telemetry;
+
+    fn reading(temperature: f32, power: f32) -> Option<Telemetry> {
+        Some(Telemetry { temperature_c: Some(temperature), power_w: Some(power), ..Telemetry::default() })
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut config = ThermalConfig::default();
+        assert!(ThermalGuard::new("#0", &config).is_none());
+        config.limits.max_temp = Some(80.);
+        config.devices.insert("#1".into(), ThermalLimits { max_temp: Some(70.), max_power: Some(200.) });
+        assert_eq!(config.limits("#0"), ThermalLimits { max_temp: Some(80.), max_power: None });
+        assert_eq!(config.limits("#1"), ThermalLimits { max_temp: Some(70.), max_power: Some(200.) });
+    }
+
+    #[test]
+    fn test_throttle() {
+        let config = ThermalConfig {
+            limits: ThermalLimits { max_temp: Some(80.), max_power: Some(200.) },
+            ..ThermalConfig::default()
+        };
+        let mut guard = ThermalGuard::new("#0", &config).unwrap();
+        guard.update(reading(85., 150.));
+        guard.update(reading(82., 150.));
+        assert_eq!(guard.intensity(), 80);
+        // Between the limit and the hysteresis point the intensity stays
+        guard.update(reading(78., 150.));
+        assert_eq!(guard.intensity(), 80);
+        guard.update(reading(70., 150.));
+        assert_eq!(guard.intensity(), 90);
+        guard.update(reading(70., 210.));
+        assert_eq!(guard.intensity(), 80);
+        guard.update(reading(70., 190.));
+        assert_eq!(guard.intensity(), 80);
+        (0..20).for_each(|_| guard.update(reading(90., 150.)));
+        assert_eq!(guard.intensity(), 10);
+        assert!(!guard.paused());
+    }
+
+    #[test]
+    fn test_pause() {
+        let config = ThermalConfig {
+            limits: ThermalLimits { max_temp: Some(80.), max_power: None },
+            action: ThermalAction::Pause,
+            ..ThermalConfig::default()
+        };
+        let mut guard = ThermalGuard::new("#0", &config).unwrap();
+        guard.update(reading(81., 300.));
+        assert!(guard.paused());
+        guard.update(reading(76., 100.));
+        assert!(guard.paused());
+        guard.update(reading(75., 100.));
+        assert!(!guard.paused());
+        assert_eq!(guard.intensity(), 100);
+        // Without readings the device is left alone
+        guard.update(None);
+        assert!(!guard.paused());
+    }
+
+    #[test]
+    fn test_check() {
+        let config = ThermalConfig {
+            limits: ThermalLimits { max_temp: Some(80.), max_power: Some(200.) },
+            ..ThermalConfig::default()
+        };
+        let guard = ThermalGuard::new("#0", &config).unwrap();
+        assert!(guard.check(reading(70., 150.).as_ref()).is_ok());
+        assert!(guard.check(None).is_err());
+        let temperature_only = Telemetry { temperature_c: Some(70.), ..Telemetry::default() };
+        assert_eq!(
+            guard.check(Some(&temperature_only)).unwrap_err().to_string(),
+            "Cannot read the power draw its limits need"
+        );
+    }
+}
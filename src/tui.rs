@@ -0,0 +1,165 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use pyrin_miner::logging;
+use pyrin_miner::miner::MinerManager;
+use pyrin_miner::stats::{self, Averages, Snapshot};
+
+const REFRESH: Duration = Duration::from_secs(1);
+// Captured log lines shown under the devices
+const LOG_LINES: usize = 10;
+
+/**
+ Redraws the dashboard every second until Ctrl+C, on the alternate screen of the terminal
+*/
+pub async fn run() {
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(REFRESH);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut stdout = std::io::stdout();
+    // Alternate screen, hidden cursor
+    let _ = write!(stdout, "\x1b[?1049h\x1b[?25l");
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let frame = render(&stats::snapshot(), started.elapsed(), &logging::recent(LOG_LINES));
+                let _ = write!(stdout, "\x1b[H\x1b[2J{}", frame);
+                let _ = stdout.flush();
+            }
+            _ = &mut ctrl_c => {
+                let _ = write!(stdout, "\x1b[?25h\x1b[?1049l");
+                let _ = stdout.flush();
+                std::process::exit(0);
+            }
+        }
+    }
+}
+
+fn render(snapshot: &Snapshot, uptime: Duration, logs: &[String]) -> String {
+    let mut frame = String::new();
+    let secs = uptime.as_secs();
+    let _ = writeln!(
+        frame,
+        "Pyrin-Miner {}    Uptime {}h {:02}m {:02}s",
+        env!("CARGO_PKG_VERSION"),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    let status = match (snapshot.connected, snapshot.synced) {
+        (false, _) => "disconnected",
+        (true, false) => "connected, not synced",
+        (true, true) => "connected",
+    };
+    let _ = writeln!(frame, "Node/pool: {} ({})", snapshot.address.as_deref().unwrap_or("-"), status);
+    let _ = writeln!(
+        frame,
+        "Difficulty: {}    Job age: {}    Devfund: {}",
+        snapshot.difficulty.map(|d| d.to_string()).unwrap_or_else(|| "-".into()),
+        snapshot.job_age.map(|age| format!("{}s", age.as_secs())).unwrap_or_else(|| "-".into()),
+        match snapshot.mining_devfund {
+            Some(true) => "mining to devfund",
+            Some(false) => "mining to your address",
+            None => "-",
+        }
+    );
+    let _ = writeln!(
+        frame,
+        "Effective hashrate 10s/60s/15m: {}",
+        snapshot.effective.map(|e| MinerManager::format_averages(&e)).unwrap_or_else(|| "-".into())
+    );
+    let _ = writeln!(frame);
+
+    let _ = writeln!(
+        frame,
+        "{:<32} {:>14} {:>14} {:>14} {:>9} {:>9} {:>7} {:>5} {:>5} {:>4}",
+        "Device", "10s", "60s", "15m", "Accepted", "Rejected", "Errors", "Temp", "Power", "Fan"
+    );
+    for device in &snapshot.devices {
+        let telemetry = device.telemetry.clone().unwrap_or_default();
+        let reading = |value: Option<f32>, unit: &str| match value {
+            Some(value) => format!("{:.0}{}", value, unit),
+            None => "-".into(),
+        };
+        let _ = writeln!(
+            frame,
+            "{:<32.32} {} {:>9} {:>9} {:>7} {:>5} {:>5} {:>4}",
+            device.id,
+            format_row(&device.averages),
+            device.accepted,
+            device.rejected,
+            device.errors,
+            reading(telemetry.temperature_c, "°C"),
+            reading(telemetry.power_w, "W"),
+            reading(telemetry.fan_percent, "%")
+        );
+    }
+    let _ = writeln!(
+        frame,
+        "{:<32} {} {:>9} {:>9} {:>7}",
+        "Total",
+        format_row(&snapshot.total),
+        snapshot.devices.iter().map(|d| d.accepted).sum::<u64>(),
+        snapshot.devices.iter().map(|d| d.rejected).sum::<u64>(),
+        snapshot.devices.iter().map(|d| d.errors).sum::<u64>()
+    );
+    let _ = writeln!(frame);
+
+    for line in logs {
+        let _ = writeln!(frame, "{}", line);
+    }
+    frame
+}
+
+fn format_row(averages: &Averages) -> String {
+    let cells = averages.map(|rate| {
+        let (rate, suffix) = MinerManager::hash_suffix(rate);
+        format!("{:.2} {}", rate, suffix)
+    });
+    format!("{:>14} {:>14} {:>14}", cells[0], cells[1], cells[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use pyrin_miner::stats::{DeviceSnapshot, Snapshot};
+    use pyrin_miner::Telemetry;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let snapshot = Snapshot {
+            total: [2e9, 2e9, 1e9],
+            devices: vec![DeviceSnapshot {
+                id: "#0 (GPU)".into(),
+                averages: [2e9, 2e9, 1e9],
+                accepted: 3,
+                rejected: 1,
+                errors: 0,
+                telemetry: Some(Telemetry {
+                    temperature_c: Some(64.4),
+                    fan_percent: Some(55.),
+                    ..Telemetry::default()
+                }),
+            }],
+            effective: None,
+            difficulty: Some(4.),
+            address: Some("stratum+tcp://pool:5555".into()),
+            connected: true,
+            synced: true,
+            job_age: Some(Duration::from_secs(2)),
+            mining_devfund: Some(false),
+        };
+        let frame = render(&snapshot, Duration::from_secs(3723), &["[log line]".into()]);
+        assert!(frame.contains("Uptime 1h 02m 03s"));
+        assert!(frame.contains("stratum+tcp://pool:5555 (connected)"));
+        assert!(frame.contains("Difficulty: 4    Job age: 2s    Devfund: mining to your address"));
+        let device = frame.lines().find(|line| line.starts_with("#0 (GPU)")).unwrap();
+        assert!(device.contains("2.00 Ghash/s") && device.contains("1.00 Ghash/s"));
+        assert!(device.ends_with(" 64°C     -  55%"));
+        assert!(frame.ends_with("[log line]\n"));
+    }
+}
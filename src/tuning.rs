@@ -0,0 +1,258 @@
+use crate::{DeviceIdentity, Error, Worker};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env::current_exe;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Longest a single batch may take while tuning, unless told otherwise: half a one-second block.
+pub const DEFAULT_LATENCY_BUDGET_MS: f32 = 500.;
+// Largest multiple of the device parallelism that the sweep tries
+const MAX_SCALE: usize = 1 << 14;
+// Batches measured for every candidate workload
+const SAMPLES: usize = 3;
+// Largest change of the workload between two batches, and how far off the target a batch may be before changing it
+const MAX_BATCH_STEP: f32 = 2.;
+const BATCH_TOLERANCE: f32 = 0.1;
+
+// Workers of the same plugin tune concurrently, serialize the read-modify-write of the profile file
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkloadProfile {
+    pub workload: usize,
+    pub hashrate: f64,
+    pub kernel_ms: f32,
+}
+
+/**
+ Tuned workloads saved to disk, keyed by the UUID of a device or else by its PCI address. A device with neither
+ cannot be told apart from another of the same model, its profile is not saved
+*/
+pub struct ProfileStore {
+    path: PathBuf,
+}
+
+impl ProfileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /**
+    The profile file of a backend, next to the miner executable
+    */
+    pub fn default_path(backend: &str) -> PathBuf {
+        let mut path = current_exe().unwrap_or_default();
+        path.pop(); // Getting the parent directory
+        path.join(format!("{}-workload-profiles.json", backend))
+    }
+
+    pub fn get(&self, device: &DeviceIdentity) -> Option<WorkloadProfile> {
+        let key = Self::key(device)?;
+        let _lock = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        self.load().remove(key)
+    }
+
+    pub fn insert(&self, device: &DeviceIdentity, profile: WorkloadProfile) -> Result<(), Error> {
+        let key = Self::key(device).ok_or("The device has no UUID or PCI address to save the profile under")?;
+        let _lock = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut profiles = self.load();
+        profiles.insert(key.to_string(), profile);
+        fs::write(&self.path, serde_json::to_string_pretty(&profiles)?)?;
+        Ok(())
+    }
+
+    fn key(device: &DeviceIdentity) -> Option<&str> {
+        device.uuid.as_deref().or(device.pci_bus_id.as_deref())
+    }
+
+    fn load(&self) -> BTreeMap<String, WorkloadProfile> {
+        fs::read(&self.path).ok().and_then(|data| serde_json::from_slice(&data).ok()).unwrap_or_default()
+    }
+}
+
+/**
+ Keeps the batches of a worker close to a target duration, so that new jobs are picked up quickly
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct BatchController {
+    target_ms: f32,
+}
+
+impl BatchController {
+    pub fn new(target_ms: f32) -> Self {
+        Self { target_ms }
+    }
+
+    /**
+    The workload for the next batch, given the time the last one took. None if it should stay
+    */
+    pub fn adjust(&self, workload: usize, kernel_ms: f32) -> Option<usize> {
+        if kernel_ms <= 0. {
+            return None;
+        }
+        let ratio = self.target_ms / kernel_ms;
+        if (ratio - 1.).abs() <= BATCH_TOLERANCE {
+            return None;
+        }
+        let next = ((workload as f32 * ratio.clamp(1. / MAX_BATCH_STEP, MAX_BATCH_STEP)) as usize).max(1);
+        (next != workload).then_some(next)
+    }
+}
+
+/**
+ How long a device rests after a batch that kept it busy for `busy_ms`, so it works `intensity` percent of the time
+*/
+pub fn idle_gap(busy_ms: f32, intensity: u8) -> Duration {
+    match intensity {
+        1..=99 if busy_ms > 0. => {
+            Duration::from_micros((busy_ms * 1000. * (100 - intensity) as f32 / intensity as f32) as u64)
+        }
+        _ => Duration::ZERO,
+    }
+}
+
+/**
+ Sweeps the workload of a worker over multiples of `base`, and returns the one with the best hashrate
+ whose batches stay within `budget_ms`.
+*/
+pub fn tune_workload(worker: &mut dyn Worker, base: usize, budget_ms: f32) -> Result<WorkloadProfile, Error> {
+    let mut best: Option<WorkloadProfile> = None;
+    let mut scale = 1;
+    while scale <= MAX_SCALE {
+        worker.set_workload(base * scale)?;
+        let workload = worker.get_workload();
+        let kernel_ms = match measure(worker)? {
+            Some(ms) if ms <= budget_ms => ms,
+            _ => break,
+        };
+        let hashrate = (workload as f64) * 1000. / (kernel_ms as f64);
+        info!("{}: Workload {} takes {:.2}ms ({:.2} Mhash/s)", worker.id(), workload, kernel_ms, hashrate / 1e6);
+        if best.filter(|b| b.hashrate >= hashrate).is_none() {
+            best = Some(WorkloadProfile { workload, hashrate, kernel_ms });
+        }
+        if workload < base * scale {
+            // The worker clamped the workload, larger values would measure the same
+            break;
+        }
+        scale *= 2;
+    }
+    best.ok_or_else(|| format!("{}: No workload fits the latency budget of {}ms", worker.id(), budget_ms).into())
+}
+
+// Average kernel time of a few batches, None if the batch was rejected for taking too long
+fn measure(worker: &mut dyn Worker) -> Result<Option<f32>, Error> {
+    let mut total = 0.;
+    let mut nonces = vec![0u64; 1];
+    for _ in 0..SAMPLES {
+        // A zero target never matches, so the kernel does not write any result
+        worker.load_block_constants(&[0u8; 72], &[[0u16; 64]; 64], &[0u64; 4])?;
+        worker.calculate_hash(None, u64::MAX, 0)?;
+        if worker.finish_batch(&mut nonces).is_err() {
+            return Ok(None);
+        }
+        total += worker.kernel_time_ms().ok_or("Worker does not report kernel time")?;
+    }
+    Ok(Some(total / SAMPLES as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idle_gap, tune_workload, BatchController, ProfileStore, WorkloadProfile};
+    use crate::{DeviceIdentity, Error, Worker, WorkerError};
+    use std::time::Duration;
+
+    // Pretends to be a device that saturates at 1000 parallel nonces
+    struct FakeWorker {
+        workload: usize,
+        capacity: usize,
+    }
+
+    impl Worker for FakeWorker {
+        fn id(&self) -> String {
+            "fake".into()
+        }
+        fn load_block_constants(&mut self, _: &[u8; 72], _: &[[u16; 64]; 64], _: &[u64; 4]) -> Result<(), WorkerError> {
+            Ok(())
+        }
+        fn calculate_hash(&mut self, _: Option<&Vec<u64>>, _: u64, _: u64) -> Result<(), WorkerError> {
+            Ok(())
+        }
+        fn sync(&self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_workload(&self) -> usize {
+            self.workload
+        }
+        fn copy_output_to(&mut self, _: &mut Vec<u64>) -> Result<(), Error> {
+            Ok(())
+        }
+        fn kernel_time_ms(&self) -> Option<f32> {
+            Some(1. + (self.workload.max(1000) as f32) / 1000.)
+        }
+        fn set_workload(&mut self, workload: usize) -> Result<(), Error> {
+            self.workload = workload.min(self.capacity);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tune_respects_budget() {
+        let mut worker = FakeWorker { workload: 0, capacity: usize::MAX };
+        let profile = tune_workload(&mut worker, 100, 50.).unwrap();
+        assert_eq!(profile.workload, 25600);
+        assert!(profile.kernel_ms <= 50.);
+
+        assert!(tune_workload(&mut worker, 100_000, 50.).is_err());
+    }
+
+    #[test]
+    fn test_tune_stops_at_capacity() {
+        let mut worker = FakeWorker { workload: 0, capacity: 3000 };
+        let profile = tune_workload(&mut worker, 100, 500.).unwrap();
+        assert_eq!(profile.workload, 3000);
+    }
+
+    #[test]
+    fn test_batch_controller_converges() {
+        let controller = BatchController::new(250.);
+        let mut worker = FakeWorker { workload: 100, capacity: usize::MAX };
+        for _ in 0..20 {
+            if let Some(workload) = controller.adjust(worker.workload, worker.kernel_time_ms().unwrap()) {
+                worker.set_workload(workload).unwrap();
+            }
+        }
+        let kernel_ms = worker.kernel_time_ms().unwrap();
+        assert!((225. ..=275.).contains(&kernel_ms), "{}", kernel_ms);
+        assert_eq!(controller.adjust(worker.workload, kernel_ms), None);
+        assert_eq!(controller.adjust(1000, 1000.), Some(500));
+    }
+
+    #[test]
+    fn test_idle_gap() {
+        assert_eq!(idle_gap(100., 50), Duration::from_millis(100));
+        assert_eq!(idle_gap(100., 25), Duration::from_millis(300));
+        assert_eq!(idle_gap(100., 100), Duration::ZERO);
+        assert_eq!(idle_gap(0., 10), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_profile_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("pyrin-miner-profiles-{}.json", std::process::id()));
+        let store = ProfileStore::new(&path);
+        let gpu = DeviceIdentity { pci_bus_id: Some("0000:01:00.0".into()), uuid: None };
+        let other = DeviceIdentity { pci_bus_id: Some("0000:02:00.0".into()), uuid: None };
+        assert_eq!(store.get(&gpu), None);
+        let profile = WorkloadProfile { workload: 1024, hashrate: 1e9, kernel_ms: 12.5 };
+        store.insert(&gpu, profile).unwrap();
+        store.insert(&other, WorkloadProfile { workload: 1, ..profile }).unwrap();
+        assert_eq!(ProfileStore::new(&path).get(&gpu), Some(profile));
+        // Identical cards without an identity would share a profile
+        assert!(store.insert(&DeviceIdentity::default(), profile).is_err());
+        assert_eq!(store.get(&DeviceIdentity::default()), None);
+        std::fs::remove_file(path).unwrap();
+    }
+}